@@ -0,0 +1,369 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{copy, BufReader, BufWriter, Read};
+use std::path::{Path, PathBuf};
+
+use chrono::Local;
+use colored::*;
+use ssh2::Session;
+
+use crate::constants::{DIR, TEMPLATES_DIR, XOCHITL_CONF};
+use crate::file_types::get_file_types_from_ls_output;
+use crate::nodes::{Directory, Hash, Metadata};
+use crate::remarkable_trees::{
+    build_tree_from_metadata, collect_hashes, find_node, get_hash_from_path,
+};
+use crate::ssh_utils::check_remote_file_exists;
+
+/// Everything a backup contains, as absolute paths on the remarkable.
+const BACKUP_PATHS: [&str; 3] = [DIR, TEMPLATES_DIR, XOCHITL_CONF];
+
+/// The name of a new backup of a host, e.g.
+/// "remarkable_2024-01-31_18-30-00.tar.zst".
+pub fn backup_file_name(host_name: &str) -> String {
+    format!(
+        "{}_{}.tar.zst",
+        host_name,
+        Local::now().format("%Y-%m-%d_%H-%M-%S")
+    )
+}
+
+/// Stream the documents, templates and settings on the remarkable into a
+/// zstd compressed tar archive. The tar is made on the remarkable, which has
+/// no zstd, and compressed as it arrives.
+pub fn backup_device(archive: &Path, sess: &mut Session) -> Result<(), Box<dyn std::error::Error>> {
+    let paths: Vec<&str> = BACKUP_PATHS
+        .iter()
+        .map(|p| p.trim_start_matches('/'))
+        .collect();
+    let mut channel = sess.channel_session()?;
+    channel.exec(&format!("tar -cf - -C / {}", paths.join(" ")))?;
+
+    let mut encoder = zstd::Encoder::new(BufWriter::new(File::create(archive)?), 0)?;
+    copy(&mut channel, &mut encoder)?;
+    encoder.finish()?;
+
+    channel.wait_close()?;
+    match channel.exit_status()? {
+        0 => Ok(()),
+        status => Err(format!("tar failed on the remote with exit status {}", status).into()),
+    }
+}
+
+/// Find the hashes of documents and collections in a backup from their paths
+/// in the backup's own tree, e.g. "Papers/Attention", or their hashes.
+pub fn resolve_backup_documents(
+    archive: &Path,
+    documents: &[String],
+    sess: &mut Session,
+) -> Result<BTreeSet<String>, Box<dyn std::error::Error>> {
+    let BackupContents {
+        metadata,
+        file_names,
+    } = read_backup_metadata(archive)?;
+    select_documents(metadata, &file_names, documents, sess)
+        .map_err(|e| format!("{} in {}", e, archive.display()).into())
+}
+
+/// Find the hashes of documents and collections from their paths in the tree
+/// built from `metadata`, or their hashes. `file_names` are the names of the
+/// files directly in the documents directory.
+///
+/// Collections include everything in them, and any collections they were in
+/// which no longer exist on the remarkable are added so they aren't orphaned.
+pub fn select_documents(
+    metadata: Vec<(String, Metadata)>,
+    file_names: &[String],
+    documents: &[String],
+    sess: &mut Session,
+) -> Result<BTreeSet<String>, Box<dyn std::error::Error>> {
+    select_hashes(metadata, file_names, documents, |hash| {
+        check_remote_file_exists(sess, &format!("{}/{}.metadata", DIR, hash))
+    })
+}
+
+/// `select_documents`, with `exists_on_remote` telling whether a collection
+/// is still on the remarkable.
+fn select_hashes(
+    metadata: Vec<(String, Metadata)>,
+    file_names: &[String],
+    documents: &[String],
+    mut exists_on_remote: impl FnMut(&str) -> bool,
+) -> Result<BTreeSet<String>, Box<dyn std::error::Error>> {
+    let file_types = get_file_types_from_ls_output(&file_names.join("\n"));
+    let parents: HashMap<String, Option<String>> = metadata
+        .iter()
+        .map(|(hash, m)| (hash.clone(), m.get_parent().cloned()))
+        .collect();
+    let root = build_tree_from_metadata(metadata, &file_types).root;
+
+    let mut hashes = BTreeSet::new();
+    for document in documents {
+        if parents.contains_key(document) {
+            hashes.insert(document.clone());
+            continue;
+        }
+        let node = match find_node(&root, document) {
+            Some(node) if !node.get_hash().to_string().is_empty() => node,
+            _ => return Err(format!("{} not found", document).into()),
+        };
+        let mut found = vec![node.get_hash().clone()];
+        if let Some(directory) = node.as_any().downcast_ref::<Directory>() {
+            collect_hashes(directory, &mut found);
+        }
+        hashes.extend(found.iter().map(Hash::to_string));
+    }
+
+    for hash in hashes.clone() {
+        let mut parent = parents.get(&hash).cloned().flatten();
+        // ancestors which are restored anyway have their own parents checked
+        while let Some(hash) = parent.filter(|p| parents.contains_key(p) && !hashes.contains(p)) {
+            if !exists_on_remote(&hash) {
+                hashes.insert(hash.clone());
+            }
+            parent = parents.get(&hash).cloned().flatten();
+        }
+    }
+
+    Ok(hashes)
+}
+
+/// Put the contents of a backup back on the remarkable, overwriting the files
+/// it contains. Files which aren't in the backup are left as they are. If
+/// `only` is given, just the files of those documents and collections are
+/// restored. Returns the number of files restored.
+pub fn restore_device(
+    archive: &Path,
+    only: Option<&BTreeSet<String>>,
+    sess: &mut Session,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut channel = sess.channel_session()?;
+    channel.exec("tar -xf - -C /")?;
+
+    let mut builder = tar::Builder::new(channel);
+    let mut restored = 0;
+    let mut backup = open_backup(archive)?;
+    for entry in backup.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if let Some(only) = only {
+            match document_hash(&path) {
+                Some(hash) if only.contains(&hash) => {}
+                _ => continue,
+            }
+        }
+        let mut header = entry.header().clone();
+        builder.append_data(&mut header, &path, &mut entry)?;
+        restored += 1;
+    }
+
+    let mut channel = builder.into_inner()?;
+    channel.send_eof()?;
+    channel.wait_eof()?;
+    channel.wait_close()?;
+    match channel.exit_status()? {
+        0 => Ok(restored),
+        status => Err(format!("tar failed on the remote with exit status {}", status).into()),
+    }
+}
+
+pub fn print_restored(archive: &Path, restored: usize) {
+    println!(
+        "{} restored {} files from {}",
+        "Success:".bold().green(),
+        restored,
+        archive.to_string_lossy().italic().purple()
+    );
+}
+
+fn open_backup(archive: &Path) -> Result<tar::Archive<impl Read>, std::io::Error> {
+    let decoder = zstd::Decoder::new(BufReader::new(File::open(archive)?))?;
+    Ok(tar::Archive::new(decoder))
+}
+
+/// What is in the documents directory of a backup.
+struct BackupContents {
+    /// The metadata of every document and collection, by hash
+    metadata: Vec<(String, Metadata)>,
+    /// The names of all the files directly in the directory
+    file_names: Vec<String>,
+}
+
+fn read_backup_metadata(archive: &Path) -> Result<BackupContents, Box<dyn std::error::Error>> {
+    let documents_directory = PathBuf::from(DIR.trim_start_matches('/'));
+    let mut metadata = Vec::new();
+    let mut file_names = Vec::new();
+
+    let mut backup = open_backup(archive)?;
+    for entry in backup.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if path.parent() != Some(documents_directory.as_path()) {
+            continue;
+        }
+        let file_name = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => continue,
+        };
+        if let Some(hash) = file_name.strip_suffix(".metadata") {
+            let mut json = String::new();
+            entry.read_to_string(&mut json)?;
+            metadata.push((hash.to_string(), serde_json::from_str(&json)?));
+        }
+        file_names.push(file_name);
+    }
+
+    Ok(BackupContents {
+        metadata,
+        file_names,
+    })
+}
+
+/// The hash of the document or collection a file in a backup belongs to.
+fn document_hash(path: &Path) -> Option<String> {
+    get_hash_from_path(path.strip_prefix(DIR.trim_start_matches('/')).ok()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+    use crate::constants::{COLLECTION, DOCUMENT};
+
+    fn metadata(name: &str, parent: &str, type_: &str) -> Metadata {
+        Metadata::new(name.into(), Some(parent.into()), "0".into(), type_.into())
+    }
+
+    /// Papers/2023/Attention, Papers/Notes and Books/Dune
+    fn library() -> (Vec<(String, Metadata)>, Vec<String>) {
+        let metadata = vec![
+            ("papers".to_string(), metadata("Papers", "", COLLECTION)),
+            ("2023".to_string(), metadata("2023", "papers", COLLECTION)),
+            (
+                "attention".to_string(),
+                metadata("Attention", "2023", DOCUMENT),
+            ),
+            ("notes".to_string(), metadata("Notes", "papers", DOCUMENT)),
+            ("books".to_string(), metadata("Books", "", COLLECTION)),
+            ("dune".to_string(), metadata("Dune", "books", DOCUMENT)),
+        ];
+        let file_names = [
+            "papers.metadata",
+            "2023.metadata",
+            "attention.metadata",
+            "attention.pdf",
+            "notes.metadata",
+            "notes.content",
+            "books.metadata",
+            "dune.metadata",
+            "dune.epub",
+        ]
+        .map(String::from)
+        .to_vec();
+        (metadata, file_names)
+    }
+
+    fn select(documents: &[&str], existing: &[&str]) -> Result<Vec<String>, String> {
+        let (metadata, file_names) = library();
+        let documents: Vec<String> = documents.iter().map(|d| d.to_string()).collect();
+        select_hashes(metadata, &file_names, &documents, |hash| {
+            existing.contains(&hash)
+        })
+        .map(|hashes| hashes.into_iter().collect())
+        .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn documents_are_selected_by_path_or_hash() {
+        let all = ["papers", "2023", "books"];
+        assert_eq!(
+            select(&["Papers/2023/Attention"], &all),
+            Ok(vec!["attention".to_string()])
+        );
+        assert_eq!(
+            select(&["dune", "Papers/Notes"], &all),
+            Ok(vec!["dune".to_string(), "notes".to_string()])
+        );
+        assert_eq!(
+            select(&["Papers/Dune"], &all),
+            Err("Papers/Dune not found".to_string())
+        );
+        assert_eq!(select(&["/"], &all), Err("/ not found".to_string()));
+    }
+
+    #[test]
+    fn collections_bring_everything_in_them() {
+        let all = ["papers", "2023", "books"];
+        assert_eq!(
+            select(&["Papers"], &all),
+            Ok(["2023", "attention", "notes", "papers"]
+                .map(String::from)
+                .to_vec())
+        );
+    }
+
+    #[test]
+    fn collections_gone_from_the_remarkable_are_restored_too() {
+        // 2023 and Papers were deleted since the backup, Books wasn't
+        assert_eq!(
+            select(&["attention", "dune"], &["books"]),
+            Ok(["2023", "attention", "dune", "papers"]
+                .map(String::from)
+                .to_vec())
+        );
+    }
+
+    #[test]
+    fn only_files_of_documents_have_a_hash() {
+        let documents = DIR.trim_start_matches('/');
+        let hash = |path: String| document_hash(Path::new(&path));
+        assert_eq!(hash(format!("{}/abc.pdf", documents)), Some("abc".into()));
+        assert_eq!(hash(format!("{}/abc/1.rm", documents)), Some("abc".into()));
+        assert_eq!(
+            hash(format!(
+                "{}/Blank.png",
+                TEMPLATES_DIR.trim_start_matches('/')
+            )),
+            None
+        );
+        assert_eq!(hash(XOCHITL_CONF.trim_start_matches('/').to_string()), None);
+    }
+
+    #[test]
+    fn backup_metadata_is_read_from_the_documents_directory() {
+        let directory = tempfile::tempdir().unwrap();
+        let archive = directory.path().join("backup.tar.zst");
+        let documents = PathBuf::from(DIR.trim_start_matches('/'));
+        let json = serde_json::to_string(&metadata("Notes", "papers", DOCUMENT)).unwrap();
+        let files: [(PathBuf, &[u8]); 4] = [
+            (documents.join("notes.metadata"), json.as_bytes()),
+            (documents.join("notes.content"), b"{}"),
+            (documents.join("notes/1.rm"), b"lines"),
+            (
+                PathBuf::from(TEMPLATES_DIR.trim_start_matches('/')).join("Blank.png"),
+                b"png",
+            ),
+        ];
+
+        let encoder = zstd::Encoder::new(File::create(&archive).unwrap(), 0).unwrap();
+        let mut builder = tar::Builder::new(encoder);
+        for (path, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, data).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let contents = read_backup_metadata(&archive).unwrap();
+        assert_eq!(contents.file_names, vec!["notes.metadata", "notes.content"]);
+        assert_eq!(contents.metadata.len(), 1);
+        assert_eq!(contents.metadata[0].0, "notes");
+        assert_eq!(
+            contents.metadata[0].1.get_parent().map(String::as_str),
+            Some("papers")
+        );
+    }
+}
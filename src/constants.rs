@@ -1,6 +1,37 @@
+// the host to connect to from the ssh config
+pub const SSH_HOST: &str = "remarkable";
+
 // directory where remarkable stores user's files
 pub const DIR: &str = "/home/root/.local/share/remarkable/xochitl";
+// page templates and UI settings, which are included in backups
+pub const TEMPLATES_DIR: &str = "/usr/share/remarkable/templates";
+pub const XOCHITL_CONF: &str = "/home/root/.config/remarkable/xochitl.conf";
+
+// where the remarkable describes its hardware and firmware
+pub const MACHINE_FILE: &str = "/sys/devices/soc0/machine";
+pub const VERSION_FILE: &str = "/etc/version";
+pub const UPDATE_CONF: &str = "/usr/share/remarkable/update.conf";
+pub const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
 
 // remarkable types
 pub const DOCUMENT: &str = "DocumentType";
 pub const COLLECTION: &str = "CollectionType";
+
+// gitignore-style files listing paths remarko should leave alone
+pub const IGNORE_FILE: &str = ".remarkoignore";
+pub const REMOTE_IGNORE_FILE: &str = "remoteignore";
+
+// where remarko keeps track of synced documents inside a local directory
+pub const STATE_DIR: &str = ".remarko";
+pub const STATE_FILE: &str = "state.json";
+
+// where snapshots are stored, inside the local data directory
+pub const SNAPSHOTS_DIR: &str = "snapshots";
+
+// the parent of documents in the remarkable's trash
+pub const TRASH: &str = "trash";
+// where remarko keeps its own files on the remarkable, outside of DIR
+pub const REMOTE_STATE_DIR: &str = "/home/root/.local/share/remarko";
+pub const REMOTE_TRASH_FILE: &str = "trash.json";
+// where `--mirror` moves local files which are no longer on the remote
+pub const LOCAL_TRASH_DIR: &str = ".remarko-trash";
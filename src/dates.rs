@@ -0,0 +1,110 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local, TimeZone, Utc};
+
+/// The format dates are shown in when `--date-format` isn't given.
+pub const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// How to show dates: in local time unless `utc` is set, either with a
+/// strftime format or relative to now, e.g. "3 days ago".
+#[derive(Clone, Debug)]
+pub struct DateFormat {
+    pub utc: bool,
+    /// None for relative dates
+    pub format: Option<String>,
+}
+
+impl Default for DateFormat {
+    fn default() -> Self {
+        DateFormat {
+            utc: false,
+            format: Some(DEFAULT_DATE_FORMAT.to_string()),
+        }
+    }
+}
+
+impl DateFormat {
+    /// `format` is a strftime format, e.g. "%d/%m/%Y", or "relative".
+    pub fn new(utc: bool, format: &str) -> Result<DateFormat, String> {
+        if format == "relative" {
+            return Ok(DateFormat { utc, format: None });
+        }
+        if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+            return Err(format!("invalid date format: {}", format));
+        }
+        Ok(DateFormat {
+            utc,
+            format: Some(format.to_string()),
+        })
+    }
+
+    pub fn format(&self, date: &DateTime<Utc>) -> String {
+        match &self.format {
+            None => format_relative(date, &Utc::now()),
+            Some(format) if self.utc => date.format(format).to_string(),
+            Some(format) => date.with_timezone(&Local).format(format).to_string(),
+        }
+    }
+
+    /// Format a date which may be missing, e.g. a document which has never
+    /// been opened.
+    pub fn format_or(&self, date: Option<&DateTime<Utc>>, missing: &str) -> String {
+        match date {
+            Some(date) => self.format(date),
+            None => missing.to_string(),
+        }
+    }
+}
+
+/// Parse a timestamp as xochitl stores them, a string of milliseconds since
+/// the epoch. "0", which xochitl uses for never, and anything which isn't a
+/// number give None.
+///
+/// ```
+/// use remarko::dates::parse_timestamp;
+///
+/// let date = parse_timestamp("1700000000000").unwrap();
+/// assert_eq!(date.to_rfc3339(), "2023-11-14T22:13:20+00:00");
+/// assert!(parse_timestamp("0").is_none());
+/// assert!(parse_timestamp("yesterday").is_none());
+/// ```
+pub fn parse_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    let millis: i64 = timestamp.trim().parse().ok()?;
+    if millis <= 0 {
+        return None;
+    }
+    Utc.timestamp_millis_opt(millis).single()
+}
+
+/// Format how long ago `date` was, e.g. "3 days ago", in the largest whole
+/// unit.
+///
+/// ```
+/// use chrono::{Duration, Utc};
+/// use remarko::dates::format_relative;
+///
+/// let now = Utc::now();
+/// assert_eq!(format_relative(&(now - Duration::days(3)), &now), "3 days ago");
+/// assert_eq!(format_relative(&(now - Duration::minutes(1)), &now), "1 minute ago");
+/// assert_eq!(format_relative(&(now + Duration::hours(2)), &now), "in 2 hours");
+/// ```
+pub fn format_relative(date: &DateTime<Utc>, now: &DateTime<Utc>) -> String {
+    let seconds = (*now - *date).num_seconds();
+    let (amount, future) = (seconds.unsigned_abs(), seconds < 0);
+    const UNITS: [(&str, u64); 6] = [
+        ("year", 365 * 86400),
+        ("month", 30 * 86400),
+        ("week", 7 * 86400),
+        ("day", 86400),
+        ("hour", 3600),
+        ("minute", 60),
+    ];
+    let (unit, count) = match UNITS.iter().find(|(_, size)| amount >= *size) {
+        Some((unit, size)) => (*unit, amount / size),
+        None => return "just now".to_string(),
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    match future {
+        true => format!("in {} {}{}", count, unit, plural),
+        false => format!("{} {}{} ago", count, unit, plural),
+    }
+}
@@ -0,0 +1,76 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
+
+use colored::*;
+use ssh2::Session;
+
+use crate::constants::SSH_HOST;
+use crate::ssh_utils::{connect_to_remote, get_ssh_config};
+
+// whether a guard has xochitl stopped, so Ctrl-C knows to start it again
+static STOPPED: AtomicBool = AtomicBool::new(false);
+static SET_HANDLER: Once = Once::new();
+
+/// Keeps xochitl, the remarkable's UI, stopped while documents are changed,
+/// so it can't ignore or overwrite the changes.
+///
+/// xochitl is started again when the guard is dropped, including when the
+/// command fails or panics, and if the command is interrupted with Ctrl-C.
+pub struct XochitlGuard {
+    sess: Session,
+}
+
+impl XochitlGuard {
+    pub fn stop(sess: &Session) -> XochitlGuard {
+        SET_HANDLER.call_once(|| {
+            ctrlc::set_handler(|| {
+                if STOPPED.load(Ordering::SeqCst) {
+                    // the main session may be blocked in a transfer, so use a new one
+                    let sess = connect_to_remote(get_ssh_config().query(SSH_HOST));
+                    if systemctl(&sess, "start").is_err() {
+                        print_start_failed();
+                    }
+                }
+                std::process::exit(130);
+            })
+            .expect("Failed to set Ctrl-C handler");
+        });
+
+        systemctl(sess, "stop").expect("Failed to stop xochitl");
+        STOPPED.store(true, Ordering::SeqCst);
+        XochitlGuard { sess: sess.clone() }
+    }
+}
+
+impl Drop for XochitlGuard {
+    fn drop(&mut self) {
+        // this may run while panicking, so report errors rather than panic
+        if systemctl(&self.sess, "start").is_err() {
+            print_start_failed();
+        }
+        STOPPED.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Restart xochitl, e.g. to make it pick up documents changed by hand.
+pub fn restart_ui(sess: &Session) -> Result<(), Box<dyn std::error::Error>> {
+    systemctl(sess, "restart")?;
+    Ok(())
+}
+
+fn systemctl(sess: &Session, action: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut channel = sess.channel_session()?;
+    channel.exec(&format!("systemctl {} xochitl", action))?;
+    channel.wait_close()?;
+    match channel.exit_status()? {
+        0 => Ok(()),
+        status => Err(format!("systemctl {} xochitl exited with status {}", action, status).into()),
+    }
+}
+
+fn print_start_failed() {
+    eprintln!(
+        "{} failed to start xochitl, run `remarko restart-ui` to start it",
+        "Error:".bold().red()
+    );
+}
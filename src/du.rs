@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use colored::*;
+use serde::Serialize;
+use ssh2::Session;
+
+use crate::constants::DIR;
+use crate::nodes::{Directory, DirectoryNode, Node};
+use crate::remarkable_trees::get_hash_from_path;
+use crate::snapshots::format_size;
+use crate::ssh_utils::{get_remote_file_stats, run_remote_command, RemoteFileStat};
+
+/// How much space a document or collection takes up on the remarkable,
+/// including everything in it.
+#[derive(Clone, Debug)]
+pub struct Usage {
+    pub name: String,
+    pub size: u64,
+    pub is_directory: bool,
+    /// Sorted largest first
+    pub children: Vec<Usage>,
+}
+
+/// The size and free space of the filesystem the documents are on, in bytes.
+#[derive(Clone, Debug, Serialize)]
+pub struct DiskSpace {
+    pub total: u64,
+    pub used: u64,
+    pub available: u64,
+}
+
+/// Sum the sizes of all the files belonging to each hash: the payload,
+/// `.content`, `.metadata`, pages, thumbnails and caches.
+pub fn get_sizes_by_hash(sess: &mut Session) -> HashMap<String, u64> {
+    sum_sizes_by_hash(&get_remote_file_stats(sess, DIR))
+}
+
+fn sum_sizes_by_hash(stats: &HashMap<String, RemoteFileStat>) -> HashMap<String, u64> {
+    let mut sizes = HashMap::new();
+    for stat in stats.values() {
+        let relative_path = match stat.path.strip_prefix(&format!("{}/", DIR)) {
+            Some(path) => path,
+            None => continue,
+        };
+        if let Some(hash) = get_hash_from_path(Path::new(relative_path)) {
+            *sizes.entry(hash).or_default() += stat.size;
+        }
+    }
+    sizes
+}
+
+/// Roll the sizes of documents up through the collections they are in.
+pub fn get_usage(directory: &Directory, sizes: &HashMap<String, u64>) -> Usage {
+    let mut children: Vec<Usage> = directory
+        .get_directories()
+        .iter()
+        .map(|d| get_usage(d, sizes))
+        .collect();
+    children.extend(directory.get_files().iter().map(|file| {
+        Usage {
+            name: file.get_visible_name().to_string(),
+            size: sizes
+                .get(&file.get_hash().to_string())
+                .copied()
+                .unwrap_or_default(),
+            is_directory: false,
+            children: Vec::new(),
+        }
+    }));
+    children.sort_by_key(|c| std::cmp::Reverse(c.size));
+
+    let own_size = sizes
+        .get(&directory.get_hash().to_string())
+        .copied()
+        .unwrap_or_default();
+    Usage {
+        name: directory.get_visible_name().to_string(),
+        size: own_size + children.iter().map(|c| c.size).sum::<u64>(),
+        is_directory: true,
+        children,
+    }
+}
+
+/// Print usage as a tree, `depth` levels deep and with at most `top` entries
+/// in each collection.
+pub fn print_usage(usage: &Usage, depth: usize, top: Option<usize>) {
+    let mut rows = Vec::new();
+    usage_rows(usage, 0, depth, top, &mut rows);
+    for row in rows {
+        match row {
+            UsageRow::Entry { usage, level } => {
+                let name = match usage.is_directory {
+                    true => format!("{}/", usage.name).bold().green(),
+                    false => usage.name.purple(),
+                };
+                let indent = " ".repeat(level * 2);
+                println!("{:>10}  {}{}", format_size(usage.size), indent, name);
+            }
+            UsageRow::Hidden { count, size, level } => {
+                let indent = " ".repeat(level * 2);
+                println!(
+                    "{:>10}  {}{}",
+                    format_size(size),
+                    indent,
+                    format!("... and {} more", count).italic()
+                );
+            }
+        }
+    }
+}
+
+/// A line of `print_usage`, indented by `level`.
+#[derive(Debug)]
+enum UsageRow<'a> {
+    Entry {
+        usage: &'a Usage,
+        level: usize,
+    },
+    /// The children left out by `top`
+    Hidden {
+        count: usize,
+        size: u64,
+        level: usize,
+    },
+}
+
+fn usage_rows<'a>(
+    usage: &'a Usage,
+    level: usize,
+    depth: usize,
+    top: Option<usize>,
+    rows: &mut Vec<UsageRow<'a>>,
+) {
+    rows.push(UsageRow::Entry { usage, level });
+    if level >= depth {
+        return;
+    }
+    let shown = top
+        .unwrap_or(usage.children.len())
+        .min(usage.children.len());
+    for child in &usage.children[..shown] {
+        usage_rows(child, level + 1, depth, top, rows);
+    }
+    let hidden = &usage.children[shown..];
+    if !hidden.is_empty() {
+        rows.push(UsageRow::Hidden {
+            count: hidden.len(),
+            size: hidden.iter().map(|c| c.size).sum(),
+            level: level + 1,
+        });
+    }
+}
+
+/// Get the size and free space of the filesystem the documents are on, from
+/// `df`.
+pub fn get_disk_space(sess: &mut Session) -> Option<DiskSpace> {
+    parse_df(&run_remote_command(sess, &format!("df -k {}", DIR)))
+}
+
+/// Parse the output of `df -k` for a single filesystem.
+fn parse_df(output: &str) -> Option<DiskSpace> {
+    // skip the header; long device names can wrap onto their own line
+    let fields: Vec<u64> = output
+        .lines()
+        .skip(1)
+        .flat_map(|line| line.split_whitespace())
+        .filter_map(|field| field.parse().ok())
+        .collect();
+    match fields[..] {
+        [total, used, available, ..] => Some(DiskSpace {
+            total: total * 1024,
+            used: used * 1024,
+            available: available * 1024,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_types::FileType;
+    use crate::ssh_utils::parse_file_stats;
+    use crate::test_utils::{directory, file};
+
+    #[test]
+    fn sizes_are_summed_by_hash() {
+        let output = [
+            "1000 1700000000 /home/root/.local/share/remarkable/xochitl/a.pdf",
+            "200 1700000000 /home/root/.local/share/remarkable/xochitl/a/p1.rm",
+            "30 1700000000 /home/root/.local/share/remarkable/xochitl/a.thumbnails/p 1.png",
+            "4 1700000000 /home/root/.local/share/remarkable/xochitl/b.metadata",
+            "not a stat line",
+        ]
+        .join("\n");
+        let sizes = sum_sizes_by_hash(&parse_file_stats(&output));
+        assert_eq!(
+            sizes,
+            HashMap::from([("a".to_string(), 1230), ("b".to_string(), 4)])
+        );
+    }
+
+    fn library() -> Usage {
+        let root = directory(
+            "",
+            "",
+            vec![file("c", "Loose", Some(FileType::Pdf))],
+            vec![directory(
+                "p",
+                "Papers",
+                vec![
+                    file("a", "Small", Some(FileType::Pdf)),
+                    file("b", "Large", Some(FileType::Pdf)),
+                    file("d", "Unknown", Some(FileType::Pdf)),
+                ],
+                Vec::new(),
+            )],
+        );
+        let sizes = HashMap::from([
+            ("p".to_string(), 10),
+            ("a".to_string(), 100),
+            ("b".to_string(), 300),
+            ("c".to_string(), 50),
+        ]);
+        get_usage(&root, &sizes)
+    }
+
+    #[test]
+    fn usage_is_rolled_up_through_collections() {
+        let usage = library();
+        assert_eq!(usage.size, 460);
+        let children: Vec<(&str, u64)> = usage
+            .children
+            .iter()
+            .map(|c| (c.name.as_str(), c.size))
+            .collect();
+        assert_eq!(children, vec![("Papers", 410), ("Loose", 50)]);
+        let papers: Vec<(&str, u64)> = usage.children[0]
+            .children
+            .iter()
+            .map(|c| (c.name.as_str(), c.size))
+            .collect();
+        assert_eq!(papers, vec![("Large", 300), ("Small", 100), ("Unknown", 0)]);
+    }
+
+    fn rows(usage: &Usage, depth: usize, top: Option<usize>) -> Vec<String> {
+        let mut rows = Vec::new();
+        usage_rows(usage, 0, depth, top, &mut rows);
+        rows.iter()
+            .map(|row| match row {
+                UsageRow::Entry { usage, level } => format!("{} {}", level, usage.name),
+                UsageRow::Hidden { count, size, level } => {
+                    format!("{} {} more, {}", level, count, size)
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn depth_and_top_limit_what_is_shown() {
+        let usage = library();
+        assert_eq!(rows(&usage, 0, None), vec!["0 "]);
+        assert_eq!(rows(&usage, 1, None), vec!["0 ", "1 Papers", "1 Loose"]);
+        assert_eq!(
+            rows(&usage, 2, Some(1)),
+            vec!["0 ", "1 Papers", "2 Large", "2 2 more, 100", "1 1 more, 50"]
+        );
+        assert_eq!(
+            rows(&usage, 2, None),
+            vec![
+                "0 ",
+                "1 Papers",
+                "2 Large",
+                "2 Small",
+                "2 Unknown",
+                "1 Loose"
+            ]
+        );
+    }
+
+    #[test]
+    fn disk_space_is_read_from_df() {
+        let output = "\
+Filesystem     1K-blocks    Used Available Use% Mounted on
+/dev/mmcblk2p4   6768432 1234567   5187654  20% /home
+";
+        let space = parse_df(output).unwrap();
+        assert_eq!(space.total, 6768432 * 1024);
+        assert_eq!(space.used, 1234567 * 1024);
+        assert_eq!(space.available, 5187654 * 1024);
+    }
+
+    #[test]
+    fn long_device_names_in_df_are_skipped() {
+        let output = "\
+Filesystem           1K-blocks      Used Available Use% Mounted on
+/dev/mapper/a-very-long-device-name
+                       6768432   1234567   5187654  20% /home
+";
+        assert_eq!(parse_df(output).unwrap().available, 5187654 * 1024);
+        assert!(parse_df("").is_none());
+        assert!(parse_df("df: /home: No such file or directory\n").is_none());
+    }
+}
@@ -0,0 +1,424 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use unicode_normalization::UnicodeNormalization;
+
+use crate::nodes::{Directory, DirectoryNode, File, Node};
+use crate::sync_state::SyncState;
+
+/// The form of a name used for comparisons: NFC normalized, so names typed
+/// on the remarkable match the decomposed (NFD) names macOS gives files,
+/// and lowercased if `ignore_case` is set.
+pub fn name_key(name: &str, ignore_case: bool) -> String {
+    let normalized: String = name.nfc().collect();
+    if ignore_case {
+        normalized.to_lowercase()
+    } else {
+        normalized
+    }
+}
+
+/// Whether two names refer to the same thing, see [`name_key`].
+///
+/// # Example
+///
+/// ```
+/// use remarko::file_names::names_match;
+///
+/// // "é" as a single code point and as "e" followed by a combining accent
+/// assert!(names_match("Caf\u{e9}", "Cafe\u{301}", false));
+/// // "が" and "か" followed by a combining voiced sound mark, as macOS stores it
+/// assert!(names_match("\u{304c}\u{3053}\u{3046}", "\u{304b}\u{3099}\u{3053}\u{3046}", false));
+/// // hangul syllables and their decomposed jamo
+/// assert!(names_match("\u{d55c}\u{ae00}", "\u{1112}\u{1161}\u{11ab}\u{1100}\u{1173}\u{11af}", false));
+/// // chinese characters have no decomposition, so only identical names match
+/// assert!(names_match("论文", "论文", true));
+/// assert!(!names_match("论文", "論文", true));
+///
+/// assert!(!names_match("Paper.PDF", "Paper.pdf", false));
+/// assert!(names_match("Paper.PDF", "Paper.pdf", true));
+/// assert!(names_match("\u{c9}TUDE", "e\u{301}tude", true));
+/// ```
+pub fn names_match(a: &str, b: &str, ignore_case: bool) -> bool {
+    name_key(a, ignore_case) == name_key(b, ignore_case)
+}
+
+/// Turn a remote visible name into a name which is safe to use as a single
+/// local path component.
+///
+/// Characters which would escape or break the path are percent-encoded, as
+/// is `%` itself, so the original name can always be recovered with
+/// [`unsanitize_file_name`]. A leading dot is encoded so names like `..`
+/// and `.hidden` are neither special nor skipped as hidden files, and a
+/// trailing dot or space is encoded since some filesystems drop them.
+pub fn sanitize_file_name(name: &str) -> String {
+    if name.is_empty() {
+        return "untitled".to_string();
+    }
+
+    let last = name.chars().count() - 1;
+    let mut sanitized = String::new();
+    for (i, c) in name.chars().enumerate() {
+        let unsafe_char = matches!(c, '%' | '/' | '\\' | '\0')
+            || c.is_control()
+            || (i == 0 && c == '.')
+            || (i == last && (c == '.' || c == ' '))
+            || (cfg!(windows) && matches!(c, ':' | '*' | '?' | '"' | '<' | '>' | '|'));
+        if unsafe_char {
+            let mut buffer = [0; 4];
+            for byte in c.encode_utf8(&mut buffer).bytes() {
+                sanitized.push_str(&format!("%{:02X}", byte));
+            }
+        } else {
+            sanitized.push(c);
+        }
+    }
+    sanitized
+}
+
+/// Reverse [`sanitize_file_name`], decoding its `%XX` escapes.
+///
+/// Names are only decoded if they are exactly what [`sanitize_file_name`]
+/// gives for the decoded name, so a name remarko didn't create, like
+/// `Report 100%DE.pdf`, is kept as it is rather than turned into something
+/// else. The result is NFC normalized, since that is how the remarkable
+/// stores names.
+pub fn unsanitize_file_name(name: &str) -> String {
+    let decoded = decode_escapes(name)
+        .filter(|decoded| sanitize_file_name(decoded) == name)
+        .unwrap_or_else(|| name.to_string());
+    decoded.nfc().collect()
+}
+
+/// Decode every `%XX` escape in a name, or None if the result isn't UTF-8.
+fn decode_escapes(name: &str) -> Option<String> {
+    let bytes = name.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let Some(byte) = name
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// The longest file name most filesystems allow, in bytes.
+const MAX_NAME_BYTES: usize = 255;
+
+/// Cut a sanitized name down to at most `max_bytes`, without splitting a
+/// character or a `%XX` escape.
+fn truncate_name(name: &str, max_bytes: usize) -> &str {
+    if name.len() <= max_bytes {
+        return name;
+    }
+    let mut end = max_bytes;
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    // every `%` in a sanitized name starts an escape
+    if let Some(escape) = name[..end].rfind('%') {
+        if escape + 3 > end {
+            end = escape;
+        }
+    }
+    &name[..end]
+}
+
+/// Split a local name into its stem and its extension, with the dot.
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name, ""),
+    }
+}
+
+/// Give each node in a remote directory the name it has on the local machine.
+///
+/// Names are sanitized and, where siblings would end up with the same local
+/// name, all but the first get a " (2)", " (3)" ... suffix. Names which only
+/// differ in case count as the same, since they are on macOS and Windows.
+/// Long names are shortened to fit in 255 bytes, keeping the extension and
+/// any suffix. Names already
+/// recorded in `state` for a document at the same location are reused, so a
+/// file keeps its local name from one run to the next.
+/// `relative_path` is the local path of `directory` relative to the root.
+pub fn map_to_local_names(
+    directory: &Directory,
+    relative_path: &Path,
+    state: &SyncState,
+) -> Directory {
+    let mut taken = HashSet::new();
+    let mut files = Vec::new();
+    let mut directories = Vec::new();
+
+    // names recorded in the state file take priority
+    let recorded_name = |hash: &str| {
+        state
+            .get_path(hash)
+            .filter(|p| p.parent() == Some(relative_path))
+            .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()))
+    };
+    for file in directory.get_files() {
+        if let Some(name) = recorded_name(&file.get_hash().to_string()) {
+            taken.insert(name_key(&name, true));
+        }
+    }
+    for sub_directory in directory.get_directories() {
+        if let Some(name) = recorded_name(&sub_directory.get_hash().to_string()) {
+            taken.insert(name_key(&name, true));
+        }
+    }
+
+    // `extension` includes the dot, and is kept when a suffix is added
+    let mut pick_name = |hash: &str, stem: &str, extension: &str| {
+        if let Some(recorded) = recorded_name(hash) {
+            return recorded;
+        }
+        let mut n = 1;
+        loop {
+            let suffix = match n {
+                1 => String::new(),
+                _ => format!(" ({})", n),
+            };
+            let stem = truncate_name(
+                stem,
+                MAX_NAME_BYTES.saturating_sub(suffix.len() + extension.len()),
+            );
+            let candidate = format!("{}{}{}", stem, suffix, extension);
+            if taken.insert(name_key(&candidate, true)) {
+                return candidate;
+            }
+            n += 1;
+        }
+    };
+
+    for sub_directory in directory.get_directories() {
+        let name = pick_name(
+            &sub_directory.get_hash().to_string(),
+            &sanitize_file_name(sub_directory.get_visible_name()),
+            "",
+        );
+        let mapped = map_to_local_names(sub_directory, &relative_path.join(&name), state);
+        let mut metadata = mapped.get_metadata().clone();
+        metadata.set_visible_name(name);
+        directories.push(Directory::new(
+            mapped.get_hash().clone(),
+            metadata,
+            Some(mapped.get_files().clone()),
+            Some(mapped.get_directories().clone()),
+        ));
+    }
+
+    for file in directory.get_files() {
+        let hash = file.get_hash().to_string();
+        let name = match file.get_file_type() {
+            Some(file_type) => pick_name(
+                &hash,
+                &sanitize_file_name(&file.get_file_stem()),
+                &format!(".{}", file_type.local_extension()),
+            ),
+            None => {
+                let name = sanitize_file_name(file.get_visible_name());
+                let (stem, extension) = split_extension(&name);
+                pick_name(&hash, stem, extension)
+            }
+        };
+
+        let mut metadata = file.get_metadata().clone();
+        metadata.set_visible_name(name);
+        let mut mapped = File::new(file.get_hash().clone(), metadata);
+        if let Some(file_type) = file.get_file_type() {
+            mapped.set_file_type(file_type);
+        }
+        files.push(mapped);
+    }
+
+    Directory::new(
+        directory.get_hash().clone(),
+        directory.get_metadata().clone(),
+        Some(files),
+        Some(directories),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::file_types::FileType;
+    use crate::test_utils::{directory, file, file_names};
+
+    fn local_names(files: Vec<File>, state: &SyncState) -> Vec<String> {
+        let root = directory("", "", files, Vec::new());
+        file_names(&map_to_local_names(&root, Path::new(""), state))
+    }
+
+    #[test]
+    fn sanitize_round_trips() {
+        for name in [
+            "Paper",
+            "a/b",
+            "50% done",
+            "..",
+            ".hidden",
+            "trailing.",
+            "trailing ",
+            "back\\slash",
+            "tab\there",
+            "",
+            "Caf\u{e9}",
+        ] {
+            let sanitized = sanitize_file_name(name);
+            assert!(!sanitized.contains('/'), "{:?}", sanitized);
+            assert!(!sanitized.starts_with('.'), "{:?}", sanitized);
+            let expected = match name {
+                "" => "untitled",
+                _ => name,
+            };
+            assert_eq!(unsanitize_file_name(&sanitized), expected);
+        }
+        assert_eq!(sanitize_file_name("a/b"), "a%2Fb");
+    }
+
+    #[test]
+    fn unsanitize_normalizes_to_nfc() {
+        assert_eq!(unsanitize_file_name("Cafe\u{301}"), "Caf\u{e9}");
+        // a stray % which isn't an escape is kept
+        assert_eq!(unsanitize_file_name("100%"), "100%");
+    }
+
+    #[test]
+    fn names_remarko_did_not_create_are_kept() {
+        for name in [
+            "Report 100%DE",
+            "50%",
+            "%zz",
+            "%41bc",
+            "100%25 sure%",
+            "ends in %2",
+            "%2e%2E",
+        ] {
+            assert_eq!(unsanitize_file_name(name), name);
+        }
+    }
+
+    #[test]
+    fn names_remarko_created_are_decoded() {
+        assert_eq!(unsanitize_file_name("a%2Fb"), "a/b");
+        assert_eq!(unsanitize_file_name("50%25 done"), "50% done");
+        assert_eq!(unsanitize_file_name("%2E%2E"), "..");
+        // lowercase escapes aren't what sanitize_file_name writes
+        assert_eq!(unsanitize_file_name("a%2fb"), "a%2fb");
+    }
+
+    #[test]
+    fn name_keys_normalize_and_fold_case() {
+        assert_eq!(name_key("Cafe\u{301}", false), "Caf\u{e9}");
+        assert_eq!(name_key("PAPER", true), "paper");
+        assert!(!names_match("Paper", "paper", false));
+    }
+
+    #[test]
+    fn collisions_get_suffixes_before_the_extension() {
+        let names = local_names(
+            vec![
+                file("a", "Notes", Some(FileType::Notebook)),
+                file("b", "Notes.pdf", Some(FileType::Pdf)),
+                file("c", "Notes", Some(FileType::Pdf)),
+            ],
+            &SyncState::default(),
+        );
+        assert_eq!(names, vec!["Notes.pdf", "Notes (2).pdf", "Notes (3).pdf"]);
+    }
+
+    #[test]
+    fn names_differing_only_in_case_collide() {
+        let names = local_names(
+            vec![
+                file("a", "Paper", Some(FileType::Pdf)),
+                file("b", "paper", Some(FileType::Pdf)),
+            ],
+            &SyncState::default(),
+        );
+        assert_eq!(names, vec!["Paper.pdf", "paper (2).pdf"]);
+    }
+
+    #[test]
+    fn recorded_names_are_kept_and_reserved() {
+        let mut state = SyncState::default();
+        state.set_path("b", PathBuf::from("Notes.pdf"));
+        let names = local_names(
+            vec![
+                file("a", "Notes", Some(FileType::Pdf)),
+                file("b", "Notes", Some(FileType::Pdf)),
+            ],
+            &state,
+        );
+        assert_eq!(names, vec!["Notes (2).pdf", "Notes.pdf"]);
+    }
+
+    #[test]
+    fn collections_keep_dots_when_suffixed() {
+        let root = directory(
+            "",
+            "",
+            Vec::new(),
+            vec![
+                directory("a", "Version 2.0", Vec::new(), Vec::new()),
+                directory("b", "version 2.0", Vec::new(), Vec::new()),
+            ],
+        );
+        let mapped = map_to_local_names(&root, Path::new(""), &SyncState::default());
+        let names: Vec<&String> = mapped
+            .get_directories()
+            .iter()
+            .map(|d| d.get_visible_name())
+            .collect();
+        assert_eq!(names, vec!["Version 2.0", "version 2.0 (2)"]);
+    }
+
+    #[test]
+    fn long_names_are_cut_to_fit() {
+        let long = "x".repeat(300);
+        let names = local_names(
+            vec![
+                file("a", &long, Some(FileType::Pdf)),
+                file("b", &long, Some(FileType::Pdf)),
+            ],
+            &SyncState::default(),
+        );
+        assert!(
+            names.iter().all(|n| n.len() == MAX_NAME_BYTES),
+            "{:?}",
+            names
+        );
+        assert!(names[0].ends_with("x.pdf"));
+        assert!(names[1].ends_with("x (2).pdf"));
+    }
+
+    #[test]
+    fn long_names_are_not_cut_through_escapes_or_characters() {
+        let name = format!("{}a/b", "x".repeat(250));
+        let names = local_names(
+            vec![file("a", &name, Some(FileType::Pdf))],
+            &SyncState::default(),
+        );
+        // "a%2Fb" doesn't fit, and is cut before the escape
+        assert_eq!(names[0], format!("{}a.pdf", "x".repeat(250)));
+
+        assert_eq!(truncate_name("ab\u{e9}", 3), "ab");
+        assert_eq!(truncate_name("ab%2F", 4), "ab");
+        assert_eq!(truncate_name("ab%2F", 5), "ab%2F");
+    }
+}
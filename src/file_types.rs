@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::nodes::{Directory, DirectoryNode, Node};
+
+/// The kinds of documents remarko knows how to compare and transfer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileType {
+    Pdf,
+    Epub,
+    Notebook,
+}
+
+/// The types considered when `--types` is not given.
+pub const DEFAULT_TYPES: &str = "pdf,epub,notebook";
+
+/// Every type, e.g. to leave out local files remarko can't transfer.
+pub const ALL_TYPES: [FileType; 3] = [FileType::Pdf, FileType::Epub, FileType::Notebook];
+
+impl FileType {
+    /// Get the type of a local file from its extension.
+    pub fn from_extension(extension: &str) -> Option<FileType> {
+        match extension.to_lowercase().as_str() {
+            "pdf" => Some(FileType::Pdf),
+            "epub" => Some(FileType::Epub),
+            _ => None,
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Option<FileType> {
+        path.extension()
+            .and_then(|e| e.to_str())
+            .and_then(FileType::from_extension)
+    }
+
+    /// The extension given to this type when it is pulled to the local machine.
+    /// Notebooks are compared against their pdf exports.
+    pub fn local_extension(&self) -> &'static str {
+        match self {
+            FileType::Epub => "epub",
+            _ => "pdf",
+        }
+    }
+
+    /// Whether pull can copy this type to the local machine.
+    /// Notebooks can't be exported yet.
+    pub fn can_pull(&self) -> bool {
+        *self != FileType::Notebook
+    }
+
+    /// The format this type is stored as on the other side of a transfer.
+    /// Files with the same stem and transfer format are considered the same
+    /// document, e.g. `Notes.pdf` locally and the notebook `Notes` on remote.
+    pub fn transfer_format(&self) -> FileType {
+        match self {
+            FileType::Epub => FileType::Epub,
+            _ => FileType::Pdf,
+        }
+    }
+}
+
+impl fmt::Display for FileType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FileType::Pdf => "pdf",
+            FileType::Epub => "epub",
+            FileType::Notebook => "notebook",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for FileType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "pdf" => Ok(FileType::Pdf),
+            "epub" => Ok(FileType::Epub),
+            "notebook" => Ok(FileType::Notebook),
+            other => Err(format!("unknown file type: {}", other)),
+        }
+    }
+}
+
+/// Parse a comma separated list of types, e.g. `pdf,epub,notebook`.
+pub fn parse_types(types: &str) -> Result<Vec<FileType>, String> {
+    types
+        .split(',')
+        .filter(|t| !t.trim().is_empty())
+        .map(FileType::from_str)
+        .collect()
+}
+
+/// Parse the output of `ls` to get the type of each document.
+/// Documents with an `.epub` payload are epubs, those with only a `.pdf`
+/// are pdfs, and anything without a payload is a notebook.
+pub fn get_file_types_from_ls_output(ls_output: &str) -> HashMap<String, FileType> {
+    let mut file_types = HashMap::new();
+    for file in ls_output.trim().split('\n') {
+        let (hash, extension) = match file.split_once('.') {
+            Some(parts) => parts,
+            None => (file, ""),
+        };
+        let file_type = file_types
+            .entry(hash.to_string())
+            .or_insert(FileType::Notebook);
+        match extension {
+            "epub" => *file_type = FileType::Epub,
+            "pdf" if *file_type != FileType::Epub => *file_type = FileType::Pdf,
+            _ => (),
+        }
+    }
+    file_types
+}
+
+/// Remove the files whose type is not in `types`.
+pub fn filter_directory_by_type(directory: &Directory, types: &[FileType]) -> Directory {
+    let files = directory
+        .get_files()
+        .iter()
+        .filter(|f| f.get_file_type().is_some_and(|t| types.contains(&t)))
+        .cloned()
+        .collect();
+
+    let directories = directory
+        .get_directories()
+        .iter()
+        .map(|d| filter_directory_by_type(d, types))
+        .collect();
+
+    Directory::new(
+        directory.get_hash().clone(),
+        directory.get_metadata().clone(),
+        Some(files),
+        Some(directories),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_fs::remove_common_files_and_directories;
+    use crate::test_utils::{directory, file, file_names};
+
+    #[test]
+    fn from_extension_ignores_case_and_unknown_extensions() {
+        assert_eq!(FileType::from_extension("PDF"), Some(FileType::Pdf));
+        assert_eq!(FileType::from_extension("epub"), Some(FileType::Epub));
+        assert_eq!(FileType::from_extension("docx"), None);
+        assert_eq!(
+            FileType::from_path(Path::new("a/b.Epub")),
+            Some(FileType::Epub)
+        );
+        assert_eq!(FileType::from_path(Path::new("notes")), None);
+    }
+
+    #[test]
+    fn parse_types_accepts_lists_and_rejects_unknown_types() {
+        assert_eq!(
+            parse_types(" pdf, Notebook,").unwrap(),
+            vec![FileType::Pdf, FileType::Notebook]
+        );
+        assert!(parse_types("pdf,image").is_err());
+        assert_eq!(parse_types(DEFAULT_TYPES).unwrap(), ALL_TYPES.to_vec());
+    }
+
+    #[test]
+    fn file_types_from_ls_output_prefer_the_payload() {
+        let types = get_file_types_from_ls_output(
+            "a.metadata\na.content\na.pdf\nb.metadata\nb.epub\nb.pdf\nc.metadata\nc.content\n",
+        );
+        assert_eq!(types["a"], FileType::Pdf);
+        assert_eq!(types["b"], FileType::Epub);
+        assert_eq!(types["c"], FileType::Notebook);
+    }
+
+    #[test]
+    fn filter_directory_by_type_keeps_selected_types() {
+        let root = directory(
+            "",
+            "",
+            vec![
+                file("a", "a.pdf", Some(FileType::Pdf)),
+                file("b", "b.epub", Some(FileType::Epub)),
+                file("c", "notes.docx", None),
+            ],
+            Vec::new(),
+        );
+        let filtered = filter_directory_by_type(&root, &[FileType::Pdf]);
+        assert_eq!(file_names(&filtered), vec!["a.pdf"]);
+        let filtered = filter_directory_by_type(&root, &ALL_TYPES);
+        assert_eq!(file_names(&filtered), vec!["a.pdf", "b.epub"]);
+    }
+
+    #[test]
+    fn notebook_exports_match_before_filtering_by_type() {
+        let remote = directory(
+            "",
+            "",
+            vec![file("a", "Notes", Some(FileType::Notebook))],
+            Vec::new(),
+        );
+        let local = directory(
+            "",
+            "",
+            vec![file("", "Notes.pdf", Some(FileType::Pdf))],
+            Vec::new(),
+        );
+
+        let (unique_on_remote, unique_on_local) =
+            remove_common_files_and_directories(&remote, &local, false);
+        let unique_on_remote = filter_directory_by_type(&unique_on_remote, &[FileType::Pdf]);
+        let unique_on_local = filter_directory_by_type(&unique_on_local, &[FileType::Pdf]);
+
+        assert!(file_names(&unique_on_remote).is_empty());
+        assert!(file_names(&unique_on_local).is_empty());
+    }
+}
@@ -0,0 +1,818 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+use serde_json::Value;
+use ssh2::Session;
+use uuid::Uuid;
+
+use crate::constants::{COLLECTION, DIR, DOCUMENT, TRASH};
+use crate::nodes::Hash;
+use crate::profile::{get_page_ids, DeviceProfile};
+use crate::ssh_utils::{run_remote_command, update_remote_metadata, write_to_remote};
+
+// separates the files printed by `cat` when reading them all at once
+const FILE_MARKER: &str = "@@remarko-file@@";
+
+// the template xochitl gives new pages
+const BLANK_TEMPLATE: &str = "Blank";
+
+/// Something wrong with the files in the documents directory.
+#[derive(Clone, Debug)]
+pub enum Problem {
+    /// Files belonging to a hash which has no `.metadata`
+    MissingMetadata { hash: String, files: Vec<String> },
+    /// A `.metadata` or `.content` file which isn't valid json
+    InvalidJson { hash: String, file: String },
+    /// A document with no `.content`
+    MissingContent { hash: String },
+    /// A pdf or epub document without its pdf or epub
+    MissingPayload { hash: String, extension: String },
+    /// A parent which doesn't exist, isn't a collection or has been deleted
+    BadParent {
+        hash: String,
+        parent: String,
+        reason: &'static str,
+    },
+    /// A collection which is its own ancestor
+    Cycle { hash: String },
+    /// Thumbnails of pages which are no longer in the document
+    StaleThumbnails { hash: String, files: Vec<String> },
+    /// A `.pagedata` without a `.content`, or with a different number of
+    /// lines than the document has pages
+    StalePagedata {
+        hash: String,
+        lines: usize,
+        pages: Option<usize>,
+    },
+    /// A file whose name doesn't start with a hash
+    UnexpectedFile { file: String },
+}
+
+impl Problem {
+    /// Whether `repair` can fix this problem.
+    pub fn is_repairable(&self) -> bool {
+        !matches!(self, Problem::UnexpectedFile { .. })
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::MissingMetadata { hash, files } => {
+                write!(f, "{} has no metadata ({})", hash, files.join(", "))
+            }
+            Problem::InvalidJson { file, .. } => write!(f, "{} is not valid json", file),
+            Problem::MissingContent { hash } => write!(f, "{} has no .content", hash),
+            Problem::MissingPayload { hash, extension } => {
+                write!(f, "{} has no .{}", hash, extension)
+            }
+            Problem::BadParent {
+                hash,
+                parent,
+                reason,
+            } => write!(f, "{} has parent {} which {}", hash, parent, reason),
+            Problem::Cycle { hash } => write!(f, "{} is inside itself", hash),
+            Problem::StaleThumbnails { hash, files } => {
+                write!(f, "{} has {} stale thumbnails", hash, files.len())
+            }
+            Problem::StalePagedata {
+                hash, pages: None, ..
+            } => write!(f, "{} has a .pagedata but no .content", hash),
+            Problem::StalePagedata {
+                hash,
+                lines,
+                pages: Some(pages),
+            } => write!(
+                f,
+                "{} has {} templates in its .pagedata for {} pages",
+                hash, lines, pages
+            ),
+            Problem::UnexpectedFile { file } => write!(f, "{} is not a document file", file),
+        }
+    }
+}
+
+/// The files in the documents directory, read with a few commands rather
+/// than one per file.
+struct DeviceFiles {
+    /// The names of the files and directories in the documents directory,
+    /// grouped by hash
+    groups: BTreeMap<String, Vec<String>>,
+    /// Names which don't start with a hash
+    unexpected: Vec<String>,
+    /// The contents of every `.metadata`, `.content` and `.pagedata`, by
+    /// file name
+    contents: HashMap<String, String>,
+    /// Thumbnails as "<hash>.thumbnails/<page>.png"
+    thumbnails: Vec<String>,
+    /// Pages of notebooks and annotations as "<hash>/<page>.rm"
+    pages: Vec<String>,
+}
+
+impl DeviceFiles {
+    /// Whether a hash has any pages in its directory.
+    fn has_pages(&self, hash: &str) -> bool {
+        !self.page_ids(hash).is_empty()
+    }
+
+    /// The ids of the pages in a hash's directory, sorted.
+    fn page_ids(&self, hash: &str) -> Vec<&str> {
+        let prefix = format!("{}/", hash);
+        let mut ids: Vec<&str> = self
+            .pages
+            .iter()
+            .filter_map(|page| page.strip_prefix(&prefix)?.strip_suffix(".rm"))
+            .collect();
+        ids.sort();
+        ids
+    }
+}
+
+fn read_device_files(sess: &mut Session) -> DeviceFiles {
+    let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut unexpected = Vec::new();
+    for name in run_remote_command(sess, &format!("ls -A {}", DIR)).lines() {
+        let hash = name.split('.').next().unwrap_or_default();
+        if Uuid::parse_str(hash).is_ok() {
+            groups
+                .entry(hash.to_string())
+                .or_default()
+                .push(name.to_string());
+        } else {
+            unexpected.push(name.to_string());
+        }
+    }
+
+    let output = run_remote_command(
+        sess,
+        &format!(
+            "cd {} && for f in *.metadata *.content *.pagedata; do [ -f \"$f\" ] && printf '\\n{} %s\\n' \"$f\" && cat \"$f\"; done",
+            DIR, FILE_MARKER
+        ),
+    );
+    let mut contents = HashMap::new();
+    for chunk in output.split(&format!("\n{} ", FILE_MARKER)).skip(1) {
+        if let Some((name, content)) = chunk.split_once('\n') {
+            contents.insert(name.to_string(), content.to_string());
+        }
+    }
+
+    let find = |sess: &mut Session, pattern: &str| -> Vec<String> {
+        run_remote_command(
+            sess,
+            &format!(
+                "cd {} && find . -mindepth 2 -maxdepth 2 -path '{}'",
+                DIR, pattern
+            ),
+        )
+        .lines()
+        .map(|line| line.trim_start_matches("./").to_string())
+        .collect()
+    };
+    let thumbnails = find(sess, "./*.thumbnails/*");
+    let pages = find(sess, "./*/*.rm");
+
+    DeviceFiles {
+        groups,
+        unexpected,
+        contents,
+        thumbnails,
+        pages,
+    }
+}
+
+/// Check the documents directory on the remarkable for problems.
+pub fn check_device(sess: &mut Session) -> Vec<Problem> {
+    let files = read_device_files(sess);
+    let mut problems: Vec<Problem> = files
+        .unexpected
+        .iter()
+        .map(|file| Problem::UnexpectedFile { file: file.clone() })
+        .collect();
+
+    // parse all metadata first, since parents are checked against it
+    let mut metadata: BTreeMap<String, Value> = BTreeMap::new();
+    for (hash, names) in &files.groups {
+        let file = format!("{}.metadata", hash);
+        if !names.contains(&file) {
+            problems.push(Problem::MissingMetadata {
+                hash: hash.clone(),
+                files: names.clone(),
+            });
+            continue;
+        }
+        match files.contents.get(&file).map(|c| serde_json::from_str(c)) {
+            Some(Ok(Value::Object(object))) => {
+                metadata.insert(hash.clone(), Value::Object(object));
+            }
+            _ => problems.push(Problem::InvalidJson {
+                hash: hash.clone(),
+                file,
+            }),
+        }
+    }
+
+    for (hash, value) in &metadata {
+        let names = &files.groups[hash];
+        if value["type"] == DOCUMENT {
+            problems.extend(check_document(hash, names, &files));
+        }
+        if value["deleted"] == true {
+            continue;
+        }
+        let parent = value["parent"].as_str().unwrap_or_default();
+        if parent.is_empty() || parent == TRASH {
+            continue;
+        }
+        let reason = match metadata.get(parent) {
+            None => Some("doesn't exist"),
+            Some(p) if p["type"] != COLLECTION => Some("isn't a collection"),
+            Some(p) if p["deleted"] == true => Some("has been deleted"),
+            Some(_) => None,
+        };
+        if let Some(reason) = reason {
+            problems.push(Problem::BadParent {
+                hash: hash.clone(),
+                parent: parent.to_string(),
+                reason,
+            });
+        }
+    }
+
+    problems.extend(find_cycles(&metadata));
+    problems
+}
+
+fn check_document(hash: &str, names: &[String], files: &DeviceFiles) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let file = format!("{}.content", hash);
+    let pagedata = files.contents.get(&format!("{}.pagedata", hash));
+    if !names.contains(&file) {
+        problems.push(Problem::MissingContent {
+            hash: hash.to_string(),
+        });
+        if let Some(pagedata) = pagedata {
+            problems.push(Problem::StalePagedata {
+                hash: hash.to_string(),
+                lines: pagedata.lines().count(),
+                pages: None,
+            });
+        }
+        return problems;
+    }
+    let content: Value = match files.contents.get(&file).map(|c| serde_json::from_str(c)) {
+        Some(Ok(content)) => content,
+        _ => {
+            problems.push(Problem::InvalidJson {
+                hash: hash.to_string(),
+                file,
+            });
+            return problems;
+        }
+    };
+
+    if let Some(extension) = content["fileType"].as_str() {
+        if (extension == "pdf" || extension == "epub")
+            && !names.contains(&format!("{}.{}", hash, extension))
+        {
+            problems.push(Problem::MissingPayload {
+                hash: hash.to_string(),
+                extension: extension.to_string(),
+            });
+        }
+    }
+
+    if let Some(pages) = get_page_ids(&content) {
+        if let Some(pagedata) = pagedata {
+            let lines = pagedata.lines().count();
+            if lines != pages.len() {
+                problems.push(Problem::StalePagedata {
+                    hash: hash.to_string(),
+                    lines,
+                    pages: Some(pages.len()),
+                });
+            }
+        }
+
+        let pages: HashSet<&str> = pages.into_iter().collect();
+        let prefix = format!("{}.thumbnails/", hash);
+        let stale: Vec<String> = files
+            .thumbnails
+            .iter()
+            .filter(|path| match path.strip_prefix(&prefix) {
+                Some(name) => !pages.contains(name.split('.').next().unwrap_or_default()),
+                None => false,
+            })
+            .cloned()
+            .collect();
+        if !stale.is_empty() {
+            problems.push(Problem::StaleThumbnails {
+                hash: hash.to_string(),
+                files: stale,
+            });
+        }
+    }
+
+    problems
+}
+
+/// Follow the parents of every node, reporting each cycle once.
+fn find_cycles(metadata: &BTreeMap<String, Value>) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut checked: HashSet<&str> = HashSet::new();
+    for start in metadata.keys() {
+        let mut chain: Vec<&str> = Vec::new();
+        let mut current = start.as_str();
+        while !checked.contains(current) {
+            if let Some(i) = chain.iter().position(|h| *h == current) {
+                let hash = chain[i..].iter().min().unwrap();
+                problems.push(Problem::Cycle {
+                    hash: hash.to_string(),
+                });
+                break;
+            }
+            chain.push(current);
+            current = match metadata.get(current).and_then(|m| m["parent"].as_str()) {
+                Some(parent) if metadata.contains_key(parent) => parent,
+                _ => break,
+            };
+        }
+        checked.extend(chain);
+    }
+    problems
+}
+
+/// Fix problems found by `check_device`.
+///
+/// Documents without metadata, or with metadata which can't be read, are
+/// recovered into the root if they have a pdf, epub, pages or `.content`, and
+/// deleted if they have none of these. Documents without a payload are moved
+/// to the trash, bad parents and cycles are fixed by moving to the root and
+/// stale thumbnails are deleted. A `.pagedata` with the wrong number of
+/// templates is cut short or padded with blank pages, and one without a
+/// `.content` is deleted unless the notebook still has its pages.
+pub fn repair_device(
+    problems: &[Problem],
+    profile: &DeviceProfile,
+    sess: &mut Session,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let files = read_device_files(sess);
+    for repair in plan_repairs(problems, &files, profile)? {
+        match repair {
+            Repair::Write { file, contents } => {
+                write_to_remote(contents.as_bytes(), &format!("{}/{}", DIR, file), sess);
+            }
+            Repair::SetParent { hash, parent } => {
+                update_remote_metadata(sess, &hash, |metadata| {
+                    metadata.set_parent(parent);
+                })?;
+            }
+            Repair::Remove { files } => {
+                let paths: Vec<String> = files.iter().map(|f| format!("{}/{}", DIR, f)).collect();
+                run_remote_command(sess, &format!("rm -rf {}", paths.join(" ")));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A change `repair_device` makes in the documents directory.
+#[derive(Clone, Debug, PartialEq)]
+enum Repair {
+    Write {
+        file: String,
+        contents: String,
+    },
+    SetParent {
+        hash: String,
+        parent: String,
+    },
+    /// Files and directories to delete, which may be shell globs
+    Remove {
+        files: Vec<String>,
+    },
+}
+
+fn plan_repairs(
+    problems: &[Problem],
+    files: &DeviceFiles,
+    profile: &DeviceProfile,
+) -> Result<Vec<Repair>, serde_json::Error> {
+    let names = &files.groups;
+    let mut repairs = Vec::new();
+    for problem in problems {
+        match problem {
+            Problem::MissingMetadata { hash, .. } => {
+                repairs.extend(recover_document(hash, files, profile)?);
+            }
+            Problem::InvalidJson { hash, file } if file.ends_with(".metadata") => {
+                repairs.extend(recover_document(hash, files, profile)?);
+            }
+            Problem::InvalidJson { hash, .. } | Problem::MissingContent { hash } => {
+                let extension = payload_extension(hash, names.get(hash)).unwrap_or("notebook");
+                repairs.push(write_content(hash, extension, files, profile)?);
+            }
+            Problem::MissingPayload { hash, .. } => repairs.push(Repair::SetParent {
+                hash: hash.clone(),
+                parent: TRASH.to_string(),
+            }),
+            Problem::BadParent { hash, .. } | Problem::Cycle { hash } => {
+                repairs.push(Repair::SetParent {
+                    hash: hash.clone(),
+                    parent: String::new(),
+                })
+            }
+            Problem::StaleThumbnails { files, .. } => repairs.push(Repair::Remove {
+                files: files.clone(),
+            }),
+            Problem::StalePagedata {
+                hash, pages: None, ..
+            } => {
+                let pages = match payload_extension(hash, names.get(hash)) {
+                    Some(_) => 0,
+                    None => files.page_ids(hash).len(),
+                };
+                repairs.push(match pages {
+                    0 => Repair::Remove {
+                        files: vec![format!("{}.pagedata", hash)],
+                    },
+                    pages => fit_pagedata_file(hash, pages, files),
+                });
+            }
+            Problem::StalePagedata {
+                hash,
+                pages: Some(pages),
+                ..
+            } => repairs.push(fit_pagedata_file(hash, *pages, files)),
+            Problem::UnexpectedFile { .. } => {}
+        }
+    }
+    Ok(repairs)
+}
+
+fn payload_extension(hash: &str, names: Option<&Vec<String>>) -> Option<&'static str> {
+    let names = names?;
+    ["pdf", "epub"]
+        .into_iter()
+        .find(|extension| names.contains(&format!("{}.{}", hash, extension)))
+}
+
+/// Give a document without readable metadata new metadata in the root. It is
+/// a notebook unless it has a pdf or epub. Its files are only deleted if it
+/// has no pdf, epub, pages or `.content`, since then there is nothing left to
+/// recover.
+fn recover_document(
+    hash: &str,
+    files: &DeviceFiles,
+    profile: &DeviceProfile,
+) -> Result<Vec<Repair>, serde_json::Error> {
+    let names = files.groups.get(hash);
+    let has_content = names.is_some_and(|n| n.contains(&format!("{}.content", hash)));
+    let extension = match payload_extension(hash, names) {
+        Some(extension) => extension,
+        None if has_content || files.has_pages(hash) => "notebook",
+        None => {
+            return Ok(vec![Repair::Remove {
+                files: vec![hash.to_string(), format!("{}.*", hash)],
+            }]);
+        }
+    };
+
+    let name = format!("Recovered {}", &hash[..8]);
+    let metadata = profile.new_metadata(&name, &Hash::new(String::new()), DOCUMENT);
+    let mut repairs = vec![Repair::Write {
+        file: format!("{}.metadata", hash),
+        contents: serde_json::to_string_pretty(&metadata)?,
+    }];
+    if !has_content {
+        repairs.push(write_content(hash, extension, files, profile)?);
+    }
+    Ok(repairs)
+}
+
+/// Give a `.pagedata` one template per page, dropping the templates of pages
+/// which no longer exist and giving new pages a blank one.
+fn fit_pagedata(pagedata: &str, pages: usize) -> String {
+    let mut templates: Vec<&str> = pagedata.lines().take(pages).collect();
+    templates.resize(pages, BLANK_TEMPLATE);
+    templates.iter().map(|t| format!("{}\n", t)).collect()
+}
+
+fn fit_pagedata_file(hash: &str, pages: usize, files: &DeviceFiles) -> Repair {
+    let file = format!("{}.pagedata", hash);
+    let pagedata = files.contents.get(&file).map(String::as_str);
+    Repair::Write {
+        contents: fit_pagedata(pagedata.unwrap_or_default(), pages),
+        file,
+    }
+}
+
+/// Write a new `.content` for a document. A notebook keeps the pages still
+/// in its directory, with the templates from its `.pagedata`. Their order
+/// isn't known, so they are put in the order of their names. Pdfs and epubs
+/// get their pages from the payload when xochitl opens them.
+fn write_content(
+    hash: &str,
+    extension: &str,
+    files: &DeviceFiles,
+    profile: &DeviceProfile,
+) -> Result<Repair, serde_json::Error> {
+    let page_ids = match extension {
+        "notebook" => files.page_ids(hash),
+        _ => Vec::new(),
+    };
+    let content = match page_ids.is_empty() {
+        true => profile.new_content(extension),
+        false => {
+            let pagedata = files.contents.get(&format!("{}.pagedata", hash));
+            let templates: Vec<&str> = pagedata.map(|p| p.lines().collect()).unwrap_or_default();
+            let pages: Vec<(&str, &str)> = page_ids
+                .iter()
+                .enumerate()
+                .map(|(i, id)| (*id, templates.get(i).copied().unwrap_or(BLANK_TEMPLATE)))
+                .collect();
+            profile.content_with_pages(extension, &pages)
+        }
+    };
+    Ok(Repair::Write {
+        file: format!("{}.content", hash),
+        contents: serde_json::to_string_pretty(&content)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const HASH: &str = "0a4b7ac4-2e55-4f0b-8c3d-9b0e1f2a3b4c";
+
+    fn device_files(
+        names: &[&str],
+        contents: &[(&str, String)],
+        thumbnails: &[&str],
+    ) -> DeviceFiles {
+        let names: Vec<String> = names.iter().map(|n| format!("{}{}", HASH, n)).collect();
+        DeviceFiles {
+            groups: BTreeMap::from([(HASH.to_string(), names)]),
+            unexpected: Vec::new(),
+            contents: contents
+                .iter()
+                .map(|(n, c)| (format!("{}{}", HASH, n), c.clone()))
+                .collect(),
+            thumbnails: thumbnails
+                .iter()
+                .map(|t| format!("{}.thumbnails/{}", HASH, t))
+                .collect(),
+            pages: Vec::new(),
+        }
+    }
+
+    fn check(files: &DeviceFiles) -> Vec<String> {
+        check_document(HASH, &files.groups[HASH], files)
+            .iter()
+            .map(|p| p.to_string().replace(HASH, "doc"))
+            .collect()
+    }
+
+    #[test]
+    fn documents_without_content_are_reported() {
+        let files = device_files(
+            &[".metadata", ".pagedata"],
+            &[(".pagedata", "Blank\n".to_string())],
+            &[],
+        );
+        assert_eq!(
+            check(&files),
+            vec!["doc has no .content", "doc has a .pagedata but no .content"]
+        );
+    }
+
+    #[test]
+    fn invalid_content_is_reported() {
+        let files = device_files(
+            &[".metadata", ".content"],
+            &[(".content", "{".to_string())],
+            &[],
+        );
+        assert_eq!(check(&files), vec!["doc.content is not valid json"]);
+    }
+
+    #[test]
+    fn pdfs_without_their_pdf_are_reported() {
+        let content = json!({"fileType": "pdf", "pages": []}).to_string();
+        let files = device_files(&[".metadata", ".content"], &[(".content", content)], &[]);
+        assert_eq!(check(&files), vec!["doc has no .pdf"]);
+    }
+
+    #[test]
+    fn thumbnails_and_pagedata_must_match_the_pages() {
+        let content = json!({"fileType": "notebook", "pages": ["p1", "p2"]}).to_string();
+        let files = device_files(
+            &[".metadata", ".content", ".pagedata", ".thumbnails"],
+            &[(".content", content), (".pagedata", "Blank\n".to_string())],
+            &["p1.png", "p3.png"],
+        );
+        assert_eq!(
+            check(&files),
+            vec![
+                "doc has 1 templates in its .pagedata for 2 pages",
+                "doc has 1 stale thumbnails",
+            ]
+        );
+    }
+
+    #[test]
+    fn healthy_documents_have_no_problems() {
+        let content = json!({
+            "fileType": "pdf",
+            "formatVersion": 2,
+            "cPages": {"pages": [{"id": "p1"}, {"id": "p2", "deleted": {"value": 1}}]},
+        })
+        .to_string();
+        let files = device_files(
+            &[".metadata", ".content", ".pdf", ".pagedata", ".thumbnails"],
+            &[
+                (".content", content),
+                (".pagedata", "Blank\nLined\n".to_string()),
+            ],
+            &["p1.png"],
+        );
+        assert!(check(&files).is_empty());
+    }
+
+    #[test]
+    fn pagedata_is_fitted_to_the_pages() {
+        assert_eq!(fit_pagedata("Lined\nGrid\nBlank\n", 2), "Lined\nGrid\n");
+        assert_eq!(fit_pagedata("Lined\n", 3), "Lined\nBlank\nBlank\n");
+        assert_eq!(fit_pagedata("", 0), "");
+    }
+
+    fn written(repair: &Repair) -> (&str, Value) {
+        match repair {
+            Repair::Write { file, contents } => (file, serde_json::from_str(contents).unwrap()),
+            other => panic!("expected a write, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn recovered_notebooks_keep_their_pages() {
+        let mut files = device_files(
+            &["", ".pagedata"],
+            &[(".pagedata", "Lined\nGrid\n".to_string())],
+            &[],
+        );
+        files.pages = ["p2.rm", "p1.rm", "p3.rm"]
+            .iter()
+            .map(|page| format!("{}/{}", HASH, page))
+            .collect();
+        let problems = [Problem::MissingMetadata {
+            hash: HASH.to_string(),
+            files: Vec::new(),
+        }];
+        let profile = DeviceProfile::for_firmware("3.5".parse().unwrap());
+        let repairs = plan_repairs(&problems, &files, &profile).unwrap();
+        assert_eq!(repairs.len(), 2);
+
+        let (file, metadata) = written(&repairs[0]);
+        assert_eq!(file, format!("{}.metadata", HASH));
+        assert_eq!(metadata["visibleName"], "Recovered 0a4b7ac4");
+        assert_eq!(metadata["parent"], "");
+
+        let (file, content) = written(&repairs[1]);
+        assert_eq!(file, format!("{}.content", HASH));
+        assert_eq!(content["fileType"], "notebook");
+        assert_eq!(get_page_ids(&content), Some(vec!["p1", "p2", "p3"]));
+        let templates: Vec<&str> = content["cPages"]["pages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p["template"]["value"].as_str().unwrap())
+            .collect();
+        assert_eq!(templates, vec!["Lined", "Grid", "Blank"]);
+
+        // older firmware lists the pages and keeps the templates in .pagedata
+        let profile = DeviceProfile::for_firmware("2.15".parse().unwrap());
+        let repairs = plan_repairs(&problems, &files, &profile).unwrap();
+        let (_, content) = written(&repairs[1]);
+        assert_eq!(content["pages"], json!(["p1", "p2", "p3"]));
+    }
+
+    #[test]
+    fn documents_with_nothing_to_recover_are_deleted() {
+        let files = device_files(&[".local"], &[], &[]);
+        let problems = [Problem::MissingMetadata {
+            hash: HASH.to_string(),
+            files: Vec::new(),
+        }];
+        let profile = DeviceProfile::for_firmware("3.5".parse().unwrap());
+        assert_eq!(
+            plan_repairs(&problems, &files, &profile).unwrap(),
+            vec![Repair::Remove {
+                files: vec![HASH.to_string(), format!("{}.*", HASH)]
+            }]
+        );
+    }
+
+    #[test]
+    fn content_and_pagedata_of_pdfs_ignore_annotated_pages() {
+        let mut files = device_files(
+            &[".metadata", ".pdf", "", ".pagedata"],
+            &[(".pagedata", "Blank\n".to_string())],
+            &[],
+        );
+        files.pages = vec![format!("{}/p1.rm", HASH)];
+        let problems = [
+            Problem::MissingContent {
+                hash: HASH.to_string(),
+            },
+            Problem::StalePagedata {
+                hash: HASH.to_string(),
+                lines: 1,
+                pages: None,
+            },
+        ];
+        let profile = DeviceProfile::for_firmware("3.5".parse().unwrap());
+        let repairs = plan_repairs(&problems, &files, &profile).unwrap();
+        let (_, content) = written(&repairs[0]);
+        assert_eq!(content, json!({"fileType": "pdf", "formatVersion": 2}));
+        assert_eq!(
+            repairs[1],
+            Repair::Remove {
+                files: vec![format!("{}.pagedata", HASH)]
+            }
+        );
+    }
+
+    #[test]
+    fn pagedata_of_notebooks_missing_their_content_is_fitted() {
+        let mut files = device_files(
+            &[".metadata", "", ".pagedata"],
+            &[(".pagedata", "Lined\n".to_string())],
+            &[],
+        );
+        files.pages = vec![format!("{}/p1.rm", HASH), format!("{}/p2.rm", HASH)];
+        let problems = [Problem::StalePagedata {
+            hash: HASH.to_string(),
+            lines: 1,
+            pages: None,
+        }];
+        let profile = DeviceProfile::for_firmware("2.15".parse().unwrap());
+        assert_eq!(
+            plan_repairs(&problems, &files, &profile).unwrap(),
+            vec![Repair::Write {
+                file: format!("{}.pagedata", HASH),
+                contents: "Lined\nBlank\n".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn misplaced_documents_are_moved() {
+        let files = device_files(&[".metadata"], &[], &[]);
+        let problems = [
+            Problem::MissingPayload {
+                hash: "a".to_string(),
+                extension: "pdf".to_string(),
+            },
+            Problem::BadParent {
+                hash: "b".to_string(),
+                parent: "c".to_string(),
+                reason: "doesn't exist",
+            },
+            Problem::Cycle {
+                hash: "d".to_string(),
+            },
+            Problem::UnexpectedFile {
+                file: ".DS_Store".to_string(),
+            },
+        ];
+        let profile = DeviceProfile::for_firmware("3.5".parse().unwrap());
+        let parent = |hash: &str, parent: &str| Repair::SetParent {
+            hash: hash.to_string(),
+            parent: parent.to_string(),
+        };
+        assert_eq!(
+            plan_repairs(&problems, &files, &profile).unwrap(),
+            vec![parent("a", TRASH), parent("b", ""), parent("d", "")]
+        );
+    }
+
+    #[test]
+    fn each_cycle_is_reported_once() {
+        let metadata = BTreeMap::from([
+            ("a".to_string(), json!({"parent": "b"})),
+            ("b".to_string(), json!({"parent": "a"})),
+            ("c".to_string(), json!({"parent": "a"})),
+            ("d".to_string(), json!({"parent": "d"})),
+            ("e".to_string(), json!({"parent": ""})),
+            ("f".to_string(), json!({"parent": "missing"})),
+        ]);
+        let cycles: Vec<String> = find_cycles(&metadata)
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        assert_eq!(cycles, vec!["a is inside itself", "d is inside itself"]);
+    }
+}
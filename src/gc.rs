@@ -0,0 +1,148 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+use colored::*;
+use ssh2::Session;
+use uuid::Uuid;
+
+use crate::constants::DIR;
+use crate::du::get_sizes_by_hash;
+use crate::remarkable_trees::get_hash_from_path;
+use crate::snapshots::format_size;
+use crate::ssh_utils::run_remote_command;
+
+/// Files in the documents directory belonging to a hash with no metadata,
+/// e.g. the pages, thumbnails and caches of a document which is long gone.
+#[derive(Clone, Debug)]
+pub struct Garbage {
+    pub hash: String,
+    /// The names of the files and directories in the documents directory
+    pub names: Vec<String>,
+    /// The total size of everything in them, in bytes
+    pub size: u64,
+}
+
+/// Find everything in the documents directory which doesn't belong to a
+/// document or collection with a `.metadata`. Files whose names don't start
+/// with a hash are left alone.
+pub fn find_garbage(sess: &mut Session) -> Vec<Garbage> {
+    let groups = find_orphans(&run_remote_command(sess, &format!("ls -A {}", DIR)));
+    if groups.is_empty() {
+        return Vec::new();
+    }
+
+    let sizes = get_sizes_by_hash(sess);
+
+    groups
+        .into_iter()
+        .map(|(hash, names)| Garbage {
+            size: sizes.get(&hash).copied().unwrap_or_default(),
+            hash,
+            names,
+        })
+        .collect()
+}
+
+/// Group the names in `ls` output of the documents directory by hash, keeping
+/// only the hashes without a `.metadata`.
+fn find_orphans(ls_output: &str) -> BTreeMap<String, Vec<String>> {
+    let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for name in ls_output.lines() {
+        if let Some(hash) = get_hash_from_path(Path::new(name)) {
+            if Uuid::parse_str(&hash).is_ok() {
+                groups.entry(hash).or_default().push(name.to_string());
+            }
+        }
+    }
+    let live: HashSet<String> = groups
+        .iter()
+        .filter(|(hash, names)| names.contains(&format!("{}.metadata", hash)))
+        .map(|(hash, _)| hash.clone())
+        .collect();
+    groups.retain(|hash, _| !live.contains(hash));
+    groups
+}
+
+pub fn print_garbage(garbage: &[Garbage]) {
+    for entry in garbage {
+        println!(
+            "  {} {:>10}  {}",
+            entry.hash.bold().purple(),
+            format_size(entry.size),
+            entry.names.join(", ")
+        );
+    }
+}
+
+/// Delete garbage from the documents directory, returning the bytes reclaimed.
+pub fn collect_garbage(garbage: &[Garbage], sess: &mut Session) -> u64 {
+    for entry in garbage {
+        run_remote_command(sess, &removal_command(entry));
+    }
+    garbage.iter().map(|g| g.size).sum()
+}
+
+/// The command deleting an entry's files, quoted for the remote shell.
+fn removal_command(entry: &Garbage) -> String {
+    let paths: Vec<String> = entry
+        .names
+        .iter()
+        .map(|name| format!("'{}/{}'", DIR, name.replace('\'', "'\\''")))
+        .collect();
+    format!("rm -rf {}", paths.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GONE: &str = "0a4b7ac4-2e55-4f0b-8c3d-9b0e1f2a3b4c";
+    const LIVE: &str = "5d2c8e1f-7b3a-4c9d-a1e2-3f4b5c6d7e8f";
+
+    #[test]
+    fn only_hashes_without_metadata_are_garbage() {
+        let ls_output = [
+            GONE.to_string(),
+            format!("{}.content", GONE),
+            format!("{}.thumbnails", GONE),
+            LIVE.to_string(),
+            format!("{}.metadata", LIVE),
+            format!("{}.pdf", LIVE),
+            // not named after a hash, so left alone
+            "notes.txt".to_string(),
+            ".tree".to_string(),
+            "0a4b7ac4.pdf".to_string(),
+        ]
+        .join("\n");
+        let orphans = find_orphans(&ls_output);
+        assert_eq!(
+            orphans,
+            BTreeMap::from([(
+                GONE.to_string(),
+                vec![
+                    GONE.to_string(),
+                    format!("{}.content", GONE),
+                    format!("{}.thumbnails", GONE),
+                ]
+            )])
+        );
+        assert!(find_orphans("").is_empty());
+    }
+
+    #[test]
+    fn only_the_garbage_is_removed() {
+        let entry = Garbage {
+            hash: GONE.to_string(),
+            names: vec![GONE.to_string(), format!("{}.it's", GONE)],
+            size: 0,
+        };
+        assert_eq!(
+            removal_command(&entry),
+            format!(
+                "rm -rf '{dir}/{gone}' '{dir}/{gone}.it'\\''s'",
+                dir = DIR,
+                gone = GONE
+            )
+        );
+    }
+}
@@ -0,0 +1,254 @@
+use std::path::{Path, PathBuf};
+
+use dirs::config_dir;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+
+use crate::constants::{IGNORE_FILE, REMOTE_IGNORE_FILE};
+use crate::nodes::{Directory, DirectoryNode, Node};
+
+/// Ignore rules gathered from the `.remarkoignore` files found while walking
+/// a local directory.
+///
+/// Each nested ignore file is stacked on top of the rules of its parents, so
+/// the deepest file with a matching pattern decides, like `.gitignore`.
+#[derive(Clone, Default)]
+pub struct LocalIgnore {
+    rules: Vec<Gitignore>,
+}
+
+impl LocalIgnore {
+    pub fn new() -> LocalIgnore {
+        LocalIgnore { rules: Vec::new() }
+    }
+
+    /// Returns these rules extended with the `.remarkoignore` in `directory`,
+    /// if there is one.
+    pub fn with_directory(&self, directory: &Path) -> Result<LocalIgnore, ignore::Error> {
+        let ignore_file = directory.join(IGNORE_FILE);
+        if !ignore_file.is_file() {
+            return Ok(self.clone());
+        }
+
+        let mut builder = GitignoreBuilder::new(directory);
+        if let Some(error) = builder.add(&ignore_file) {
+            return Err(error);
+        }
+
+        let mut rules = self.rules.clone();
+        rules.push(builder.build()?);
+        Ok(LocalIgnore { rules })
+    }
+
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        for rules in self.rules.iter().rev() {
+            match rules.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => continue,
+            }
+        }
+        false
+    }
+}
+
+/// Load the remote ignore patterns from `<config dir>/remarko/remoteignore`.
+///
+/// The file uses gitignore syntax and is matched against paths in the
+/// remote tree, starting from the root, e.g. `/Papers/drafts/`.
+pub fn load_remote_ignore() -> Result<Option<Gitignore>, ignore::Error> {
+    let ignore_file = match config_dir() {
+        Some(config_dir) => config_dir.join("remarko").join(REMOTE_IGNORE_FILE),
+        None => return Ok(None),
+    };
+    if !ignore_file.is_file() {
+        return Ok(None);
+    }
+
+    let mut builder = GitignoreBuilder::new("/");
+    if let Some(error) = builder.add(&ignore_file) {
+        return Err(error);
+    }
+    Ok(Some(builder.build()?))
+}
+
+/// Remove the files and directories matched by `rules` from a remote directory.
+///
+/// `path` is the resolved remote path of `directory`, e.g. `Papers/2023`,
+/// or an empty string for the root. A `/` in a visible name is matched as
+/// `%2F`, like in its local name, so it can't be taken for a nested path.
+pub fn filter_remote_directory(directory: &Directory, path: &str, rules: &Gitignore) -> Directory {
+    let mut rule_path = PathBuf::from("/");
+    rule_path.extend(path.split('/').filter(|part| !part.is_empty()));
+    filter_below(directory, &rule_path, rules)
+}
+
+fn filter_below(directory: &Directory, path: &Path, rules: &Gitignore) -> Directory {
+    let child_path = |name: &str| path.join(name.replace('/', "%2F"));
+
+    let files = directory
+        .get_files()
+        .iter()
+        .filter(|f| {
+            !rules
+                .matched(child_path(f.get_visible_name()), false)
+                .is_ignore()
+        })
+        .cloned()
+        .collect();
+
+    let directories = directory
+        .get_directories()
+        .iter()
+        .filter(|d| {
+            !rules
+                .matched(child_path(d.get_visible_name()), true)
+                .is_ignore()
+        })
+        .map(|d| filter_below(d, &child_path(d.get_visible_name()), rules))
+        .collect();
+
+    Directory::new(
+        directory.get_hash().clone(),
+        directory.get_metadata().clone(),
+        Some(files),
+        Some(directories),
+    )
+}
+
+/// Apply the remote ignore file, if any, to `directory`.
+pub fn apply_remote_ignore(directory: Directory, path: &str) -> Result<Directory, ignore::Error> {
+    match load_remote_ignore()? {
+        Some(rules) => Ok(filter_remote_directory(&directory, path, &rules)),
+        None => Ok(directory),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, write};
+
+    use super::*;
+    use crate::test_utils::{directory, file, file_names};
+
+    fn remote_rules(lines: &[&str]) -> Gitignore {
+        let mut builder = GitignoreBuilder::new("/");
+        for line in lines {
+            builder.add_line(None, line).unwrap();
+        }
+        builder.build().unwrap()
+    }
+
+    fn remote_tree() -> Directory {
+        directory(
+            "",
+            "",
+            vec![file("a", "Quick sheets", None), file("b", "a/b", None)],
+            vec![
+                directory(
+                    "c",
+                    "Papers",
+                    vec![file("d", "Paper", None), file("e", "Draft 1", None)],
+                    vec![directory(
+                        "f",
+                        "drafts",
+                        vec![file("g", "Draft 2", None)],
+                        Vec::new(),
+                    )],
+                ),
+                directory("h", "/Papers", vec![file("i", "Paper", None)], Vec::new()),
+            ],
+        )
+    }
+
+    fn names(directory: &Directory) -> Vec<String> {
+        let mut names = file_names(directory);
+        for sub_directory in directory.get_directories() {
+            let prefix = sub_directory.get_visible_name();
+            names.push(format!("{}/", prefix));
+            names.extend(names_in(sub_directory, prefix));
+        }
+        names
+    }
+
+    fn names_in(directory: &Directory, prefix: &str) -> Vec<String> {
+        names(directory)
+            .into_iter()
+            .map(|name| format!("{} > {}", prefix, name))
+            .collect()
+    }
+
+    #[test]
+    fn remote_rules_are_anchored_at_the_root() {
+        let rules = remote_rules(&["/Quick sheets", "/Papers/drafts/"]);
+        let filtered = filter_remote_directory(&remote_tree(), "", &rules);
+        assert_eq!(
+            names(&filtered),
+            vec![
+                "a/b",
+                "Papers/",
+                "Papers > Paper",
+                "Papers > Draft 1",
+                "/Papers/",
+                "/Papers > Paper",
+            ]
+        );
+    }
+
+    #[test]
+    fn remote_rules_use_the_path_of_a_sub_directory() {
+        let papers = remote_tree().get_directories()[0].clone();
+        let rules = remote_rules(&["/Papers/Draft*", "!/Papers/Draft 1"]);
+        let filtered = filter_remote_directory(&papers, "Papers", &rules);
+        assert_eq!(file_names(&filtered), vec!["Paper", "Draft 1"]);
+        // the directory doesn't match the file pattern
+        assert_eq!(filtered.get_directories().len(), 1);
+    }
+
+    #[test]
+    fn slashes_in_names_are_not_nested_paths() {
+        let rules = remote_rules(&["/a/b", "/Papers/Paper"]);
+        let filtered = filter_remote_directory(&remote_tree(), "", &rules);
+        assert_eq!(file_names(&filtered), vec!["Quick sheets", "a/b"]);
+        assert_eq!(file_names(&filtered.get_directories()[0]), vec!["Draft 1"]);
+        assert_eq!(file_names(&filtered.get_directories()[1]), vec!["Paper"]);
+
+        let rules = remote_rules(&["/a%2Fb", "/%2FPapers/"]);
+        let filtered = filter_remote_directory(&remote_tree(), "", &rules);
+        assert_eq!(file_names(&filtered), vec!["Quick sheets"]);
+        assert_eq!(filtered.get_directories().len(), 1);
+    }
+
+    #[test]
+    fn directory_patterns_only_match_directories() {
+        let rules = remote_rules(&["drafts/", "Paper/"]);
+        let filtered = filter_remote_directory(&remote_tree(), "", &rules);
+        let papers = &filtered.get_directories()[0];
+        assert!(papers.get_directories().is_empty());
+        assert_eq!(file_names(papers), vec!["Paper", "Draft 1"]);
+    }
+
+    #[test]
+    fn nested_ignore_files_override_their_parents() {
+        let root = tempfile::tempdir().unwrap();
+        create_dir_all(root.path().join("build/keep")).unwrap();
+        write(root.path().join(IGNORE_FILE), "*.tex\nbuild/\n").unwrap();
+        write(root.path().join("build").join(IGNORE_FILE), "!*.tex\n").unwrap();
+
+        let top = LocalIgnore::new().with_directory(root.path()).unwrap();
+        assert!(top.is_ignored(&root.path().join("paper.tex"), false));
+        assert!(!top.is_ignored(&root.path().join("paper.pdf"), false));
+        assert!(top.is_ignored(&root.path().join("build"), true));
+        assert!(!top.is_ignored(&root.path().join("build"), false));
+
+        let nested = top.with_directory(&root.path().join("build")).unwrap();
+        assert!(!nested.is_ignored(&root.path().join("build/notes.tex"), false));
+        assert!(!nested.is_ignored(&root.path().join("build/notes.pdf"), false));
+
+        // directories without an ignore file keep their parent's rules
+        let unchanged = nested
+            .with_directory(&root.path().join("build/keep"))
+            .unwrap();
+        assert!(!unchanged.is_ignored(&root.path().join("build/keep/a.tex"), false));
+    }
+}
@@ -0,0 +1,294 @@
+use colored::*;
+use serde::Serialize;
+use ssh2::Session;
+
+use crate::constants::{MACHINE_FILE, POWER_SUPPLY_DIR, VERSION_FILE};
+use crate::du::{get_disk_space, DiskSpace};
+use crate::file_types::FileType;
+use crate::nodes::{Directory, DirectoryNode};
+use crate::profile::{read_firmware_version, ContentLayout, DeviceProfile};
+use crate::snapshots::format_size;
+use crate::ssh_utils::run_remote_command;
+
+/// What remarko can find out about a remarkable and the documents on it.
+#[derive(Clone, Debug, Serialize)]
+pub struct DeviceInfo {
+    /// e.g. "reMarkable 2"
+    pub model: String,
+    /// As reported by the device, e.g. "reMarkable 2.0"
+    pub machine: String,
+    /// The release version from `update.conf`, e.g. "3.5.2.1807"
+    pub firmware: Option<String>,
+    /// The build number from `/etc/version`
+    pub build: Option<String>,
+    /// The file formats the firmware reads and writes
+    pub profile: DeviceProfile,
+    pub storage: Option<DiskSpace>,
+    pub battery: Option<Battery>,
+    /// How long xochitl has been running, in seconds
+    pub ui_uptime: Option<u64>,
+    pub counts: DocumentCounts,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Battery {
+    /// Percent
+    pub level: u8,
+    /// e.g. "Charging", "Discharging" or "Full"
+    pub status: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DocumentCounts {
+    pub documents: usize,
+    pub pdfs: usize,
+    pub epubs: usize,
+    pub notebooks: usize,
+    pub collections: usize,
+    /// Documents and collections in the trash
+    pub trashed: usize,
+}
+
+pub fn get_device_info(root: &Directory, trash: &Directory, sess: &mut Session) -> DeviceInfo {
+    let machine = read_remote_value(sess, MACHINE_FILE).unwrap_or_default();
+    let firmware = read_firmware_version(sess);
+
+    let mut counts = DocumentCounts::default();
+    count_documents(root, &mut counts);
+    counts.trashed = trash.get_files().len() + trash.get_directories().len();
+
+    DeviceInfo {
+        model: get_model(&machine).to_string(),
+        machine,
+        firmware,
+        build: read_remote_value(sess, VERSION_FILE),
+        profile: DeviceProfile::detect(sess),
+        storage: get_disk_space(sess),
+        battery: get_battery(sess),
+        ui_uptime: get_ui_uptime(sess),
+        counts,
+    }
+}
+
+pub fn print_device_info(info: &DeviceInfo) {
+    let unknown = || "unknown".italic().to_string();
+
+    println!("{:<12} {} ({})", "Model:".bold(), info.model, info.machine);
+    let firmware = match (&info.firmware, &info.build) {
+        (Some(firmware), Some(build)) => format!("{} (build {})", firmware, build),
+        (Some(firmware), None) => firmware.clone(),
+        (None, Some(build)) => format!("build {}", build),
+        (None, None) => unknown(),
+    };
+    println!("{:<12} {}", "Firmware:".bold(), firmware);
+    let content_layout = match info.profile.content_layout {
+        ContentLayout::Pages => "pages",
+        ContentLayout::CPages => "cPages",
+    };
+    println!("{:<12} .content with {}", "Formats:".bold(), content_layout);
+    let storage = match &info.storage {
+        Some(space) => format!(
+            "{} of {} used, {} free",
+            format_size(space.used),
+            format_size(space.total),
+            format_size(space.available)
+        ),
+        None => unknown(),
+    };
+    println!("{:<12} {}", "Storage:".bold(), storage);
+    let battery = match &info.battery {
+        Some(battery) => format!("{}% ({})", battery.level, battery.status),
+        None => unknown(),
+    };
+    println!("{:<12} {}", "Battery:".bold(), battery);
+    let uptime = match info.ui_uptime {
+        Some(seconds) => format_duration(seconds),
+        None => "not running".italic().to_string(),
+    };
+    println!("{:<12} {}", "UI uptime:".bold(), uptime);
+
+    let counts = &info.counts;
+    println!(
+        "{:<12} {} ({} pdfs, {} epubs, {} notebooks) in {} collections, {} in the trash",
+        "Documents:".bold(),
+        counts.documents,
+        counts.pdfs,
+        counts.epubs,
+        counts.notebooks,
+        counts.collections,
+        counts.trashed
+    );
+}
+
+/// The marketing name of a remarkable from its machine name.
+fn get_model(machine: &str) -> &'static str {
+    match machine {
+        m if m.contains("1.0") => "reMarkable 1",
+        m if m.contains("2.0") => "reMarkable 2",
+        m if m.contains("Ferrari") => "reMarkable Paper Pro",
+        m if m.contains("Chiappa") => "reMarkable Paper Pro Move",
+        _ => "unknown",
+    }
+}
+
+fn count_documents(directory: &Directory, counts: &mut DocumentCounts) {
+    for file in directory.get_files() {
+        counts.documents += 1;
+        match file.get_file_type() {
+            Some(FileType::Pdf) => counts.pdfs += 1,
+            Some(FileType::Epub) => counts.epubs += 1,
+            Some(FileType::Notebook) => counts.notebooks += 1,
+            _ => {}
+        }
+    }
+    for sub_directory in directory.get_directories() {
+        counts.collections += 1;
+        count_documents(sub_directory, counts);
+    }
+}
+
+fn read_remote_value(sess: &mut Session, path: &str) -> Option<String> {
+    parse_remote_value(&run_remote_command(
+        sess,
+        &format!("cat {} 2>/dev/null", path),
+    ))
+}
+
+/// Files in `/sys` and `/proc` can end in a NUL as well as a newline.
+fn parse_remote_value(output: &str) -> Option<String> {
+    let value = output.trim().trim_end_matches('\0');
+    match value.is_empty() {
+        true => None,
+        false => Some(value.to_string()),
+    }
+}
+
+/// The battery's name differs between models, so look for the power supply
+/// whose type is Battery.
+fn get_battery(sess: &mut Session) -> Option<Battery> {
+    let command = format!(
+        "for d in {}/*; do \
+         [ \"$(cat $d/type 2>/dev/null)\" = Battery ] && cat $d/capacity $d/status && break; \
+         done",
+        POWER_SUPPLY_DIR
+    );
+    parse_battery(&run_remote_command(sess, &command))
+}
+
+/// Parse the capacity and status of a power supply, one per line.
+fn parse_battery(output: &str) -> Option<Battery> {
+    let mut lines = output.lines();
+    Some(Battery {
+        level: lines.next()?.trim().parse().ok()?,
+        status: lines.next()?.trim().to_string(),
+    })
+}
+
+/// systemd records when xochitl started in microseconds since boot, which
+/// is compared with the time since boot from `/proc/uptime`.
+fn get_ui_uptime(sess: &mut Session) -> Option<u64> {
+    let output = run_remote_command(
+        sess,
+        "cat /proc/uptime; systemctl show xochitl -p ActiveState -p ActiveEnterTimestampMonotonic",
+    );
+    parse_ui_uptime(&output)
+}
+
+/// Parse `/proc/uptime` followed by the properties from `systemctl show`.
+fn parse_ui_uptime(output: &str) -> Option<u64> {
+    let mut since_boot = None;
+    let mut started = None;
+    let mut active = false;
+    for line in output.lines() {
+        match line.split_once('=') {
+            Some(("ActiveState", state)) => active = state == "active",
+            Some(("ActiveEnterTimestampMonotonic", micros)) => started = micros.parse::<u64>().ok(),
+            Some(_) => {}
+            None => {
+                since_boot = line
+                    .split_whitespace()
+                    .next()
+                    .and_then(|s| s.parse::<f64>().ok())
+            }
+        }
+    }
+    match active {
+        true => Some((since_boot? as u64).saturating_sub(started? / 1_000_000)),
+        false => None,
+    }
+}
+
+/// Format a number of seconds for people, e.g. "3d 4h" or "12m".
+fn format_duration(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds % 86400 / 3600, seconds % 3600 / 60);
+    match (days, hours) {
+        (0, 0) => format!("{}m", minutes),
+        (0, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h", days, hours),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn models_are_named_from_the_machine() {
+        let machine = |output: &str| parse_remote_value(output).unwrap();
+        assert_eq!(get_model(&machine("reMarkable 1.0\n")), "reMarkable 1");
+        assert_eq!(get_model(&machine("reMarkable 2.0\0")), "reMarkable 2");
+        assert_eq!(
+            get_model(&machine("reMarkable Ferrari\n")),
+            "reMarkable Paper Pro"
+        );
+        assert_eq!(
+            get_model(&machine("reMarkable Chiappa\0\n")),
+            "reMarkable Paper Pro Move"
+        );
+        assert_eq!(get_model(&machine("Raspberry Pi 4")), "unknown");
+        assert_eq!(parse_remote_value("\0\n"), None);
+    }
+
+    #[test]
+    fn durations_are_formatted_by_their_largest_units() {
+        assert_eq!(format_duration(0), "0m");
+        assert_eq!(format_duration(59), "0m");
+        assert_eq!(format_duration(12 * 60), "12m");
+        assert_eq!(format_duration(3600 + 5 * 60), "1h 5m");
+        assert_eq!(format_duration(3 * 86400 + 4 * 3600 + 59 * 60), "3d 4h");
+    }
+
+    #[test]
+    fn ui_uptime_is_the_time_since_xochitl_started() {
+        let output = "\
+7265.31 13920.84
+ActiveState=active
+ActiveEnterTimestampMonotonic=65310000
+";
+        assert_eq!(parse_ui_uptime(output), Some(7200));
+    }
+
+    #[test]
+    fn ui_uptime_is_unknown_unless_xochitl_is_running() {
+        let stopped = "\
+7265.31 13920.84
+ActiveState=inactive
+ActiveEnterTimestampMonotonic=0
+";
+        assert_eq!(parse_ui_uptime(stopped), None);
+        let no_uptime = "ActiveState=active\nActiveEnterTimestampMonotonic=65310000\n";
+        assert_eq!(parse_ui_uptime(no_uptime), None);
+        assert_eq!(parse_ui_uptime(""), None);
+    }
+
+    #[test]
+    fn battery_is_read_from_capacity_and_status() {
+        let battery = parse_battery("87\nDischarging\n").unwrap();
+        assert_eq!(
+            (battery.level, battery.status.as_str()),
+            (87, "Discharging")
+        );
+        assert!(parse_battery("").is_none());
+        assert!(parse_battery("full\nCharging\n").is_none());
+    }
+}
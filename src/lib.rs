@@ -1,5 +1,28 @@
+pub mod backup;
 pub mod constants;
+pub mod dates;
+pub mod device;
+pub mod du;
+pub mod file_names;
+pub mod file_types;
+pub mod fsck;
+pub mod gc;
+pub mod ignore_files;
+pub mod info;
 pub mod local_fs;
+pub mod mirror;
 pub mod nodes;
+pub mod profile;
 pub mod remarkable_trees;
+pub mod remote_fs;
+pub mod renames;
+pub mod show;
+pub mod snapshots;
 pub mod ssh_utils;
+pub mod sync_state;
+pub mod tags;
+#[cfg(test)]
+mod test_utils;
+pub mod trash;
+pub mod tree_diff;
+pub mod tree_filter;
@@ -1,11 +1,30 @@
+use std::collections::HashSet;
 use std::path::Path;
 
+use sha2::{Digest, Sha256};
+
+use crate::file_names::{name_key, names_match};
+use crate::file_types::FileType;
+use crate::ignore_files::LocalIgnore;
 use crate::nodes::{Directory, DirectoryNode, File, Hash, Metadata, Node};
+use crate::remarkable_trees::collect_hashes;
+use crate::sync_state::SyncState;
 
+/// Build a directory tree from the local filesystem, skipping hidden files
+/// and anything matched by a `.remarkoignore` in `path` or below it.
 pub fn build_local_directory(path: &Path) -> Result<Directory, std::io::Error> {
+    build_local_directory_with_ignore(path, &LocalIgnore::new())
+}
+
+fn build_local_directory_with_ignore(
+    path: &Path,
+    ignore: &LocalIgnore,
+) -> Result<Directory, std::io::Error> {
     let mut local_files = Vec::new();
     let mut local_directories = Vec::new();
 
+    let ignore = ignore.with_directory(path).map_err(std::io::Error::other)?;
+
     for entry in std::fs::read_dir(path)? {
         let entry = entry?;
         let path = entry.path();
@@ -16,14 +35,19 @@ pub fn build_local_directory(path: &Path) -> Result<Directory, std::io::Error> {
             continue;
         }
 
+        if ignore.is_ignored(&path, path.is_dir()) {
+            continue;
+        }
+
         if path.is_file() {
             let metadata = Metadata::new(name, None, "420".into(), "file".into());
-            local_files.push(File::new(
-                Hash::new("3rr93jfierjf-9erijfe0".into()),
-                metadata,
-            ));
+            let mut file = File::new(Hash::new("3rr93jfierjf-9erijfe0".into()), metadata);
+            if let Some(file_type) = FileType::from_path(&path) {
+                file.set_file_type(file_type);
+            }
+            local_files.push(file);
         } else if path.is_dir() {
-            local_directories.push(build_local_directory(&path)?);
+            local_directories.push(build_local_directory_with_ignore(&path, &ignore)?);
         }
     }
 
@@ -40,39 +64,101 @@ pub fn build_local_directory(path: &Path) -> Result<Directory, std::io::Error> {
     ))
 }
 
+/// Compare two directory trees, returning the parts of each which are not in
+/// the other. Names are compared after NFC normalization and, if
+/// `ignore_case` is set, case-insensitively.
+///
+/// # Example
+///
+/// ```
+/// use remarko::file_types::FileType;
+/// use remarko::local_fs::remove_common_files_and_directories;
+/// use remarko::nodes::{Directory, DirectoryNode, File, Hash, Metadata};
+///
+/// let file = |name: &str| {
+///     let metadata = Metadata::new(name.into(), None, "0".into(), "DocumentType".into());
+///     let mut file = File::new(Hash::new(name.into()), metadata);
+///     file.set_file_type(FileType::Pdf);
+///     file
+/// };
+/// let directory = |name: &str, files| {
+///     let metadata = Metadata::new(name.into(), None, "0".into(), "CollectionType".into());
+///     Directory::new(Hash::new(name.into()), metadata, Some(files), None)
+/// };
+///
+/// // NFC names on the remarkable, NFD names from macOS locally
+/// let remote = Directory::new(
+///     Hash::new("".into()),
+///     Metadata::new("home".into(), None, "0".into(), "directory".into()),
+///     Some(vec![file("R\u{e9}sum\u{e9}"), file("\u{30ac}\u{30a4}\u{30c9}"), file("Paper")]),
+///     Some(vec![directory("\u{d55c}\u{ae00}", vec![file("\u{8ad6}\u{6587}")])]),
+/// );
+/// let local = Directory::new(
+///     Hash::new("".into()),
+///     Metadata::new("local".into(), None, "0".into(), "directory".into()),
+///     Some(vec![
+///         file("Re\u{301}sume\u{301}.pdf"),
+///         file("\u{30ab}\u{3099}\u{30a4}\u{30c8}\u{3099}.pdf"),
+///         file("paper.PDF"),
+///     ]),
+///     Some(vec![directory(
+///         "\u{1112}\u{1161}\u{11ab}\u{1100}\u{1173}\u{11af}",
+///         vec![file("\u{8ad6}\u{6587}.pdf")],
+///     )]),
+/// );
+///
+/// let (unique_on_remote, unique_on_local) =
+///     remove_common_files_and_directories(&remote, &local, false);
+/// assert_eq!(unique_on_remote.get_files().len(), 1);
+/// assert_eq!(unique_on_local.get_files().len(), 1);
+/// assert!(unique_on_remote.get_directories().is_empty());
+///
+/// let (unique_on_remote, unique_on_local) =
+///     remove_common_files_and_directories(&remote, &local, true);
+/// assert!(unique_on_remote.get_files().is_empty());
+/// assert!(unique_on_local.get_files().is_empty());
+/// ```
 pub fn remove_common_files_and_directories(
     dir1: &Directory,
     dir2: &Directory,
+    ignore_case: bool,
 ) -> (Directory, Directory) {
     let mut dir1_diff_files = Vec::new();
     let mut dir2_diff_files = Vec::new();
     let mut dir1_diff_directories = Vec::new();
     let mut dir2_diff_directories = Vec::new();
 
-    let dir1_files: Vec<_> = dir1.get_files().iter().map(|f| f.get_file_stem()).collect();
-
-    let dir2_files: Vec<_> = dir2.get_files().iter().map(|f| f.get_file_stem()).collect();
-
     for file in dir1.get_files() {
-        if !dir2_files.contains(&file.get_file_stem()) {
+        if !dir2
+            .get_files()
+            .iter()
+            .any(|f| f.is_same_document(file, ignore_case))
+        {
             dir1_diff_files.push(file.clone());
         }
     }
 
     for file in dir2.get_files() {
-        if !dir1_files.contains(&file.get_file_stem()) {
+        if !dir1
+            .get_files()
+            .iter()
+            .any(|f| f.is_same_document(file, ignore_case))
+        {
             dir2_diff_files.push(file.clone());
         }
     }
 
     // Recursively compare sub-directories
     for subdir1 in dir1.get_directories() {
-        if let Some(subdir2) = dir2
-            .get_directories()
-            .iter()
-            .find(|d| d.get_visible_name() == subdir1.get_visible_name())
-        {
-            let (sub_diff1, sub_diff2) = remove_common_files_and_directories(subdir1, subdir2);
+        if let Some(subdir2) = dir2.get_directories().iter().find(|d| {
+            names_match(
+                d.get_visible_name(),
+                subdir1.get_visible_name(),
+                ignore_case,
+            )
+        }) {
+            let (sub_diff1, sub_diff2) =
+                remove_common_files_and_directories(subdir1, subdir2, ignore_case);
 
             if !sub_diff1.get_files().is_empty() || !sub_diff1.get_directories().is_empty() {
                 dir1_diff_directories.push(sub_diff1);
@@ -86,12 +172,13 @@ pub fn remove_common_files_and_directories(
     }
 
     for subdir2 in dir2.get_directories() {
-        if dir1
-            .get_directories()
-            .iter()
-            .find(|d| d.get_visible_name() == subdir2.get_visible_name())
-            .is_none()
-        {
+        if !dir1.get_directories().iter().any(|d| {
+            names_match(
+                d.get_visible_name(),
+                subdir2.get_visible_name(),
+                ignore_case,
+            )
+        }) {
             dir2_diff_directories.push(subdir2.clone());
         }
     }
@@ -113,22 +200,91 @@ pub fn remove_common_files_and_directories(
     (diff_dir1, diff_dir2)
 }
 
+/// Split local files which aren't on the remote into those which were never
+/// there and those which were synced from documents since deleted on the
+/// remote, and so should be treated as removed rather than pushed again.
+///
+/// Local files are matched to deleted documents by the hash recorded for
+/// their path in the sync state, so a new file which happens to have the name
+/// of a deleted document is still pushed. A directory synced from a deleted
+/// collection counts as deleted with everything in it.
+pub fn separate_deleted_on_remote(
+    unique_on_local: &Directory,
+    deleted_on_remote: &Directory,
+    state: &SyncState,
+) -> (Directory, Directory) {
+    let mut deleted_hashes = Vec::new();
+    collect_hashes(deleted_on_remote, &mut deleted_hashes);
+    let deleted_hashes: HashSet<String> = deleted_hashes.iter().map(|h| h.to_string()).collect();
+    split_by_state(unique_on_local, Path::new(""), &deleted_hashes, state)
+}
+
+/// Split a local directory into what isn't and what is recorded in the sync
+/// state as one of `hashes`. `relative_path` is the path of `directory`
+/// relative to the synced directory.
+fn split_by_state(
+    directory: &Directory,
+    relative_path: &Path,
+    hashes: &HashSet<String>,
+    state: &SyncState,
+) -> (Directory, Directory) {
+    let is_deleted = |path: &Path| {
+        state
+            .get_hash(path)
+            .is_some_and(|hash| hashes.contains(hash))
+    };
+
+    let (mut kept_files, mut deleted_files) = (Vec::new(), Vec::new());
+    for file in directory.get_files() {
+        match is_deleted(&relative_path.join(file.get_visible_name())) {
+            true => deleted_files.push(file.clone()),
+            false => kept_files.push(file.clone()),
+        }
+    }
+    let (mut kept_directories, mut deleted_directories) = (Vec::new(), Vec::new());
+    for sub_directory in directory.get_directories() {
+        let path = relative_path.join(sub_directory.get_visible_name());
+        if is_deleted(&path) {
+            deleted_directories.push(sub_directory.clone());
+            continue;
+        }
+        let (kept, deleted) = split_by_state(sub_directory, &path, hashes, state);
+        kept_directories.push(kept);
+        if !deleted.is_empty() {
+            deleted_directories.push(deleted);
+        }
+    }
+
+    let rebuild = |files, directories| {
+        Directory::new(
+            directory.get_hash().clone(),
+            directory.get_metadata().clone(),
+            Some(files),
+            Some(directories),
+        )
+    };
+    (
+        rebuild(kept_files, kept_directories),
+        rebuild(deleted_files, deleted_directories),
+    )
+}
+
 pub fn compare_directories(dir1: &Directory, dir2: &Directory) -> Vec<String> {
     let mut diffs = Vec::new();
 
     let dir1_files: Vec<_> = dir1
         .get_files()
         .iter()
-        .map(|f| f.get_visible_name().as_str())
+        .map(|f| name_key(f.get_visible_name(), false))
         .collect();
     let dir2_files: Vec<_> = dir2
         .get_files()
         .iter()
-        .map(|f| f.get_visible_name().as_str())
+        .map(|f| name_key(f.get_visible_name(), false))
         .collect();
 
     for file in &dir1_files {
-        if !dir2_files.contains(&file) {
+        if !dir2_files.contains(file) {
             diffs.push(format!(
                 "File {} is missing in {}.",
                 file,
@@ -138,7 +294,7 @@ pub fn compare_directories(dir1: &Directory, dir2: &Directory) -> Vec<String> {
     }
 
     for file in &dir2_files {
-        if !dir1_files.contains(&file) {
+        if !dir1_files.contains(file) {
             diffs.push(format!(
                 "File {} is missing in {}.",
                 file,
@@ -152,7 +308,7 @@ pub fn compare_directories(dir1: &Directory, dir2: &Directory) -> Vec<String> {
         if let Some(subdir2) = dir2
             .get_directories()
             .iter()
-            .find(|d| d.get_visible_name() == subdir1.get_visible_name())
+            .find(|d| names_match(d.get_visible_name(), subdir1.get_visible_name(), false))
         {
             diffs.extend(compare_directories(subdir1, subdir2));
         } else {
@@ -165,11 +321,10 @@ pub fn compare_directories(dir1: &Directory, dir2: &Directory) -> Vec<String> {
     }
 
     for subdir2 in dir2.get_directories() {
-        if dir1
+        if !dir1
             .get_directories()
             .iter()
-            .find(|d| d.get_visible_name() == subdir2.get_visible_name())
-            .is_none()
+            .any(|d| names_match(d.get_visible_name(), subdir2.get_visible_name(), false))
         {
             diffs.push(format!(
                 "Directory {} is missing in {}.",
@@ -181,3 +336,152 @@ pub fn compare_directories(dir1: &Directory, dir2: &Directory) -> Vec<String> {
 
     diffs
 }
+
+/// Get the sha256 of a local file as a hex string.
+pub fn get_local_sha256(path: &Path) -> Result<String, std::io::Error> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{directory, file, file_names};
+
+    #[test]
+    fn common_directories_match_after_normalization() {
+        let remote = directory(
+            "",
+            "",
+            Vec::new(),
+            vec![directory(
+                "a",
+                "Caf\u{e9}",
+                vec![file("b", "Menu", Some(FileType::Pdf))],
+                Vec::new(),
+            )],
+        );
+        let local = directory(
+            "",
+            "",
+            Vec::new(),
+            vec![directory(
+                "",
+                "Cafe\u{301}",
+                vec![file("", "Menu.pdf", Some(FileType::Pdf))],
+                Vec::new(),
+            )],
+        );
+        let (unique_on_remote, unique_on_local) =
+            remove_common_files_and_directories(&remote, &local, false);
+        assert!(unique_on_remote.is_empty());
+        assert!(unique_on_local.is_empty());
+    }
+
+    #[test]
+    fn case_only_matters_without_ignore_case() {
+        let remote = directory(
+            "",
+            "",
+            vec![file("a", "Paper", Some(FileType::Pdf))],
+            vec![directory("b", "Work", Vec::new(), Vec::new())],
+        );
+        let local = directory(
+            "",
+            "",
+            vec![file("", "paper.pdf", Some(FileType::Pdf))],
+            vec![directory("", "work", Vec::new(), Vec::new())],
+        );
+
+        let (unique_on_remote, unique_on_local) =
+            remove_common_files_and_directories(&remote, &local, false);
+        assert_eq!(file_names(&unique_on_remote), vec!["Paper"]);
+        assert_eq!(file_names(&unique_on_local), vec!["paper.pdf"]);
+        assert_eq!(unique_on_remote.get_directories().len(), 1);
+        assert_eq!(unique_on_local.get_directories().len(), 1);
+
+        let (unique_on_remote, unique_on_local) =
+            remove_common_files_and_directories(&remote, &local, true);
+        assert!(unique_on_remote.is_empty());
+        assert!(unique_on_local.is_empty());
+    }
+
+    #[test]
+    fn only_files_synced_from_deleted_documents_are_deleted() {
+        let deleted_on_remote = directory(
+            "",
+            "",
+            vec![file("a", "Paper", Some(FileType::Pdf))],
+            vec![directory(
+                "b",
+                "Old",
+                vec![file("c", "Notes", Some(FileType::Notebook))],
+                Vec::new(),
+            )],
+        );
+        // Paper.pdf was synced from the deleted document, New.pdf only has
+        // the name of one, and Old was synced from a deleted collection
+        let unique_on_local = directory(
+            "",
+            "",
+            vec![
+                file("", "Paper.pdf", Some(FileType::Pdf)),
+                file("", "Notes.pdf", Some(FileType::Pdf)),
+            ],
+            vec![
+                directory(
+                    "",
+                    "Old",
+                    vec![file("", "Extra.pdf", Some(FileType::Pdf))],
+                    Vec::new(),
+                ),
+                directory(
+                    "",
+                    "Work",
+                    vec![file("", "Notes.pdf", Some(FileType::Pdf))],
+                    Vec::new(),
+                ),
+            ],
+        );
+        let mut state = SyncState::default();
+        state.set_path("a", "Paper.pdf".into());
+        state.set_path("b", "Old".into());
+        state.set_path("d", "Work".into());
+        state.set_path("e", "Work/Notes.pdf".into());
+
+        let (not_deleted, deleted) =
+            separate_deleted_on_remote(&unique_on_local, &deleted_on_remote, &state);
+        assert_eq!(file_names(&deleted), vec!["Paper.pdf"]);
+        assert_eq!(deleted.get_directories().len(), 1);
+        assert_eq!(file_names(&deleted.get_directories()[0]), vec!["Extra.pdf"]);
+
+        assert_eq!(file_names(&not_deleted), vec!["Notes.pdf"]);
+        assert_eq!(not_deleted.get_directories().len(), 1);
+        assert_eq!(
+            file_names(&not_deleted.get_directories()[0]),
+            vec!["Notes.pdf"]
+        );
+    }
+
+    #[test]
+    fn epubs_and_pdfs_with_the_same_stem_are_different() {
+        let remote = directory(
+            "",
+            "",
+            vec![file("a", "Book", Some(FileType::Epub))],
+            Vec::new(),
+        );
+        let local = directory(
+            "",
+            "",
+            vec![file("", "Book.pdf", Some(FileType::Pdf))],
+            Vec::new(),
+        );
+        let (unique_on_remote, unique_on_local) =
+            remove_common_files_and_directories(&remote, &local, false);
+        assert_eq!(file_names(&unique_on_remote), vec!["Book"]);
+        assert_eq!(file_names(&unique_on_local), vec!["Book.pdf"]);
+    }
+}
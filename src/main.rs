@@ -1,17 +1,61 @@
+use std::collections::HashMap;
 use std::env::current_dir;
 use std::fs::create_dir_all;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use clap::{arg, ArgAction, Command};
+use clap::{arg, ArgAction, ArgMatches, Command};
 use colored::*;
+use ssh2::Session;
 
-use remarko::constants::DIR;
-use remarko::local_fs::{build_local_directory, remove_common_files_and_directories};
-use remarko::nodes::{DirectoryNode, Node};
-use remarko::remarkable_trees::{build_tree, get_hashes_from_ls_output, print_tree};
+use remarko::backup::{
+    backup_device, backup_file_name, print_restored, resolve_backup_documents, restore_device,
+};
+use remarko::constants::{LOCAL_TRASH_DIR, SSH_HOST};
+use remarko::dates::{DateFormat, DEFAULT_DATE_FORMAT};
+use remarko::device::{restart_ui, XochitlGuard};
+use remarko::du::{get_disk_space, get_sizes_by_hash, get_usage, print_usage};
+use remarko::file_names::map_to_local_names;
+use remarko::file_types::{
+    filter_directory_by_type, parse_types, FileType, ALL_TYPES, DEFAULT_TYPES,
+};
+use remarko::fsck::{check_device, repair_device, Problem};
+use remarko::gc::{collect_garbage, find_garbage, print_garbage};
+use remarko::ignore_files::apply_remote_ignore;
+use remarko::info::{get_device_info, print_device_info};
+use remarko::local_fs::{
+    build_local_directory, remove_common_files_and_directories, separate_deleted_on_remote,
+};
+use remarko::mirror::{
+    confirm, find_extraneous, find_extraneous_on_remote, move_to_local_trash, move_to_remote_trash,
+    print_extraneous,
+};
+use remarko::nodes::{Directory, Node};
+use remarko::profile::DeviceProfile;
+use remarko::remarkable_trees::{
+    find_directory, find_node, get_remote_trees, get_remote_trees_with_deleted, print_tree,
+    split_deleted, TreeWarning,
+};
+use remarko::remote_fs::{
+    make_remote_directory, move_remote_nodes, pin_remote_nodes, remove_remote_nodes,
+    rename_remote_node,
+};
+use remarko::renames::{apply_renames, detect_renames, print_renames, remove_renamed, Side};
+use remarko::show::{get_details, print_details};
+use remarko::snapshots::{format_size, print_snapshots, SnapshotStore};
 use remarko::ssh_utils::{
-    connect_to_remote, copy_directory_from_remote, get_ssh_config, run_remote_command,
-    send_to_remote,
+    connect_to_remote, copy_directory_from_remote, get_ssh_config, send_directory_to_remote,
+    upload_document,
+};
+use remarko::sync_state::SyncState;
+use remarko::tags::{
+    add_remote_tag, count_tags, print_tag_counts, read_all_tags, remove_remote_tag,
+};
+use remarko::trash::{
+    empty_trash, find_expired, parse_age, print_trash, restore_from_trash, TrashRecord,
+};
+use remarko::tree_diff::{compare_trees, print_tree_changes, SavedTrees};
+use remarko::tree_filter::{
+    filter_tree, flatten_tree, limit_depth, parse_date, sort_tree, SortKey, TreeFilter,
 };
 
 fn cli() -> Command {
@@ -24,12 +68,73 @@ fn cli() -> Command {
         .arg(arg!(verbose: -v --verbose "Print verbose output")
             .global(true)
             .action(ArgAction::SetTrue))
-        .subcommand(Command::new("list").about("Lists files on the remote filesystem"))
+        .arg(arg!(utc: --utc "Show dates in UTC rather than local time")
+            .global(true)
+            .action(ArgAction::SetTrue))
+        .arg(arg!(date_format: --"date-format" <FORMAT> "How to show dates: a strftime format, or relative for e.g. 3 days ago")
+            .global(true)
+            .required(false)
+            .default_value(DEFAULT_DATE_FORMAT))
+        .subcommand(
+            Command::new("list")
+                .about("Lists files on the remote filesystem")
+                .arg(arg!(save: --save <FILE> "Also save the tree to a json file, to diff against later")
+                    .required(false))
+                .arg(arg!(sort: --sort <KEY> "Sort each collection by name, modified, opened or size")
+                    .required(false))
+                .arg(arg!(reverse: -r --reverse "Reverse the order").action(ArgAction::SetTrue))
+                .arg(arg!(type_: --type <TYPES> "Only list documents of these comma separated types: pdf, epub, notebook")
+                    .required(false))
+                .arg(arg!(modified_since: --"modified-since" <DATE> "Only list documents modified on or after a date, e.g. 2026-01-01")
+                    .required(false))
+                .arg(arg!(name: --name <PATTERN> "Only list documents whose name matches a glob, or a regex between slashes")
+                    .required(false))
+                .arg(arg!(pinned: --pinned "Only list pinned documents").action(ArgAction::SetTrue))
+                .arg(arg!(tag: --tag <TAG> "Only list documents with a tag").required(false))
+                .arg(arg!(depth: --depth <DEPTH> "How many levels of collections to show")
+                    .required(false)
+                    .value_parser(clap::value_parser!(usize)))
+                .arg(include_deleted_arg()),
+        )
+        .subcommand(
+            Command::new("show")
+                .about("Shows the details of a document or collection on the remarkable")
+                .arg(arg!(path: <PATH> "The path of the document or collection")),
+        )
+        .subcommand(Command::new("tags").about("Lists the tags on the remarkable and how often they are used"))
+        .subcommand(
+            Command::new("tag")
+                .about("Manage the tags of documents and pages on the remarkable")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("add")
+                        .about("Tag a document, or one of its pages")
+                        .arg(arg!(path: <PATH> "The document to tag"))
+                        .arg(arg!(tag: <TAG> "The tag to add"))
+                        .arg(page_arg()),
+                )
+                .subcommand(
+                    Command::new("remove")
+                        .about("Remove a tag from a document, or from one of its pages")
+                        .arg(arg!(path: <PATH> "The document to untag"))
+                        .arg(arg!(tag: <TAG> "The tag to remove"))
+                        .arg(page_arg()),
+                ),
+        )
         .subcommand(
             Command::new("diff")
                 .about("Compares the local filesystem to the remote filesystem")
                 .arg(arg!(local_directory: <LOCAL_DIRECTORY> "The local directory to compare").required(false).default_value(""))
-                .arg(arg!(remote_directory: -d --remote_directory <REMOTE_DIRECTORY>  "The remote directory to compare").required(false).default_value("")),
+                .arg(arg!(remote_directory: -d --remote_directory <REMOTE_DIRECTORY>  "The remote directory to compare").required(false).default_value(""))
+                .arg(types_arg())
+                .arg(ignore_case_arg())
+                .arg(arg!(from: --from <FILE> "Compare a tree saved by list --save with --to instead of a local directory")
+                    .required(false))
+                .arg(arg!(to: --to <FILE> "A later tree saved by list --save, or device for the current tree")
+                    .required(false)
+                    .default_value("device")
+                    .requires("from"))
+                .arg(include_deleted_arg()),
         )
         .subcommand(
             Command::new("pull")
@@ -38,26 +143,358 @@ fn cli() -> Command {
                     .required(false)
                     .default_value(""))
                 .arg(arg!(destination: <DESTINATION> "The local directory to pull to"))
+                .arg(types_arg())
+                .arg(ignore_case_arg())
+                .arg(arg!(mirror: --mirror "Also move local files which are not on the remote to .remarko-trash/")
+                    .action(ArgAction::SetTrue))
+                .arg(include_deleted_arg())
+                .arg(arg!(tag: --tag <TAG> "Only pull documents with a tag, from any collection")
+                    .required(false)
+                    .conflicts_with("mirror"))
+                .arg(arg!(pinned: --pinned "Only pull favourite documents, all into the destination itself")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with("remote_directory"))
+                .arg(yes_arg()),
         )
         .subcommand(
             Command::new("push")
-                .about("Push a file to the remote filesystem")
-                .arg(arg!(<FILE> "The file to push")),
+                .about("Push any files from the source directory which are not on the remote filesystem, or a single file")
+                .arg(arg!(remote_directory: -d --directory <DIRECTORY> "The remote directory to push to")
+                    .required(false)
+                    .default_value(""))
+                .arg(arg!(source: <SOURCE> "The local directory or file to push"))
+                .arg(types_arg())
+                .arg(ignore_case_arg())
+                .arg(arg!(mirror: --mirror "Also move remote files which are not in the source directory to the remarkable's trash")
+                    .action(ArgAction::SetTrue))
+                .arg(yes_arg()),
+        )
+        .subcommand(
+            Command::new("sync")
+                .about("Pull and push any files which are only on one side, and apply renames and moves made on either side")
+                .arg(arg!(remote_directory: -d --directory <DIRECTORY> "The remote directory to sync")
+                    .required(false)
+                    .default_value(""))
+                .arg(arg!(local_directory: <LOCAL_DIRECTORY> "The local directory to sync"))
+                .arg(types_arg())
+                .arg(ignore_case_arg()),
+        )
+        .subcommand(
+            Command::new("mkdir")
+                .about("Create collections on the remote filesystem")
+                .arg(arg!(paths: <PATH> ... "The collections to create"))
+                .arg(arg!(parents: -p --parents "Create parent collections as needed, and don't fail if a collection exists")
+                    .action(ArgAction::SetTrue)),
+        )
+        .subcommand(
+            Command::new("mv")
+                .about("Move documents and collections on the remote filesystem")
+                .arg(arg!(sources: <SOURCE> ... "The documents or collections to move"))
+                .arg(arg!(destination: <DESTINATION> "The collection to move into, or the new path of a single source")),
+        )
+        .subcommand(
+            Command::new("rename")
+                .about("Rename a document or collection on the remote filesystem")
+                .arg(arg!(path: <PATH> "The document or collection to rename"))
+                .arg(arg!(new_name: <NEW_NAME> "The new name")),
+        )
+        .subcommand(
+            Command::new("pin")
+                .about("Add documents and collections on the remote filesystem to the favourites")
+                .arg(arg!(paths: <PATH> ... "The documents or collections to pin")),
+        )
+        .subcommand(
+            Command::new("unpin")
+                .about("Remove documents and collections on the remote filesystem from the favourites")
+                .arg(arg!(paths: <PATH> ... "The documents or collections to unpin")),
+        )
+        .subcommand(
+            Command::new("rm")
+                .about("Move documents and collections on the remote filesystem to the trash")
+                .arg(arg!(paths: <PATH> ... "The documents or collections to remove"))
+                .arg(arg!(permanent: --permanent "Delete them instead of moving them to the trash")
+                    .action(ArgAction::SetTrue)),
+        )
+        .subcommand(
+            Command::new("backup")
+                .about("Back up all documents, templates and settings on the remarkable to a .tar.zst archive")
+                .arg(arg!(output_directory: <OUTPUT_DIRECTORY> "The local directory to write the archive to")
+                    .required(false)
+                    .default_value(".")),
+        )
+        .subcommand(
+            Command::new("restore")
+                .about("Restore a backup to the remarkable, replacing what is there")
+                .arg(arg!(archive: <ARCHIVE> "The .tar.zst archive made by backup"))
+                .arg(arg!(documents: [DOCUMENT] ... "Only restore these documents or collections, as paths in the backup or hashes"))
+                .arg(yes_arg()),
+        )
+        .subcommand(
+            Command::new("snapshot")
+                .about("Take a snapshot of the documents on the remarkable, storing only what changed since the last one")
+                .subcommand(Command::new("list").about("Lists the snapshots taken"))
+                .subcommand(
+                    Command::new("restore")
+                        .about("Put a document or collection back on the remarkable as it was in a snapshot")
+                        .arg(arg!(id: <ID> "The snapshot to restore from"))
+                        .arg(arg!(path: <PATH> "The document or collection, as a path in the snapshot or a hash"))
+                        .arg(yes_arg()),
+                ),
+        )
+        .subcommand(
+            Command::new("fsck")
+                .about("Checks the documents on the remarkable for missing, orphaned and inconsistent files")
+                .arg(arg!(repair: --repair "Fix the problems found, after confirmation")
+                    .action(ArgAction::SetTrue))
+                .arg(yes_arg()),
+        )
+        .subcommand(
+            Command::new("gc")
+                .about("Deletes files on the remarkable which belong to documents which no longer exist")
+                .arg(arg!(dry_run: --"dry-run" "Only show what would be deleted")
+                    .action(ArgAction::SetTrue))
+                .arg(yes_arg()),
+        )
+        .subcommand(
+            Command::new("du")
+                .about("Shows how much space documents and collections take up on the remarkable")
+                .arg(arg!(remote_directory: [DIRECTORY] "The remote directory to report on")
+                    .default_value(""))
+                .arg(arg!(depth: --depth <DEPTH> "How many levels of collections to show")
+                    .required(false)
+                    .default_value("1")
+                    .value_parser(clap::value_parser!(usize)))
+                .arg(arg!(top: --top <N> "Only show the N largest entries in each collection")
+                    .required(false)
+                    .value_parser(clap::value_parser!(usize))),
+        )
+        .subcommand(
+            Command::new("info")
+                .about("Shows the model, firmware, storage and battery of the remarkable and counts its documents")
+                .arg(arg!(json: --json "Print the information as json")
+                    .action(ArgAction::SetTrue)),
+        )
+        .subcommand(Command::new("restart-ui").about("Restarts the remarkable's UI, e.g. to pick up changed documents"))
+        .subcommand(
+            Command::new("trash")
+                .about("Manage the trash on the remote filesystem")
+                .subcommand_required(true)
+                .subcommand(Command::new("list").about("Lists what is in the trash"))
+                .subcommand(
+                    Command::new("restore")
+                        .about("Move a document or collection out of the trash, back where it was if known")
+                        .arg(arg!(path: <PATH> "The document or collection in the trash to restore"))
+                        .arg(arg!(to: --to <COLLECTION> "The collection to restore to").required(false)),
+                )
+                .subcommand(
+                    Command::new("empty")
+                        .about("Permanently delete what is in the trash")
+                        .arg(arg!(older_than: --"older-than" <AGE> "Only delete what was trashed longer ago than this, e.g. 30d")
+                            .required(false))
+                        .arg(yes_arg()),
+                ),
+        )
+}
+
+fn types_arg() -> clap::Arg {
+    arg!(types: -t --types <TYPES> "Comma separated file types to consider: pdf, epub, notebook")
+        .required(false)
+        .default_value(DEFAULT_TYPES)
+}
+
+fn page_arg() -> clap::Arg {
+    arg!(page: --page <PAGE> "The page to tag instead of the document, counting from 1")
+        .required(false)
+        .value_parser(clap::value_parser!(usize))
+}
+
+fn ignore_case_arg() -> clap::Arg {
+    arg!(ignore_case: -i --"ignore-case" "Match names regardless of case")
+        .action(ArgAction::SetTrue)
+}
+
+fn include_deleted_arg() -> clap::Arg {
+    arg!(include_deleted: --"include-deleted" "Include documents and collections which have been deleted")
+        .action(ArgAction::SetTrue)
+}
+
+fn yes_arg() -> clap::Arg {
+    arg!(yes: -y --yes "Don't ask for confirmation").action(ArgAction::SetTrue)
+}
+
+fn get_types(sub_matches: &ArgMatches) -> Vec<FileType> {
+    let types = sub_matches.get_one::<String>("types").expect("default");
+    parse_types(types).unwrap_or_else(|e| panic!("{} {}", "Error:".bold().red(), e))
+}
+
+/// Connect to the `remarkable` host from the ssh config, returning the
+/// session and the host name.
+fn connect() -> (Session, String) {
+    let ssh_config = get_ssh_config();
+    let params = ssh_config.query(SSH_HOST);
+    let host_name = params
+        .host_name
+        .as_ref()
+        .expect("No HostName in ssh config")
+        .to_string();
+    (connect_to_remote(params), host_name)
+}
+
+/// Get a sub-directory of the remote root, panicking if it doesn't exist.
+fn get_remote_directory(root: &Directory, path: &str) -> Directory {
+    find_directory(root, path).unwrap_or_else(|| {
+        panic!(
+            "{} {} {}\n",
+            "Error: Directory".bold().red(),
+            path.bold().red(),
+            "not found in remote directory".bold().red()
+        )
+    })
+}
+
+/// Get a remote directory ready to compare with a local directory: with
+/// ignored files removed, and names mapped to the names they have locally.
+fn prepare_remote_directory(root: &Directory, path: &str, state: &SyncState) -> Directory {
+    // get the sub-directory on remote if specified
+    let remote_directory = get_remote_directory(root, path);
+    let remote_directory =
+        apply_remote_ignore(remote_directory, path).expect("Failed to read remote ignore file");
+    map_to_local_names(&remote_directory, Path::new(""), state)
+}
+
+/// Keep only the documents of the selected types in what is unique to each
+/// side of a comparison.
+///
+/// Types are filtered after matching, so e.g. a local pdf exported from a
+/// notebook still matches the notebook when notebooks aren't selected,
+/// rather than looking like a new document.
+fn filter_unique_by_type(
+    unique_on_remote: &Directory,
+    unique_on_local: &Directory,
+    types: &[FileType],
+) -> (Directory, Directory) {
+    (
+        filter_directory_by_type(unique_on_remote, types),
+        filter_directory_by_type(unique_on_local, types),
+    )
+}
+
+/// Split a remote directory prepared by `prepare_remote_directory` into what
+/// is live and what has been deleted, unless `include_deleted`, in which case
+/// deleted documents are compared like any other.
+fn split_remote_directory(directory: Directory, include_deleted: bool) -> (Directory, Directory) {
+    match include_deleted {
+        true => {
+            let nothing_deleted = Directory::new(
+                directory.get_hash().clone(),
+                directory.get_metadata().clone(),
+                None,
+                None,
+            );
+            (directory, nothing_deleted)
+        }
+        false => split_deleted(&directory),
+    }
+}
+
+/// Get the home and trash trees of the remote, with or without deleted
+/// documents and collections, keeping any warnings to report at the end.
+fn get_trees(
+    sess: &mut Session,
+    include_deleted: bool,
+    warnings: &mut Vec<TreeWarning>,
+) -> (Directory, Directory) {
+    let trees = match include_deleted {
+        true => get_remote_trees_with_deleted(sess),
+        false => get_remote_trees(sess),
+    };
+    warnings.extend(trees.warnings);
+    (trees.root, trees.trash)
+}
+
+/// Build a local directory, leaving out files remarko can't transfer.
+fn prepare_local_directory(path: &Path) -> Directory {
+    let local_directory = build_local_directory(path).unwrap();
+    filter_directory_by_type(&local_directory, &ALL_TYPES)
+}
+
+/// Change documents on the remote with xochitl stopped, then report any error
+/// once it has been started again.
+fn edit_remote(
+    sess: &mut Session,
+    edit: impl FnOnce(&mut Session) -> Result<(), Box<dyn std::error::Error>>,
+) {
+    let xochitl = XochitlGuard::stop(sess);
+    let result = edit(sess);
+    drop(xochitl);
+    if let Err(e) = result {
+        panic!("{} {}", "Error:".bold().red(), e);
+    }
+}
+
+/// Push a single file into a remote collection, whether or not a document
+/// with the same name is already there.
+fn push_file(path: &Path, remote_directory: &Directory, sess: &mut Session) {
+    let file_type = FileType::from_path(path).unwrap_or_else(|| {
+        panic!(
+            "{} {} is not a pdf or epub",
+            "Error:".bold().red(),
+            path.display()
         )
+    });
+    let profile = DeviceProfile::detect(sess);
+    edit_remote(sess, |sess| {
+        upload_document(path, file_type, remote_directory.get_hash(), &profile, sess)?;
+        println!(
+            "{} {}",
+            "Pushed:".bold().green(),
+            path.to_str().unwrap().italic().purple()
+        );
+        Ok(())
+    });
+}
+
+fn get_many(sub_matches: &ArgMatches, id: &str) -> Vec<String> {
+    sub_matches
+        .get_many::<String>(id)
+        .expect("required")
+        .cloned()
+        .collect()
+}
+
+/// Record the documents and collections created by a push in the sync state.
+fn record_pushed(state: &mut SyncState, pushed: Vec<(PathBuf, String)>, local_root: &Path) {
+    for (local_path, hash) in pushed {
+        let relative_path = local_path.strip_prefix(local_root).unwrap().to_path_buf();
+        if local_path.is_file() {
+            state.record_file(&hash, local_root, relative_path).unwrap();
+        } else {
+            state.set_path(&hash, relative_path);
+        }
+    }
 }
 
 fn main() {
     let matches = cli().get_matches();
+    let dates = DateFormat::new(
+        matches.get_flag("utc"),
+        matches.get_one::<String>("date_format").expect("default"),
+    )
+    .unwrap_or_else(|e| panic!("{} {}", "Error:".bold().red(), e));
+
+    let mut warnings = Vec::new();
+    run(&matches, &dates, &mut warnings);
+    for warning in &warnings {
+        eprintln!("{} {}", "Warning:".bold().yellow(), warning);
+    }
+}
 
+/// Run a subcommand. Problems found in the remote trees are collected in
+/// `warnings` so they are reported once, after everything else.
+fn run(matches: &ArgMatches, dates: &DateFormat, warnings: &mut Vec<TreeWarning>) {
     match matches.subcommand() {
-        Some(("list", _)) => {
-            let ssh_config = get_ssh_config();
-            let params = ssh_config.query("remarkable");
-            let host_name = params
-                .host_name
-                .as_ref()
-                .expect("No HostName in ssh config")
-                .to_string();
+        Some(("list", sub_matches)) => {
+            let (mut sess, host_name) = connect();
 
             println!(
                 "\n{} {}\n",
@@ -65,14 +502,137 @@ fn main() {
                 host_name.bold().yellow()
             );
 
-            let mut sess = connect_to_remote(params);
-            let output = run_remote_command(&mut sess, format!("ls {}", DIR).as_str());
-            let hashes = get_hashes_from_ls_output(&output);
-            let (root_directory, trash_directory) = build_tree(hashes, &mut sess);
-            print_tree(&root_directory, 0);
+            let (root_directory, trash_directory) =
+                get_trees(&mut sess, sub_matches.get_flag("include_deleted"), warnings);
+
+            let user_error = |e: String| -> ! { panic!("{} {}", "Error:".bold().red(), e) };
+            let filter = TreeFilter {
+                types: sub_matches
+                    .get_one::<String>("type_")
+                    .map(|types| parse_types(types).unwrap_or_else(|e| user_error(e))),
+                modified_since: sub_matches
+                    .get_one::<String>("modified_since")
+                    .map(|date| parse_date(date).unwrap_or_else(|e| user_error(e))),
+                name: sub_matches
+                    .get_one::<String>("name")
+                    .map(|name| name.parse().unwrap_or_else(|e| user_error(e))),
+                pinned: sub_matches.get_flag("pinned"),
+                tag: sub_matches.get_one::<String>("tag").cloned(),
+            };
+            let sort: Option<SortKey> = sub_matches
+                .get_one::<String>("sort")
+                .map(|key| key.parse().unwrap_or_else(|e| user_error(e)));
+            let tags = match filter.tag {
+                Some(_) => read_all_tags(&mut sess),
+                None => HashMap::new(),
+            };
+            let sizes = match sort {
+                Some(SortKey::Size) => get_sizes_by_hash(&mut sess),
+                _ => HashMap::new(),
+            };
+            let reverse = sub_matches.get_flag("reverse");
+            let depth = sub_matches.get_one::<usize>("depth");
+
+            for directory in [&root_directory, &trash_directory] {
+                let mut directory = filter_tree(directory, &filter, &tags);
+                directory = sort_tree(&directory, sort, reverse, &sizes);
+                if let Some(depth) = depth {
+                    directory = limit_depth(&directory, *depth);
+                }
+                print_tree(&directory, 0, dates);
+                println!();
+            }
+
+            if let Some(save) = sub_matches.get_one::<String>("save") {
+                let trees = SavedTrees {
+                    root: root_directory,
+                    trash: trash_directory,
+                };
+                trees.save(Path::new(save)).unwrap();
+                println!(
+                    "{} saved the tree to {}",
+                    "Success:".bold().green(),
+                    save.italic().purple()
+                );
+            }
+        }
+        Some(("show", sub_matches)) => {
+            let path = sub_matches.get_one::<String>("path").expect("required");
+
+            let (mut sess, _) = connect();
+            let (remote_root_directory, _) = get_trees(&mut sess, false, warnings);
+            let node = find_node(&remote_root_directory, path).unwrap_or_else(|| {
+                panic!("{} {} not found", "Error:".bold().red(), path.bold().red())
+            });
+
             println!();
-            print_tree(&trash_directory, 0);
+            print_details(&get_details(node, path, &mut sess), dates);
+        }
+        Some(("tags", _)) => {
+            let (mut sess, host_name) = connect();
+            let (remote_root_directory, _) = get_trees(&mut sess, false, warnings);
+
+            println!(
+                "\n{} {}\n",
+                "Tags on".bold().yellow(),
+                host_name.bold().yellow()
+            );
+            let counts = count_tags(&remote_root_directory, &read_all_tags(&mut sess));
+            if counts.is_empty() {
+                println!("No tags");
+            }
+            print_tag_counts(&counts);
+        }
+        Some(("tag", sub_matches)) => {
+            let (mut sess, _) = connect();
+            let (remote_root_directory, _) = get_trees(&mut sess, false, warnings);
+
+            let (command, tag_matches) = sub_matches.subcommand().expect("required");
+            let path = tag_matches.get_one::<String>("path").expect("required");
+            let tag = tag_matches.get_one::<String>("tag").expect("required");
+            let page = tag_matches.get_one::<usize>("page").copied();
+
             println!();
+            edit_remote(&mut sess, |sess| match command {
+                "add" => add_remote_tag(&remote_root_directory, path, tag, page, sess),
+                "remove" => remove_remote_tag(&remote_root_directory, path, tag, page, sess),
+                _ => unreachable!(),
+            });
+        }
+        Some(("diff", sub_matches)) if sub_matches.contains_id("from") => {
+            let from = sub_matches.get_one::<String>("from").expect("checked");
+            let to = sub_matches.get_one::<String>("to").expect("default");
+            let remote_directory_path = sub_matches
+                .get_one::<String>("remote_directory")
+                .expect("required");
+
+            let load = |path: &String| {
+                SavedTrees::load(Path::new(path))
+                    .unwrap_or_else(|e| panic!("{} {}", "Error:".bold().red(), e))
+            };
+            let old_trees = load(from);
+            let new_trees = match to.as_str() {
+                "device" => {
+                    let (mut sess, _) = connect();
+                    let (root, trash) = get_trees(&mut sess, false, warnings);
+                    SavedTrees { root, trash }
+                }
+                _ => load(to),
+            };
+
+            println!(
+                "\n{} {} {} {}",
+                "Comparing".bold().yellow(),
+                from.bold().yellow(),
+                "with".bold().yellow(),
+                to.bold().yellow(),
+            );
+
+            let changes = compare_trees(
+                &get_remote_directory(&old_trees.root, remote_directory_path),
+                &get_remote_directory(&new_trees.root, remote_directory_path),
+            );
+            print_tree_changes(&changes);
         }
         Some(("diff", sub_matches)) => {
             let local_directory_path_input = sub_matches
@@ -81,30 +641,18 @@ fn main() {
             let remote_directory_path = sub_matches
                 .get_one::<String>("remote_directory")
                 .expect("required");
+            let types = get_types(sub_matches);
+            let ignore_case = sub_matches.get_flag("ignore_case");
 
             let current_dir = current_dir().unwrap().to_str().unwrap().to_string();
             let local_directory_path_input =
                 format!("{}/{}", current_dir, local_directory_path_input);
             let local_directory_path = Path::new(&local_directory_path_input).to_path_buf();
 
-            // TODO pull out this repeated code
-            let ssh_config = get_ssh_config();
-            let params = ssh_config.query("remarkable");
-            let host_name = params
-                .host_name
-                .as_ref()
-                .expect("No HostName in ssh config")
-                .to_string();
-
-            let mut sess = connect_to_remote(params);
-            let output = run_remote_command(&mut sess, format!("ls {}", DIR).as_str());
-            let hashes = get_hashes_from_ls_output(&output);
-            let (remote_root_directory, _) = build_tree(hashes, &mut sess);
-            let local_directory = build_local_directory(&local_directory_path).unwrap();
-
-            // get the sub-directory on remote if specified
-            let mut remote_directory = remote_root_directory.clone();
-            if remote_directory_path == "" {
+            let (mut sess, host_name) = connect();
+            let (remote_root_directory, _) = get_trees(&mut sess, true, warnings);
+
+            if remote_directory_path.is_empty() {
                 println!(
                     "\n{} {} {} {}",
                     "Comparing all files on".bold().yellow(),
@@ -122,49 +670,160 @@ fn main() {
                     "to".bold().yellow(),
                     local_directory_path.to_str().unwrap().bold().yellow(),
                 );
-                for dir in remote_directory_path.split("/") {
-                    remote_directory = remote_directory
-                        .get_directories()
-                        .iter()
-                        .find(|d| d.get_visible_name() == dir)
-                        .expect(&format!(
-                            "{} {} {}\n",
-                            "Error: Directory".bold().red(),
-                            dir.to_string().bold().red(),
-                            "not found in remote directory".bold().red(),
-                        ))
-                        .clone();
-                }
             }
 
+            let state = SyncState::load(&local_directory_path).unwrap();
+            let local_directory = prepare_local_directory(&local_directory_path);
+            let (remote_directory, deleted_remote_directory) = split_remote_directory(
+                prepare_remote_directory(&remote_root_directory, remote_directory_path, &state),
+                sub_matches.get_flag("include_deleted"),
+            );
+
+            let (unique_on_remote, unique_on_local) = remove_common_files_and_directories(
+                &remote_directory,
+                &local_directory,
+                ignore_case,
+            );
+            let (unique_on_remote, unique_on_local) =
+                filter_unique_by_type(&unique_on_remote, &unique_on_local, &types);
+            let renames = detect_renames(
+                &unique_on_remote,
+                &unique_on_local,
+                &local_directory_path,
+                &state,
+                &mut sess,
+            )
+            .unwrap();
             let (unique_on_remote, unique_on_local) =
-                remove_common_files_and_directories(&remote_directory, &local_directory);
+                remove_renamed(&unique_on_remote, &unique_on_local, &renames);
+            let (unique_on_local, deleted_on_remote) =
+                separate_deleted_on_remote(&unique_on_local, &deleted_remote_directory, &state);
 
             println!("\nUnique on remote:");
-            print_tree(&unique_on_remote, 0);
+            print_tree(&unique_on_remote, 0, dates);
             println!("\nUnique on local:");
-            print_tree(&unique_on_local, 0);
+            print_tree(&unique_on_local, 0, dates);
+            if !renames.is_empty() {
+                println!("\nRenamed or moved:");
+                print_renames(&renames);
+            }
+            if !deleted_on_remote.is_empty() {
+                println!("\nDeleted on remote:");
+                print_tree(&deleted_on_remote, 0, dates);
+            }
         }
         Some(("push", sub_matches)) => {
-            let local_file_path = sub_matches.get_one::<String>("FILE").expect("required");
+            let remote_directory_path = sub_matches
+                .get_one::<String>("remote_directory")
+                .expect("required");
+            let local_directory_path = sub_matches.get_one::<String>("source").expect("required");
+            let types = get_types(sub_matches);
+            let ignore_case = sub_matches.get_flag("ignore_case");
+            let verbose = sub_matches.get_flag("verbose");
 
-            // TODO pull out this repeated code
-            let ssh_config = get_ssh_config();
-            let params = ssh_config.query("remarkable");
-            let host_name = params
-                .host_name
-                .as_ref()
-                .expect("No HostName in ssh config")
-                .to_string();
+            let (mut sess, host_name) = connect();
+            let (remote_root_directory, _) = get_trees(&mut sess, true, warnings);
 
-            println!(
-                "\n{} {}\n",
-                "Pushing test file to".bold().yellow(),
-                host_name.bold().yellow()
+            if remote_directory_path.is_empty() {
+                println!(
+                    "\n{} {} {} {}",
+                    "Pushing all files from".bold().yellow(),
+                    local_directory_path.bold().yellow(),
+                    "to".bold().yellow(),
+                    host_name.bold().yellow(),
+                );
+            } else {
+                println!(
+                    "\n{} {} {} {} {} {}",
+                    "Pushing files from".bold().yellow(),
+                    local_directory_path.bold().yellow(),
+                    "to".bold().yellow(),
+                    remote_directory_path.bold().yellow(),
+                    "on".bold().yellow(),
+                    host_name.bold().yellow(),
+                );
+            }
+
+            let local_directory_path_ = Path::new(&local_directory_path);
+            if local_directory_path_.is_file() {
+                push_file(
+                    local_directory_path_,
+                    &get_remote_directory(&remote_root_directory, remote_directory_path),
+                    &mut sess,
+                );
+                return;
+            }
+
+            let mut state = SyncState::load(local_directory_path_).unwrap();
+            let local_directory = prepare_local_directory(local_directory_path_);
+            let (remote_directory, deleted_remote_directory) = split_remote_directory(
+                prepare_remote_directory(&remote_root_directory, remote_directory_path, &state),
+                false,
+            );
+
+            let (unique_on_remote, unique_on_local) = remove_common_files_and_directories(
+                &remote_directory,
+                &local_directory,
+                ignore_case,
             );
+            let (unique_on_remote, unique_on_local) =
+                filter_unique_by_type(&unique_on_remote, &unique_on_local, &types);
+            let (unique_on_local, deleted_on_remote) =
+                separate_deleted_on_remote(&unique_on_local, &deleted_remote_directory, &state);
+            if !deleted_on_remote.is_empty() {
+                println!("\nNot pushing files which were deleted on the remarkable:");
+                print_tree(&deleted_on_remote, 0, dates);
+            }
+
+            if verbose {
+                println!();
+                print_tree(&unique_on_local, 0, dates);
+                println!();
+            }
 
-            let mut sess = connect_to_remote(params);
-            send_to_remote(local_file_path, "/home/root/test_file", &mut sess);
+            // xochitl is stopped the first time something on the remote changes
+            let mut xochitl = None;
+            if sub_matches.get_flag("mirror") {
+                let extraneous = find_extraneous_on_remote(&unique_on_remote, &|path| {
+                    local_directory_path_.join(path).is_dir()
+                });
+                if !extraneous.is_empty() {
+                    println!("\nThe following will be moved to the trash on the remarkable:");
+                    print_extraneous(&extraneous);
+                    if sub_matches.get_flag("yes") || confirm("Continue?") {
+                        xochitl.get_or_insert_with(|| XochitlGuard::stop(&sess));
+                        let trashed =
+                            move_to_remote_trash(&extraneous, &remote_root_directory, &mut sess)
+                                .unwrap();
+                        for hash in trashed {
+                            state.remove(&hash.to_string());
+                        }
+                    } else {
+                        println!("Not moving anything to the trash");
+                    }
+                }
+            }
+
+            // copy unique_on_local to remote
+            if !unique_on_local.is_empty() {
+                xochitl.get_or_insert_with(|| XochitlGuard::stop(&sess));
+            }
+            let profile = DeviceProfile::detect(&mut sess);
+            let pushed = send_directory_to_remote(
+                &unique_on_local,
+                local_directory_path_,
+                &remote_directory,
+                ignore_case,
+                &profile,
+                &mut sess,
+            )
+            .unwrap();
+
+            if xochitl.is_none() {
+                println!("\nNothing to push");
+            }
+            record_pushed(&mut state, pushed, local_directory_path_);
+            state.save(local_directory_path_).unwrap();
         }
         Some(("pull", sub_matches)) => {
             let remote_directory_path = sub_matches
@@ -173,24 +832,36 @@ fn main() {
             let local_directory_path = sub_matches
                 .get_one::<String>("destination")
                 .expect("required");
+            let types = get_types(sub_matches);
+            let ignore_case = sub_matches.get_flag("ignore_case");
             let verbose = sub_matches.get_flag("verbose");
 
-            let ssh_config = get_ssh_config();
-            let params = ssh_config.query("remarkable");
-            let host_name = params
-                .host_name
-                .as_ref()
-                .expect("No HostName in ssh config")
-                .to_string();
-
-            let mut sess = connect_to_remote(params);
-            let output = run_remote_command(&mut sess, format!("ls {}", DIR).as_str());
-            let hashes = get_hashes_from_ls_output(&output);
-            let (remote_root_directory, _) = build_tree(hashes, &mut sess);
-
-            // get the sub-directory on remote if specified
-            let mut remote_directory = remote_root_directory.clone();
-            if remote_directory_path == "" {
+            let (mut sess, host_name) = connect();
+            let (mut remote_root_directory, _) =
+                get_trees(&mut sess, sub_matches.get_flag("include_deleted"), warnings);
+            let filter = TreeFilter {
+                pinned: sub_matches.get_flag("pinned"),
+                tag: sub_matches.get_one::<String>("tag").cloned(),
+                ..TreeFilter::default()
+            };
+            let tags = match filter.tag {
+                Some(_) => read_all_tags(&mut sess),
+                None => HashMap::new(),
+            };
+            remote_root_directory = filter_tree(&remote_root_directory, &filter, &tags);
+            if filter.pinned {
+                remote_root_directory = flatten_tree(&remote_root_directory);
+            }
+
+            if filter.pinned {
+                println!(
+                    "\n{} {} {} {}",
+                    "Pulling favourites from".bold().yellow(),
+                    host_name.bold().yellow(),
+                    "to".bold().yellow(),
+                    local_directory_path.bold().yellow(),
+                );
+            } else if remote_directory_path.is_empty() {
                 println!(
                     "\n{} {} {} {}",
                     "Pulling all files from".bold().yellow(),
@@ -208,43 +879,64 @@ fn main() {
                     "to".bold().yellow(),
                     local_directory_path.bold().yellow(),
                 );
-                for dir in remote_directory_path.split("/") {
-                    remote_directory = remote_directory
-                        .get_directories()
-                        .iter()
-                        .find(|d| d.get_visible_name() == dir)
-                        .expect(&format!(
-                            "{} {} {}\n",
-                            "Error: Directory".bold().red(),
-                            dir.to_string().bold().red(),
-                            "not found in remote directory".bold().red(),
-                        ))
-                        .clone();
-                }
             }
 
             let local_directory_path_ = Path::new(&local_directory_path);
-            if local_directory_path_.exists() == false {
+            if !local_directory_path_.exists() {
                 create_dir_all(local_directory_path_).unwrap();
                 println!(
-                    "\n{} {} {}",
+                    "\n{} created local directory {}",
                     "Success:".bold().green(),
-                    "created local directory",
                     local_directory_path_.to_str().unwrap().italic().purple(),
                 );
             }
 
-            let local_directory = build_local_directory(local_directory_path_).unwrap();
+            let mut state = SyncState::load(local_directory_path_).unwrap();
+            let local_directory = prepare_local_directory(local_directory_path_);
+            let remote_directory =
+                prepare_remote_directory(&remote_root_directory, remote_directory_path, &state);
 
-            let (unique_on_remote, _) =
-                remove_common_files_and_directories(&remote_directory, &local_directory);
+            let (unique_on_remote, unique_on_local) = remove_common_files_and_directories(
+                &remote_directory,
+                &local_directory,
+                ignore_case,
+            );
+            let (unique_on_remote, unique_on_local) =
+                filter_unique_by_type(&unique_on_remote, &unique_on_local, &types);
 
             if verbose {
                 println!();
-                print_tree(&unique_on_remote, 0);
+                print_tree(&unique_on_remote, 0, dates);
                 println!();
             }
 
+            if sub_matches.get_flag("mirror") {
+                let extraneous = find_extraneous(&unique_on_local, Path::new(""), &|path| {
+                    find_directory(&remote_directory, path.to_str().unwrap()).is_some()
+                });
+                if !extraneous.is_empty() {
+                    println!(
+                        "\nThe following will be moved to {}:",
+                        local_directory_path_
+                            .join(LOCAL_TRASH_DIR)
+                            .to_str()
+                            .unwrap()
+                    );
+                    print_extraneous(&extraneous);
+                    if sub_matches.get_flag("yes") || confirm("Continue?") {
+                        move_to_local_trash(local_directory_path_, &extraneous).unwrap();
+                        for entry in &extraneous {
+                            // directories with anything left in them are kept
+                            if !local_directory_path_.join(&entry.path).exists() {
+                                state.remove_below(&entry.path);
+                            }
+                        }
+                    } else {
+                        println!("Not moving anything to the trash");
+                    }
+                }
+            }
+
             // copy unique_on_remote to local
             copy_directory_from_remote(
                 &unique_on_remote,
@@ -252,6 +944,513 @@ fn main() {
                 &mut sess,
             )
             .unwrap();
+
+            // remember where each document ended up, so it matches next time
+            state
+                .record_directory(&remote_directory, local_directory_path_, Path::new(""))
+                .unwrap();
+            state.save(local_directory_path_).unwrap();
+        }
+        Some(("sync", sub_matches)) => {
+            let remote_directory_path = sub_matches
+                .get_one::<String>("remote_directory")
+                .expect("required");
+            let local_directory_path = sub_matches
+                .get_one::<String>("local_directory")
+                .expect("required");
+            let types = get_types(sub_matches);
+            let ignore_case = sub_matches.get_flag("ignore_case");
+            let verbose = sub_matches.get_flag("verbose");
+
+            let (mut sess, host_name) = connect();
+            let (remote_root_directory, _) = get_trees(&mut sess, true, warnings);
+
+            if remote_directory_path.is_empty() {
+                println!(
+                    "\n{} {} {} {}",
+                    "Syncing all files on".bold().yellow(),
+                    host_name.bold().yellow(),
+                    "with".bold().yellow(),
+                    local_directory_path.bold().yellow(),
+                );
+            } else {
+                println!(
+                    "\n{} {} {} {} {} {}",
+                    "Syncing files from".bold().yellow(),
+                    remote_directory_path.bold().yellow(),
+                    "on".bold().yellow(),
+                    host_name.bold().yellow(),
+                    "with".bold().yellow(),
+                    local_directory_path.bold().yellow(),
+                );
+            }
+
+            let local_directory_path_ = Path::new(&local_directory_path);
+            if !local_directory_path_.exists() {
+                create_dir_all(local_directory_path_).unwrap();
+            }
+
+            let mut state = SyncState::load(local_directory_path_).unwrap();
+            let local_directory = prepare_local_directory(local_directory_path_);
+            let (remote_directory, deleted_remote_directory) = split_remote_directory(
+                prepare_remote_directory(&remote_root_directory, remote_directory_path, &state),
+                false,
+            );
+
+            let (unique_on_remote, unique_on_local) = remove_common_files_and_directories(
+                &remote_directory,
+                &local_directory,
+                ignore_case,
+            );
+            let (unique_on_remote, unique_on_local) =
+                filter_unique_by_type(&unique_on_remote, &unique_on_local, &types);
+            let renames = detect_renames(
+                &unique_on_remote,
+                &unique_on_local,
+                local_directory_path_,
+                &state,
+                &mut sess,
+            )
+            .unwrap();
+            let (unique_on_remote, unique_on_local) =
+                remove_renamed(&unique_on_remote, &unique_on_local, &renames);
+            let (unique_on_local, deleted_on_remote) =
+                separate_deleted_on_remote(&unique_on_local, &deleted_remote_directory, &state);
+
+            if verbose {
+                println!("\nUnique on remote:");
+                print_tree(&unique_on_remote, 0, dates);
+                println!("\nUnique on local:");
+                print_tree(&unique_on_local, 0, dates);
+                println!("\nRenamed or moved:");
+                print_renames(&renames);
+                println!("\nDeleted on remote:");
+                print_tree(&deleted_on_remote, 0, dates);
+                println!();
+            }
+
+            let remote_changes =
+                !unique_on_local.is_empty() || renames.iter().any(|r| r.changed_on == Side::Local);
+            let _xochitl = remote_changes.then(|| XochitlGuard::stop(&sess));
+            let profile = DeviceProfile::detect(&mut sess);
+
+            // renames first, so the files aren't copied again
+            apply_renames(
+                &renames,
+                &remote_directory,
+                local_directory_path_,
+                &mut state,
+                &profile,
+                &mut sess,
+            )
+            .unwrap();
+
+            copy_directory_from_remote(&unique_on_remote, local_directory_path_, &mut sess)
+                .unwrap();
+            state
+                .record_directory(&remote_directory, local_directory_path_, Path::new(""))
+                .unwrap();
+
+            let pushed = send_directory_to_remote(
+                &unique_on_local,
+                local_directory_path_,
+                &remote_directory,
+                ignore_case,
+                &profile,
+                &mut sess,
+            )
+            .unwrap();
+            record_pushed(&mut state, pushed, local_directory_path_);
+            state.save(local_directory_path_).unwrap();
+        }
+        Some(("mkdir", sub_matches)) => {
+            let paths = get_many(sub_matches, "paths");
+            let parents = sub_matches.get_flag("parents");
+
+            let (mut sess, _) = connect();
+            let (remote_root_directory, _) = get_trees(&mut sess, false, warnings);
+            let profile = DeviceProfile::detect(&mut sess);
+
+            let mut created = HashMap::new();
+            edit_remote(&mut sess, |sess| {
+                paths.iter().try_for_each(|path| {
+                    make_remote_directory(
+                        &remote_root_directory,
+                        path,
+                        parents,
+                        &mut created,
+                        &profile,
+                        sess,
+                    )
+                    .map(|_| ())
+                })
+            });
+        }
+        Some(("mv", sub_matches)) => {
+            let sources = get_many(sub_matches, "sources");
+            let destination = sub_matches
+                .get_one::<String>("destination")
+                .expect("required");
+
+            let (mut sess, _) = connect();
+            let (remote_root_directory, _) = get_trees(&mut sess, false, warnings);
+
+            edit_remote(&mut sess, |sess| {
+                move_remote_nodes(&remote_root_directory, &sources, destination, sess)
+            });
+        }
+        Some(("rename", sub_matches)) => {
+            let path = sub_matches.get_one::<String>("path").expect("required");
+            let new_name = sub_matches.get_one::<String>("new_name").expect("required");
+
+            let (mut sess, _) = connect();
+            let (remote_root_directory, _) = get_trees(&mut sess, false, warnings);
+
+            edit_remote(&mut sess, |sess| {
+                rename_remote_node(&remote_root_directory, path, new_name, sess)
+            });
+        }
+        Some((command @ ("pin" | "unpin"), sub_matches)) => {
+            let paths = get_many(sub_matches, "paths");
+
+            let (mut sess, _) = connect();
+            let (remote_root_directory, _) = get_trees(&mut sess, false, warnings);
+
+            edit_remote(&mut sess, |sess| {
+                pin_remote_nodes(&remote_root_directory, &paths, command == "pin", sess)
+            });
+        }
+        Some(("rm", sub_matches)) => {
+            let paths = get_many(sub_matches, "paths");
+            let permanent = sub_matches.get_flag("permanent");
+
+            let (mut sess, _) = connect();
+            let (remote_root_directory, _) = get_trees(&mut sess, false, warnings);
+
+            edit_remote(&mut sess, |sess| {
+                remove_remote_nodes(&remote_root_directory, &paths, permanent, sess)
+            });
+        }
+        Some(("backup", sub_matches)) => {
+            let output_directory = sub_matches
+                .get_one::<String>("output_directory")
+                .expect("default");
+            let (mut sess, host_name) = connect();
+
+            let archive = Path::new(output_directory).join(backup_file_name(&host_name));
+            println!(
+                "\n{} {} {} {}",
+                "Backing up".bold().yellow(),
+                host_name.bold().yellow(),
+                "to".bold().yellow(),
+                archive.to_str().unwrap().bold().yellow(),
+            );
+            backup_device(&archive, &mut sess)
+                .unwrap_or_else(|e| panic!("{} {}", "Error:".bold().red(), e));
+            println!(
+                "{} backed up to {}",
+                "Success:".bold().green(),
+                archive.to_str().unwrap().italic().purple()
+            );
+        }
+        Some(("restore", sub_matches)) => {
+            let archive = Path::new(sub_matches.get_one::<String>("archive").expect("required"));
+            let documents: Vec<String> = sub_matches
+                .get_many::<String>("documents")
+                .map(|d| d.cloned().collect())
+                .unwrap_or_default();
+            let (mut sess, host_name) = connect();
+
+            let only = match documents.is_empty() {
+                true => None,
+                false => Some(
+                    resolve_backup_documents(archive, &documents, &mut sess)
+                        .unwrap_or_else(|e| panic!("{} {}", "Error:".bold().red(), e)),
+                ),
+            };
+
+            match &only {
+                Some(only) => println!(
+                    "\nThe files of {} documents on {} will be overwritten by the versions in {}",
+                    only.len(),
+                    host_name.bold().yellow(),
+                    archive.to_str().unwrap().bold().yellow()
+                ),
+                None => println!(
+                    "\nFiles on {} will be overwritten by the versions in {}, files which aren't in it are kept",
+                    host_name.bold().yellow(),
+                    archive.to_str().unwrap().bold().yellow()
+                ),
+            }
+            if !sub_matches.get_flag("yes") && !confirm("Continue?") {
+                println!("Not restoring anything");
+                return;
+            }
+
+            edit_remote(&mut sess, |sess| {
+                let restored = restore_device(archive, only.as_ref(), sess)?;
+                print_restored(archive, restored);
+                Ok(())
+            });
+        }
+        Some(("snapshot", sub_matches)) => {
+            let (mut sess, host_name) = connect();
+            let store = SnapshotStore::open(&host_name)
+                .unwrap_or_else(|e| panic!("{} {}", "Error:".bold().red(), e));
+
+            match sub_matches.subcommand() {
+                Some(("list", _)) => {
+                    println!(
+                        "\n{} {}\n",
+                        "Snapshots of".bold().yellow(),
+                        host_name.bold().yellow()
+                    );
+                    print_snapshots(&store.list().unwrap());
+                    println!();
+                }
+                Some(("restore", restore_matches)) => {
+                    let id = restore_matches.get_one::<String>("id").expect("required");
+                    let path = restore_matches.get_one::<String>("path").expect("required");
+
+                    let snapshot = store
+                        .load(id)
+                        .unwrap_or_else(|e| panic!("{} {}", "Error:".bold().red(), e));
+                    let hashes = store
+                        .resolve_documents(&snapshot, std::slice::from_ref(path), &mut sess)
+                        .unwrap_or_else(|e| panic!("{} {}", "Error:".bold().red(), e));
+
+                    println!(
+                        "\n{} documents on {} will be replaced by the versions in snapshot {}",
+                        hashes.len(),
+                        host_name.bold().yellow(),
+                        id.bold().yellow()
+                    );
+                    if !restore_matches.get_flag("yes") && !confirm("Continue?") {
+                        println!("Not restoring anything");
+                        return;
+                    }
+
+                    edit_remote(&mut sess, |sess| {
+                        let restored = store.restore_documents(&snapshot, &hashes, sess)?;
+                        println!(
+                            "{} restored {} files from snapshot {}",
+                            "Success:".bold().green(),
+                            restored,
+                            id.italic().purple()
+                        );
+                        Ok(())
+                    });
+                }
+                _ => {
+                    println!(
+                        "\n{} {}",
+                        "Taking a snapshot of".bold().yellow(),
+                        host_name.bold().yellow()
+                    );
+                    let snapshot = store
+                        .take_snapshot(&mut sess)
+                        .unwrap_or_else(|e| panic!("{} {}", "Error:".bold().red(), e));
+                    println!(
+                        "{} took snapshot {} of {} files, storing {} of changes",
+                        "Success:".bold().green(),
+                        snapshot.id.italic().purple(),
+                        snapshot.files.len(),
+                        format_size(snapshot.stored)
+                    );
+                }
+            }
+        }
+        Some(("fsck", sub_matches)) => {
+            let (mut sess, host_name) = connect();
+
+            println!(
+                "\n{} {}\n",
+                "Checking documents on".bold().yellow(),
+                host_name.bold().yellow()
+            );
+            let problems = check_device(&mut sess);
+            if problems.is_empty() {
+                println!("No problems found");
+                return;
+            }
+            for problem in &problems {
+                println!("  {}", problem);
+            }
+
+            let repairable: Vec<Problem> =
+                problems.into_iter().filter(|p| p.is_repairable()).collect();
+            if !sub_matches.get_flag("repair") || repairable.is_empty() {
+                return;
+            }
+            println!("\n{} problems can be repaired", repairable.len());
+            if sub_matches.get_flag("yes") || confirm("Continue?") {
+                let profile = DeviceProfile::detect(&mut sess);
+                edit_remote(&mut sess, |sess| repair_device(&repairable, &profile, sess));
+                println!(
+                    "{} repaired {} problems",
+                    "Success:".bold().green(),
+                    repairable.len()
+                );
+            } else {
+                println!("Not repairing anything");
+            }
+        }
+        Some(("gc", sub_matches)) => {
+            let (mut sess, host_name) = connect();
+
+            println!(
+                "\n{} {}\n",
+                "Looking for orphaned files on".bold().yellow(),
+                host_name.bold().yellow()
+            );
+            let garbage = find_garbage(&mut sess);
+            if garbage.is_empty() {
+                println!("Nothing to delete");
+                return;
+            }
+            print_garbage(&garbage);
+            let size: u64 = garbage.iter().map(|g| g.size).sum();
+
+            if sub_matches.get_flag("dry_run") {
+                println!("\nWould reclaim {}", format_size(size));
+                return;
+            }
+            if !sub_matches.get_flag("yes") && !confirm("\nDelete these files?") {
+                println!("Not deleting anything");
+                return;
+            }
+            let mut reclaimed = 0;
+            edit_remote(&mut sess, |sess| {
+                reclaimed = collect_garbage(&garbage, sess);
+                Ok(())
+            });
+            println!(
+                "{} reclaimed {}",
+                "Success:".bold().green(),
+                format_size(reclaimed)
+            );
+        }
+        Some(("du", sub_matches)) => {
+            let remote_directory_path = sub_matches
+                .get_one::<String>("remote_directory")
+                .expect("default");
+            let depth = *sub_matches.get_one::<usize>("depth").expect("default");
+            let top = sub_matches.get_one::<usize>("top").copied();
+
+            let (mut sess, host_name) = connect();
+            let (remote_root_directory, trash_directory) = get_trees(&mut sess, false, warnings);
+
+            println!(
+                "\n{} {}\n",
+                "Storage used on".bold().yellow(),
+                host_name.bold().yellow()
+            );
+            let sizes = get_sizes_by_hash(&mut sess);
+            let directory = get_remote_directory(&remote_root_directory, remote_directory_path);
+            print_usage(&get_usage(&directory, &sizes), depth, top);
+
+            if remote_directory_path.is_empty() {
+                let trash = get_usage(&trash_directory, &sizes);
+                print_usage(&trash, 0, None);
+                let total: u64 = sizes.values().sum();
+                let documents = get_usage(&remote_root_directory, &sizes).size + trash.size;
+                println!(
+                    "{:>10}  {}",
+                    format_size(total.saturating_sub(documents)),
+                    "deleted and orphaned files".italic()
+                );
+                println!("\n{:>10}  {}", format_size(total), "total".bold());
+            }
+
+            if let Some(space) = get_disk_space(&mut sess) {
+                println!(
+                    "\n{} of {} used, {} free",
+                    format_size(space.used),
+                    format_size(space.total),
+                    format_size(space.available).bold()
+                );
+            }
+        }
+        Some(("info", sub_matches)) => {
+            let (mut sess, host_name) = connect();
+            let (remote_root_directory, trash_directory) = get_trees(&mut sess, false, warnings);
+            let info = get_device_info(&remote_root_directory, &trash_directory, &mut sess);
+
+            if sub_matches.get_flag("json") {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&info).expect("Failed to serialize info")
+                );
+                return;
+            }
+            println!(
+                "\n{} {}\n",
+                "About".bold().yellow(),
+                host_name.bold().yellow()
+            );
+            print_device_info(&info);
+        }
+        Some(("restart-ui", _)) => {
+            let (sess, host_name) = connect();
+            restart_ui(&sess).unwrap_or_else(|e| panic!("{} {}", "Error:".bold().red(), e));
+            println!(
+                "{} restarted the UI on {}",
+                "Success:".bold().green(),
+                host_name.bold().yellow()
+            );
+        }
+        Some(("trash", sub_matches)) => {
+            let (mut sess, host_name) = connect();
+            let (remote_root_directory, trash_directory) = get_trees(&mut sess, false, warnings);
+
+            match sub_matches.subcommand() {
+                Some(("list", _)) => {
+                    println!(
+                        "\n{} {}\n",
+                        "Trash on".bold().yellow(),
+                        host_name.bold().yellow()
+                    );
+                    let record = TrashRecord::load(&mut sess).unwrap();
+                    print_trash(&trash_directory, &remote_root_directory, &record, dates);
+                    println!();
+                }
+                Some(("restore", restore_matches)) => {
+                    let path = restore_matches.get_one::<String>("path").expect("required");
+                    let to = restore_matches.get_one::<String>("to");
+
+                    edit_remote(&mut sess, |sess| {
+                        restore_from_trash(
+                            &trash_directory,
+                            &remote_root_directory,
+                            path,
+                            to.map(|t| t.as_str()),
+                            sess,
+                        )
+                    });
+                }
+                Some(("empty", empty_matches)) => {
+                    let older_than = empty_matches.get_one::<String>("older_than").map(|age| {
+                        parse_age(age).unwrap_or_else(|e| panic!("{} {}", "Error:".bold().red(), e))
+                    });
+
+                    let record = TrashRecord::load(&mut sess).unwrap();
+                    let expired = find_expired(&trash_directory, &record, older_than);
+                    if expired.is_empty() {
+                        println!("\nNothing to delete");
+                        return;
+                    }
+                    println!("\nThe following will be permanently deleted:");
+                    for node in &expired {
+                        println!("  {}", node.get_visible_name().purple());
+                    }
+                    if empty_matches.get_flag("yes") || confirm("Continue?") {
+                        edit_remote(&mut sess, |sess| empty_trash(&expired, sess));
+                    } else {
+                        println!("Not deleting anything");
+                    }
+                }
+                _ => unreachable!(),
+            }
         }
         _ => unreachable!(), // If all subcommands are defined above, anything else is unreachable!()
     };
@@ -0,0 +1,316 @@
+use std::fs::{create_dir_all, read_dir, remove_dir, rename};
+use std::io::{stdin, stdout, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::Local;
+use colored::*;
+use ssh2::Session;
+
+use crate::constants::LOCAL_TRASH_DIR;
+use crate::file_types::{filter_directory_by_type, FileType, ALL_TYPES};
+use crate::nodes::{Directory, DirectoryNode, Hash, Node};
+use crate::trash::trash_remote_nodes;
+
+/// A file or directory which no longer exists on the side being mirrored.
+#[derive(Clone, Debug)]
+pub struct Extraneous {
+    /// The path relative to the mirrored directory
+    pub path: PathBuf,
+    pub hash: Hash,
+    pub is_directory: bool,
+}
+
+/// Find what has to be removed to mirror the other side, given what is
+/// unique to this side from `remove_common_files_and_directories`.
+///
+/// Only the files in `unique` are removed, so anything left out of it, e.g.
+/// ignored files, is kept. Directories which don't exist on the other side
+/// are listed after their contents, and are only removed if nothing is left
+/// in them. `exists_on_source` tells whether a directory exists on the side
+/// being mirrored.
+pub fn find_extraneous(
+    unique: &Directory,
+    relative_path: &Path,
+    exists_on_source: &dyn Fn(&Path) -> bool,
+) -> Vec<Extraneous> {
+    let mut extraneous = Vec::new();
+    for sub_directory in unique.get_directories() {
+        let path = relative_path.join(sub_directory.get_visible_name());
+        extraneous.extend(find_extraneous(sub_directory, &path, exists_on_source));
+        if !exists_on_source(&path) {
+            extraneous.push(Extraneous {
+                path,
+                hash: sub_directory.get_hash().clone(),
+                is_directory: true,
+            });
+        }
+    }
+    for file in unique.get_files() {
+        extraneous.push(Extraneous {
+            path: relative_path.join(file.get_visible_name()),
+            hash: file.get_hash().clone(),
+            is_directory: false,
+        });
+    }
+    extraneous
+}
+
+/// Find what has to be moved to the trash on the remote to mirror the local
+/// directory. Only types which pull can produce are considered, so e.g. a
+/// notebook is never trashed for lacking a local copy it can't have.
+pub fn find_extraneous_on_remote(
+    unique_on_remote: &Directory,
+    exists_locally: &dyn Fn(&Path) -> bool,
+) -> Vec<Extraneous> {
+    let pullable: Vec<FileType> = ALL_TYPES.into_iter().filter(FileType::can_pull).collect();
+    let mirrored = filter_directory_by_type(unique_on_remote, &pullable);
+    find_extraneous(&mirrored, Path::new(""), exists_locally)
+}
+
+pub fn print_extraneous(extraneous: &[Extraneous]) {
+    for entry in extraneous {
+        let path = entry.path.to_string_lossy();
+        if entry.is_directory {
+            println!(
+                "  {}{} {}",
+                path.bold().green(),
+                "/".bold().green(),
+                "(if left empty)".italic()
+            );
+        } else {
+            println!("  {}", path.purple());
+        }
+    }
+}
+
+/// Ask a yes/no question on the terminal, defaulting to no.
+pub fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    stdout().flush().unwrap();
+    let mut answer = String::new();
+    if stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Move local files into a timestamped directory in `.remarko-trash/`
+/// rather than deleting them, then remove the directories which are left
+/// empty. Returns the directory the files were moved to.
+pub fn move_to_local_trash(
+    local_root: &Path,
+    extraneous: &[Extraneous],
+) -> Result<PathBuf, std::io::Error> {
+    let trash_directory = local_root
+        .join(LOCAL_TRASH_DIR)
+        .join(Local::now().format("%Y-%m-%d_%H-%M-%S").to_string());
+
+    for entry in extraneous.iter().filter(|e| !e.is_directory) {
+        let destination = trash_directory.join(&entry.path);
+        if let Some(parent) = destination.parent() {
+            create_dir_all(parent)?;
+        }
+        rename(local_root.join(&entry.path), destination)?;
+    }
+    // directories come after what is in them, so nested ones are removed first
+    for entry in extraneous.iter().filter(|e| e.is_directory) {
+        let path = local_root.join(&entry.path);
+        if read_dir(&path)?.next().is_none() {
+            remove_dir(path)?;
+        }
+    }
+
+    Ok(trash_directory)
+}
+
+/// Move documents to the remarkable's trash by changing their parent, so they
+/// can still be restored from the device, along with the collections which
+/// would be left empty. `root` is the whole tree on the remarkable, which
+/// tells what else is in a collection. Returns the hashes which were moved.
+pub fn move_to_remote_trash(
+    extraneous: &[Extraneous],
+    root: &Directory,
+    sess: &mut Session,
+) -> Result<Vec<Hash>, Box<dyn std::error::Error>> {
+    let mut hashes: Vec<Hash> = extraneous
+        .iter()
+        .filter(|e| !e.is_directory)
+        .map(|e| e.hash.clone())
+        .collect();
+    // directories come after what is in them, so nested ones are checked first
+    for entry in extraneous.iter().filter(|e| e.is_directory) {
+        match find_directory_by_hash(root, &entry.hash) {
+            Some(directory) if holds_only(directory, &hashes) => hashes.push(entry.hash.clone()),
+            _ => {}
+        }
+    }
+    trash_remote_nodes(&hashes, sess)?;
+    Ok(hashes)
+}
+
+fn find_directory_by_hash<'a>(directory: &'a Directory, hash: &Hash) -> Option<&'a Directory> {
+    if directory.get_hash().to_string() == hash.to_string() {
+        return Some(directory);
+    }
+    directory
+        .get_directories()
+        .iter()
+        .find_map(|d| find_directory_by_hash(d, hash))
+}
+
+/// Whether everything live in a directory is among `hashes`.
+fn holds_only(directory: &Directory, hashes: &[Hash]) -> bool {
+    let is_gone = |node: &dyn Node| {
+        node.get_metadata().is_deleted()
+            || hashes
+                .iter()
+                .any(|h| h.to_string() == node.get_hash().to_string())
+    };
+    directory.get_files().iter().all(|f| is_gone(f))
+        && directory.get_directories().iter().all(|d| is_gone(d))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, write};
+
+    use super::*;
+    use crate::test_utils::{directory, file};
+
+    fn paths(extraneous: &[Extraneous]) -> Vec<(String, bool)> {
+        extraneous
+            .iter()
+            .map(|e| (e.path.to_string_lossy().to_string(), e.is_directory))
+            .collect()
+    }
+
+    #[test]
+    fn missing_directories_come_after_their_files() {
+        let unique = directory(
+            "",
+            "",
+            vec![file("a", "Loose.pdf", Some(FileType::Pdf))],
+            vec![
+                directory(
+                    "b",
+                    "Old",
+                    vec![file("c", "Paper.pdf", Some(FileType::Pdf))],
+                    vec![directory(
+                        "d",
+                        "Drafts",
+                        vec![file("e", "Draft.pdf", Some(FileType::Pdf))],
+                        Vec::new(),
+                    )],
+                ),
+                directory(
+                    "f",
+                    "Kept",
+                    vec![file("g", "Gone.pdf", Some(FileType::Pdf))],
+                    Vec::new(),
+                ),
+            ],
+        );
+        let extraneous = find_extraneous(&unique, Path::new(""), &|path| path == Path::new("Kept"));
+        assert_eq!(
+            paths(&extraneous),
+            vec![
+                ("Old/Drafts/Draft.pdf".to_string(), false),
+                ("Old/Drafts".to_string(), true),
+                ("Old/Paper.pdf".to_string(), false),
+                ("Old".to_string(), true),
+                ("Kept/Gone.pdf".to_string(), false),
+                ("Loose.pdf".to_string(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn notebooks_are_not_trashed_on_the_remote() {
+        let unique_on_remote = directory(
+            "",
+            "",
+            vec![
+                file("a", "Notes", Some(FileType::Notebook)),
+                file("b", "Paper", Some(FileType::Pdf)),
+            ],
+            vec![directory(
+                "c",
+                "Journal",
+                vec![file("d", "Today", Some(FileType::Notebook))],
+                Vec::new(),
+            )],
+        );
+        let extraneous = find_extraneous_on_remote(&unique_on_remote, &|_| false);
+        // Journal is listed, but only trashed if nothing is left in it
+        assert_eq!(
+            paths(&extraneous),
+            vec![("Journal".to_string(), true), ("Paper".to_string(), false)]
+        );
+    }
+
+    #[test]
+    fn ignored_files_keep_their_directory() {
+        let local_root = tempfile::tempdir().unwrap();
+        create_dir_all(local_root.path().join("Old/Drafts")).unwrap();
+        write(local_root.path().join("Old/Paper.pdf"), "pdf").unwrap();
+        write(local_root.path().join("Old/paper.tex"), "tex").unwrap();
+        write(local_root.path().join("Old/Drafts/Draft.pdf"), "pdf").unwrap();
+
+        // paper.tex is ignored, so it isn't in what is unique locally
+        let unique = directory(
+            "",
+            "",
+            Vec::new(),
+            vec![directory(
+                "",
+                "Old",
+                vec![file("", "Paper.pdf", Some(FileType::Pdf))],
+                vec![directory(
+                    "",
+                    "Drafts",
+                    vec![file("", "Draft.pdf", Some(FileType::Pdf))],
+                    Vec::new(),
+                )],
+            )],
+        );
+        let extraneous = find_extraneous(&unique, Path::new(""), &|_| false);
+        let trash_directory = move_to_local_trash(local_root.path(), &extraneous).unwrap();
+
+        assert!(local_root.path().join("Old/paper.tex").exists());
+        assert!(!local_root.path().join("Old/Paper.pdf").exists());
+        assert!(!local_root.path().join("Old/Drafts").exists());
+        assert!(trash_directory.join("Old/Paper.pdf").exists());
+        assert!(trash_directory.join("Old/Drafts/Draft.pdf").exists());
+        assert!(!trash_directory.join("Old/paper.tex").exists());
+    }
+
+    #[test]
+    fn collections_with_other_documents_are_not_trashed() {
+        let old = directory(
+            "b",
+            "Old",
+            vec![
+                file("c", "Paper", Some(FileType::Pdf)),
+                file("d", "Ignored", Some(FileType::Pdf)),
+            ],
+            vec![directory(
+                "e",
+                "Drafts",
+                vec![file("f", "Draft", Some(FileType::Pdf))],
+                Vec::new(),
+            )],
+        );
+        let root = directory("", "", Vec::new(), vec![old]);
+        let find = |hash: &str| find_directory_by_hash(&root, &Hash::new(hash.to_string()));
+        let hashes = |hashes: &[&str]| -> Vec<Hash> {
+            hashes.iter().map(|h| Hash::new(h.to_string())).collect()
+        };
+
+        assert!(holds_only(find("e").unwrap(), &hashes(&["f"])));
+        assert!(!holds_only(find("b").unwrap(), &hashes(&["c", "f", "e"])));
+        assert!(holds_only(
+            find("b").unwrap(),
+            &hashes(&["c", "d", "f", "e"])
+        ));
+    }
+}
@@ -1,8 +1,12 @@
-use chrono::{DateTime, NaiveDateTime, Utc};
-use serde::Deserialize;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use std::any::Any;
 use std::fmt;
 
+use crate::dates::parse_timestamp;
+use crate::file_names::names_match;
+use crate::file_types::FileType;
+
 pub trait AsAny {
     fn as_any(&self) -> &dyn Any;
 }
@@ -25,7 +29,7 @@ impl AsAny for SystemDirectory {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Hash(String);
 
 impl Hash {
@@ -40,15 +44,117 @@ impl fmt::Display for Hash {
     }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+/// A time in milliseconds since the epoch, as found in `.metadata`.
+///
+/// xochitl writes these as strings, but some firmware versions and tools
+/// write numbers, so both are read and written back in the form they were
+/// read.
+///
+/// ```
+/// use remarko::nodes::Timestamp;
+///
+/// let string: Timestamp = serde_json::from_str(r#""1700000000000""#).unwrap();
+/// let number: Timestamp = serde_json::from_str("1700000000000").unwrap();
+/// assert_eq!(string.get_millis(), number.get_millis());
+/// assert_eq!(serde_json::to_string(&number).unwrap(), "1700000000000");
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Timestamp {
+    String(String),
+    Number(serde_json::Number),
+}
+
+impl Timestamp {
+    pub fn get_millis(&self) -> Option<i64> {
+        match self {
+            Timestamp::String(s) => s.trim().parse().ok(),
+            Timestamp::Number(n) => n.as_i64(),
+        }
+    }
+
+    /// None for 0, which xochitl uses for never.
+    pub fn get_date(&self) -> Option<DateTime<Utc>> {
+        match self {
+            Timestamp::String(s) => parse_timestamp(s),
+            Timestamp::Number(n) => parse_timestamp(&n.to_string()),
+        }
+    }
+
+    /// Change the time, keeping the form it was read in.
+    pub fn set_millis(&mut self, millis: i64) {
+        *self = match self {
+            Timestamp::String(_) => Timestamp::String(millis.to_string()),
+            Timestamp::Number(_) => Timestamp::Number(millis.into()),
+        };
+    }
+}
+
+/// The `.metadata` of a document or collection.
+///
+/// Every field xochitl is known to write is modelled, and any others are
+/// kept in `extra`, so metadata read and written back by remarko keeps
+/// everything the firmware relies on. Fields which were missing stay missing.
+///
+/// ```
+/// use remarko::nodes::Metadata;
+///
+/// let json = r#"{"visibleName":"Notes","parent":"","lastModified":"1700000000000","type":"DocumentType","version":3,"newField":{"a":1}}"#;
+/// let mut metadata: Metadata = serde_json::from_str(json).unwrap();
+/// metadata.set_visible_name("Old notes".into());
+/// let written = serde_json::to_value(&metadata).unwrap();
+/// assert_eq!(written["visibleName"], "Old notes");
+/// assert_eq!(written["version"], 3);
+/// assert_eq!(written["newField"]["a"], 1);
+/// assert!(written.get("deleted").is_none());
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Metadata {
     #[serde(rename = "visibleName")]
     visible_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     parent: Option<String>,
     #[serde(rename = "lastModified")]
-    last_modified: String,
+    last_modified: Timestamp,
     #[serde(rename = "type")]
     pub type_: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deleted: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadatamodified: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    modified: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pinned: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    synced: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
+    #[serde(
+        rename = "lastOpened",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    last_opened: Option<Timestamp>,
+    #[serde(
+        rename = "lastOpenedPage",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    last_opened_page: Option<u64>,
+    #[serde(
+        rename = "createdTime",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    created_time: Option<Timestamp>,
+    /// Where the document came from, e.g. an email address for documents
+    /// sent to the tablet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    /// Fields remarko doesn't know about
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
 }
 
 pub trait Node: AsAny {
@@ -65,13 +171,15 @@ pub trait DirectoryNode: Node {
     fn add_file(&mut self, file: File);
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct File {
     hash: Hash,
     metadata: Metadata,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    file_type: Option<FileType>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Directory {
     hash: Hash,
     metadata: Metadata,
@@ -178,30 +286,51 @@ impl DirectoryNode for SystemDirectory {
 
 impl File {
     pub fn new(hash: Hash, metadata: Metadata) -> File {
-        File { hash, metadata }
+        File {
+            hash,
+            metadata,
+            file_type: None,
+        }
+    }
+
+    pub fn get_file_type(&self) -> Option<FileType> {
+        self.file_type
     }
 
-    pub fn get_last_modified(&self) -> String {
-        // TODO fix this to parse the timestamp correctly
-        let naive_datetime = NaiveDateTime::from_timestamp_opt(
-            self.metadata.last_modified.parse::<i64>().unwrap(),
-            0,
-        )
-        .expect("Failed to parse timestamp");
-        let utc_datetime: DateTime<Utc> = DateTime::from_naive_utc_and_offset(naive_datetime, Utc);
-        utc_datetime.format("%Y-%m-%d %H:%M:%S").to_string()
+    pub fn set_file_type(&mut self, file_type: FileType) {
+        self.file_type = Some(file_type);
     }
 
-    /// if file_name ends with ".pdf or .epub", remove that
+    pub fn get_last_modified(&self) -> Option<DateTime<Utc>> {
+        self.metadata.get_last_modified()
+    }
+
+    /// if file_name ends with the extension of a known file type, e.g. ".pdf", remove that
     pub fn get_file_stem(&self) -> String {
         let file_name = self.get_visible_name();
-        if file_name.ends_with(".pdf") || file_name.ends_with(".epub") {
-            let split_file_name = file_name.split(".").collect::<Vec<&str>>();
-            split_file_name[..split_file_name.len() - 1].join(".")
-        } else {
-            file_name.to_string()
+        match file_name.rsplit_once('.') {
+            Some((stem, extension)) if FileType::from_extension(extension).is_some() => {
+                stem.to_string()
+            }
+            _ => file_name.to_string(),
         }
     }
+
+    /// The name to give this file on the local machine, e.g. "Notes.pdf"
+    pub fn get_local_file_name(&self) -> String {
+        match self.file_type {
+            Some(file_type) => format!("{}.{}", self.get_file_stem(), file_type.local_extension()),
+            None => self.get_visible_name().to_string(),
+        }
+    }
+
+    /// Whether two files are the same document on either side of a transfer,
+    /// i.e. they have matching stems and are transferred in the same format.
+    pub fn is_same_document(&self, other: &File, ignore_case: bool) -> bool {
+        names_match(&self.get_file_stem(), &other.get_file_stem(), ignore_case)
+            && self.file_type.map(|t| t.transfer_format())
+                == other.file_type.map(|t| t.transfer_format())
+    }
 }
 
 impl Metadata {
@@ -214,10 +343,98 @@ impl Metadata {
         Metadata {
             visible_name,
             parent,
-            last_modified,
+            last_modified: Timestamp::String(last_modified),
             type_,
+            deleted: None,
+            metadatamodified: None,
+            modified: None,
+            pinned: None,
+            synced: None,
+            version: None,
+            last_opened: None,
+            last_opened_page: None,
+            created_time: None,
+            source: None,
+            extra: serde_json::Map::new(),
         }
     }
+
+    pub fn set_visible_name(&mut self, visible_name: String) {
+        self.visible_name = visible_name;
+    }
+
+    pub fn get_parent(&self) -> Option<&String> {
+        self.parent.as_ref()
+    }
+
+    pub fn set_parent(&mut self, parent: String) {
+        self.parent = Some(parent);
+    }
+
+    /// Whether it has been deleted for good, and only waits for xochitl or a
+    /// sync to remove its files.
+    pub fn is_deleted(&self) -> bool {
+        self.deleted.unwrap_or(false)
+    }
+
+    pub fn set_deleted(&mut self, deleted: bool) {
+        self.deleted = Some(deleted);
+    }
+
+    pub fn get_version(&self) -> u64 {
+        self.version.unwrap_or(0)
+    }
+
+    /// Counting from 0
+    pub fn get_last_opened_page(&self) -> Option<u64> {
+        self.last_opened_page
+    }
+
+    pub fn get_source(&self) -> Option<&String> {
+        self.source.as_ref()
+    }
+
+    /// Record a change made by remarko the way xochitl records its own: bump
+    /// the version, flag the metadata as modified and update the
+    /// modification time.
+    pub fn mark_changed(&mut self) {
+        self.version = Some(self.get_version() + 1);
+        self.metadatamodified = Some(true);
+        self.last_modified.set_millis(Utc::now().timestamp_millis());
+    }
+
+    /// The raw `lastModified` value, in milliseconds since the epoch.
+    pub fn get_last_modified_timestamp(&self) -> Option<i64> {
+        self.last_modified.get_millis()
+    }
+
+    /// The raw `lastOpened` value, or None if it has never been opened.
+    pub fn get_last_opened_timestamp(&self) -> Option<i64> {
+        self.get_last_opened().map(|t| t.timestamp_millis())
+    }
+
+    pub fn get_last_modified(&self) -> Option<DateTime<Utc>> {
+        self.last_modified.get_date()
+    }
+
+    /// None if it has never been opened.
+    pub fn get_last_opened(&self) -> Option<DateTime<Utc>> {
+        self.last_opened.as_ref().and_then(Timestamp::get_date)
+    }
+
+    /// None for documents made by firmware which didn't record it.
+    pub fn get_created(&self) -> Option<DateTime<Utc>> {
+        self.created_time.as_ref().and_then(Timestamp::get_date)
+    }
+
+    /// Whether it is starred, i.e. in the favourites.
+    pub fn is_pinned(&self) -> bool {
+        self.pinned.unwrap_or(false)
+    }
+
+    pub fn set_pinned(&mut self, pinned: bool) {
+        self.pinned = Some(pinned);
+    }
 }
 
 impl Directory {
@@ -230,10 +447,14 @@ impl Directory {
         Directory {
             hash,
             metadata,
-            files: files.unwrap_or(Vec::new()),
-            directories: directories.unwrap_or(Vec::new()),
+            files: files.unwrap_or_default(),
+            directories: directories.unwrap_or_default(),
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty() && self.directories.is_empty()
+    }
 }
 
 impl SystemDirectory {
@@ -244,8 +465,69 @@ impl SystemDirectory {
     ) -> SystemDirectory {
         SystemDirectory {
             name,
-            files: files.unwrap_or(Vec::new()),
-            directories: directories.unwrap_or(Vec::new()),
+            files: files.unwrap_or_default(),
+            directories: directories.unwrap_or_default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn metadata(value: serde_json::Value) -> Metadata {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn numeric_timestamps_are_read() {
+        let metadata = metadata(json!({
+            "visibleName": "Notes",
+            "type": "DocumentType",
+            "lastModified": 1700000000000_i64,
+            "lastOpened": 1700000100000_i64,
+            "createdTime": "1600000000000",
+        }));
+        assert_eq!(metadata.get_last_modified_timestamp(), Some(1700000000000));
+        assert_eq!(metadata.get_last_opened_timestamp(), Some(1700000100000));
+        assert_eq!(
+            metadata.get_created().map(|d| d.timestamp_millis()),
+            Some(1600000000000)
+        );
+    }
+
+    #[test]
+    fn timestamps_are_written_in_the_form_they_were_read() {
+        let value = json!({
+            "visibleName": "Notes",
+            "type": "DocumentType",
+            "lastModified": "1700000000000",
+            "lastOpened": 0,
+            "createdTime": 1600000000000_i64,
+        });
+        let read = metadata(value.clone());
+        assert_eq!(serde_json::to_value(&read).unwrap(), value);
+        assert!(read.get_last_opened().is_none());
+    }
+
+    #[test]
+    fn changes_keep_the_form_of_the_modification_time() {
+        let mut numeric = metadata(json!({
+            "visibleName": "Notes",
+            "type": "DocumentType",
+            "lastModified": 1700000000000_i64,
+        }));
+        numeric.mark_changed();
+        let written = serde_json::to_value(&numeric).unwrap();
+        assert!(written["lastModified"].as_i64().unwrap() > 1700000000000);
+        assert_eq!(written["version"], 1);
+        assert_eq!(written["metadatamodified"], true);
+
+        let mut string = Metadata::new("Notes".into(), None, "0".into(), "DocumentType".into());
+        string.mark_changed();
+        let written = serde_json::to_value(&string).unwrap();
+        assert!(written["lastModified"].is_string());
+    }
+}
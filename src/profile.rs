@@ -0,0 +1,381 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::Value;
+use ssh2::Session;
+
+use crate::constants::{DIR, UPDATE_CONF};
+use crate::nodes::Hash;
+use crate::ssh_utils::run_remote_command;
+
+/// A firmware release, e.g. 3.5.2.1807. Missing parts compare as 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FirmwareVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+    pub build: u32,
+}
+
+impl FirmwareVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32, build: u32) -> FirmwareVersion {
+        FirmwareVersion {
+            major,
+            minor,
+            patch,
+            build,
+        }
+    }
+}
+
+impl FromStr for FirmwareVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<u32> = s
+            .trim()
+            .split('.')
+            .map(|part| part.parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("invalid firmware version: {}", s))?;
+        if parts.is_empty() || parts.len() > 4 {
+            return Err(format!("invalid firmware version: {}", s));
+        }
+        let part = |i: usize| parts.get(i).copied().unwrap_or_default();
+        Ok(FirmwareVersion::new(part(0), part(1), part(2), part(3)))
+    }
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}.{}",
+            self.major, self.minor, self.patch, self.build
+        )
+    }
+}
+
+impl Serialize for FirmwareVersion {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// The version of the `.rm` files pages are drawn in. remarko doesn't read
+/// or write pages, but a device with v6 pages uses `cPages`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinesFormat {
+    V3,
+    V5,
+    V6,
+}
+
+impl LinesFormat {
+    /// Read the version from the start of an `.rm` file, e.g.
+    /// `reMarkable .lines file, version=6`.
+    pub fn from_header(header: &[u8]) -> Option<LinesFormat> {
+        let header = String::from_utf8_lossy(header);
+        let version = header
+            .strip_prefix("reMarkable .lines file, version=")?
+            .split_whitespace()
+            .next()?;
+        match version {
+            "3" => Some(LinesFormat::V3),
+            "5" => Some(LinesFormat::V5),
+            "6" => Some(LinesFormat::V6),
+            _ => None,
+        }
+    }
+}
+
+/// How a `.content` file lists the pages of a document.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ContentLayout {
+    /// A `pages` array of page ids
+    Pages,
+    /// A `cPages` object, with `formatVersion` 2
+    CPages,
+}
+
+impl ContentLayout {
+    pub fn from_content(content: &Value) -> Option<ContentLayout> {
+        if content["cPages"].is_object() {
+            Some(ContentLayout::CPages)
+        } else if content["pages"].is_array() {
+            Some(ContentLayout::Pages)
+        } else {
+            None
+        }
+    }
+}
+
+// firmware releases which changed the formats remarko writes
+const C_PAGES: FirmwareVersion = FirmwareVersion::new(3, 0, 0, 0);
+const CREATED_TIME: FirmwareVersion = FirmwareVersion::new(3, 0, 0, 0);
+
+/// The `.content` and `.metadata` formats a particular remarkable uses, so
+/// files remarko writes are ones that tablet understands.
+#[derive(Clone, Debug, Serialize)]
+pub struct DeviceProfile {
+    pub firmware: Option<FirmwareVersion>,
+    pub content_layout: ContentLayout,
+    /// Whether metadata has `createdTime` and `lastOpened`
+    pub created_time: bool,
+}
+
+impl DeviceProfile {
+    /// The profile of a known firmware release.
+    pub fn for_firmware(firmware: FirmwareVersion) -> DeviceProfile {
+        DeviceProfile {
+            firmware: Some(firmware),
+            content_layout: match firmware >= C_PAGES {
+                true => ContentLayout::CPages,
+                false => ContentLayout::Pages,
+            },
+            created_time: firmware >= CREATED_TIME,
+        }
+    }
+
+    /// Work out the profile of a remarkable from its firmware version, or if
+    /// that can't be read, from the files already on it. A device with no
+    /// pages or documents to go on is assumed to be up to date.
+    pub fn detect(sess: &mut Session) -> DeviceProfile {
+        if let Some(firmware) = read_firmware_version(sess).and_then(|v| v.parse().ok()) {
+            return DeviceProfile::for_firmware(firmware);
+        }
+
+        let header = run_remote_command(
+            sess,
+            &format!("for f in {}/*/*.rm; do head -c 43 \"$f\"; break; done", DIR),
+        );
+        let lines_format = LinesFormat::from_header(header.as_bytes()).unwrap_or(LinesFormat::V6);
+        let content = run_remote_command(
+            sess,
+            &format!("for f in {}/*.content; do cat \"$f\"; break; done", DIR),
+        );
+        let content_layout = serde_json::from_str(&content)
+            .ok()
+            .and_then(|content| ContentLayout::from_content(&content))
+            .unwrap_or(match lines_format {
+                LinesFormat::V6 => ContentLayout::CPages,
+                _ => ContentLayout::Pages,
+            });
+        DeviceProfile {
+            firmware: None,
+            content_layout,
+            created_time: content_layout == ContentLayout::CPages,
+        }
+    }
+
+    /// The metadata xochitl expects for a newly created document or collection.
+    pub fn new_metadata(&self, name: &str, parent: &Hash, type_: &str) -> Value {
+        let now = Utc::now().timestamp_millis().to_string();
+        let mut metadata = serde_json::json!({
+            "deleted": false,
+            "lastModified": now,
+            "metadatamodified": false,
+            "modified": false,
+            "parent": parent.to_string(),
+            "pinned": false,
+            "synced": false,
+            "type": type_,
+            "version": 0,
+            "visibleName": name,
+        });
+        if self.created_time {
+            metadata["createdTime"] = now.into();
+            metadata["lastOpened"] = "0".into();
+        }
+        metadata
+    }
+
+    /// The `.content` of a new document with the given payload extension, or
+    /// "notebook". Pages are left for xochitl to fill in.
+    pub fn new_content(&self, file_type: &str) -> Value {
+        match self.content_layout {
+            ContentLayout::Pages => serde_json::json!({ "fileType": file_type }),
+            ContentLayout::CPages => serde_json::json!({
+                "fileType": file_type,
+                "formatVersion": 2,
+            }),
+        }
+    }
+
+    /// The `.content` of a document whose pages are already on the remarkable,
+    /// given as the id and template of each page in order.
+    pub fn content_with_pages(&self, file_type: &str, pages: &[(&str, &str)]) -> Value {
+        let mut content = self.new_content(file_type);
+        content["pageCount"] = pages.len().into();
+        match self.content_layout {
+            ContentLayout::Pages => {
+                content["pages"] = pages.iter().map(|(id, _)| *id).collect();
+            }
+            ContentLayout::CPages => {
+                let pages: Vec<Value> = pages
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (id, template))| {
+                        serde_json::json!({
+                            "id": id,
+                            "idx": { "timestamp": "1:2", "value": page_index(i) },
+                            "template": { "timestamp": "1:1", "value": template },
+                        })
+                    })
+                    .collect();
+                content["cPages"] = serde_json::json!({ "pages": pages });
+            }
+        }
+        content
+    }
+}
+
+/// The position of a page in `cPages`, which xochitl sorts by as a string:
+/// "ba", "bb" ... "bz", "cba", "cbb" and so on.
+fn page_index(i: usize) -> String {
+    let mut digits = Vec::new();
+    let mut rest = i;
+    loop {
+        digits.push((b'a' + (rest % 26) as u8) as char);
+        rest /= 26;
+        if rest == 0 {
+            break;
+        }
+    }
+    // a longer index starts with a later letter, so it sorts after all
+    // shorter ones
+    let prefix = (b'a' + digits.len() as u8) as char;
+    std::iter::once(prefix)
+        .chain(digits.into_iter().rev())
+        .collect()
+}
+
+/// Read the release version from `update.conf`, e.g. "3.5.2.1807".
+pub fn read_firmware_version(sess: &mut Session) -> Option<String> {
+    run_remote_command(sess, &format!("cat {} 2>/dev/null", UPDATE_CONF))
+        .lines()
+        .find_map(|line| line.strip_prefix("REMARKABLE_RELEASE_VERSION="))
+        .map(|version| version.trim().to_string())
+}
+
+/// The ids of the pages of a document, from either content layout.
+pub fn get_page_ids(content: &Value) -> Option<Vec<&str>> {
+    match ContentLayout::from_content(content)? {
+        ContentLayout::Pages => Some(
+            content["pages"]
+                .as_array()?
+                .iter()
+                .filter_map(|p| p.as_str())
+                .collect(),
+        ),
+        ContentLayout::CPages => Some(
+            content["cPages"]["pages"]
+                .as_array()?
+                .iter()
+                .filter_map(|p| p["id"].as_str())
+                .collect(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn version(s: &str) -> FirmwareVersion {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn firmware_versions_are_parsed() {
+        assert_eq!(version("3.5.2.1807"), FirmwareVersion::new(3, 5, 2, 1807));
+        assert_eq!(version("2.15"), FirmwareVersion::new(2, 15, 0, 0));
+        assert_eq!(version(" 3\n"), FirmwareVersion::new(3, 0, 0, 0));
+        assert_eq!(version("3.5.2.1807").to_string(), "3.5.2.1807");
+        for invalid in ["", "3.", "3.x", "v3.5", "-1", "1.2.3.4.5"] {
+            assert!(invalid.parse::<FirmwareVersion>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn firmware_versions_compare_numerically() {
+        assert!(version("2.15") > version("2.9"));
+        assert!(version("3.0.0.1") > version("3"));
+        assert_eq!(version("3.0"), version("3.0.0.0"));
+    }
+
+    #[test]
+    fn profiles_change_at_each_boundary() {
+        let formats = |v: &str| {
+            let profile = DeviceProfile::for_firmware(version(v));
+            (profile.content_layout, profile.created_time)
+        };
+        let v2 = (ContentLayout::Pages, false);
+        let v3 = (ContentLayout::CPages, true);
+
+        assert_eq!(formats("1.7.2.3"), v2);
+        assert_eq!(formats("2.15"), v2);
+        assert_eq!(formats("2.99.99.99"), v2);
+        assert_eq!(formats("3.0"), v3);
+        assert_eq!(formats("3.5.2.1807"), v3);
+    }
+
+    #[test]
+    fn lines_formats_are_read_from_the_header() {
+        let header = b"reMarkable .lines file, version=6          ";
+        assert_eq!(LinesFormat::from_header(header), Some(LinesFormat::V6));
+        let header = b"reMarkable .lines file, version=3          ";
+        assert_eq!(LinesFormat::from_header(header), Some(LinesFormat::V3));
+        assert_eq!(LinesFormat::from_header(b"%PDF-1.7"), None);
+    }
+
+    #[test]
+    fn content_layouts_are_read_from_the_content() {
+        let pages = json!({"pages": ["p1"]});
+        let c_pages = json!({"cPages": {"pages": [{"id": "p1"}, {"id": "p2"}]}});
+        assert_eq!(
+            ContentLayout::from_content(&pages),
+            Some(ContentLayout::Pages)
+        );
+        assert_eq!(
+            ContentLayout::from_content(&c_pages),
+            Some(ContentLayout::CPages)
+        );
+        assert_eq!(ContentLayout::from_content(&json!({})), None);
+        assert_eq!(get_page_ids(&c_pages), Some(vec!["p1", "p2"]));
+        assert_eq!(get_page_ids(&pages), Some(vec!["p1"]));
+    }
+
+    #[test]
+    fn contents_list_existing_pages_in_either_layout() {
+        let pages = [("p1", "Blank"), ("p2", "Lined")];
+        let old =
+            DeviceProfile::for_firmware(version("2.15")).content_with_pages("notebook", &pages);
+        assert_eq!(
+            old,
+            json!({"fileType": "notebook", "pageCount": 2, "pages": ["p1", "p2"]})
+        );
+
+        let new =
+            DeviceProfile::for_firmware(version("3.5")).content_with_pages("notebook", &pages);
+        assert_eq!(get_page_ids(&new), Some(vec!["p1", "p2"]));
+        assert_eq!(new["formatVersion"], 2);
+        assert_eq!(new["cPages"]["pages"][1]["template"]["value"], "Lined");
+        assert_eq!(new["cPages"]["pages"][0]["idx"]["value"], "ba");
+    }
+
+    #[test]
+    fn page_indexes_sort_in_page_order() {
+        let indexes: Vec<String> = (0..1000).map(page_index).collect();
+        assert_eq!(indexes[25], "bz");
+        assert_eq!(indexes[26], "cba");
+        let mut sorted = indexes.clone();
+        sorted.sort();
+        assert_eq!(sorted, indexes);
+    }
+}
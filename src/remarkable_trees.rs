@@ -1,9 +1,17 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+
 use colored::*;
+use serde::Serialize;
 use ssh2::Session;
 
-use crate::constants::{COLLECTION, DIR, DOCUMENT};
+use crate::constants::{COLLECTION, DIR, DOCUMENT, TRASH};
+use crate::dates::DateFormat;
+use crate::file_names::names_match;
+use crate::file_types::{get_file_types_from_ls_output, FileType};
 use crate::nodes::{Directory, DirectoryNode, File, Hash, Metadata, Node, SystemDirectory};
-use crate::ssh_utils::read_remote_metadata;
+use crate::ssh_utils::{read_remote_metadata, run_remote_command};
 
 /// Parse the output of `ls` to get the file and directory hashes.
 /// The output of `ls` is a single string, so it needs to be trimmed
@@ -19,27 +27,159 @@ pub fn get_hashes_from_ls_output(ls_output: &str) -> Vec<&str> {
 
     file_hashes.dedup();
 
-    return file_hashes;
+    file_hashes
+}
+
+/// The hash of the document or collection a file belongs to, from the first
+/// component of its path relative to the documents directory, e.g. the hash
+/// of "<hash>.thumbnails/<page>.png".
+pub fn get_hash_from_path(relative_path: &Path) -> Option<String> {
+    let first = relative_path
+        .components()
+        .next()?
+        .as_os_str()
+        .to_string_lossy();
+    let hash = first.split('.').next()?;
+    (!hash.is_empty()).then(|| hash.to_string())
+}
+
+/// Something wrong with a document or collection found while building the
+/// remote trees. What can still be placed is, and the rest is left out.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TreeWarning {
+    /// Files with no `.metadata`, e.g. left behind by a document which was
+    /// removed; these are left out
+    MissingMetadata { hash: String },
+    /// A `.metadata` which can't be read, e.g. truncated by a crash; the
+    /// document or collection is left out
+    InvalidMetadata { hash: String, error: String },
+    /// A type other than a document or collection; these are left out
+    UnknownType { hash: String, type_: String },
+    /// A parent which isn't a collection on the device; these are put in the
+    /// root instead
+    MissingParent { hash: String, parent: String },
+    /// A collection which is, through its parents, inside itself; it is left
+    /// out along with everything in it
+    ParentCycle { hash: String },
 }
 
-pub fn build_tree(hashes: Vec<&str>, sess: &mut Session) -> (Directory, Directory) {
-    let mut directories = Vec::new();
-    let mut files = Vec::new();
+impl fmt::Display for TreeWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TreeWarning::MissingMetadata { hash } => {
+                write!(f, "{} has no .metadata, skipping it (see gc)", hash)
+            }
+            TreeWarning::InvalidMetadata { hash, error } => {
+                write!(
+                    f,
+                    "{}.metadata can't be read ({}), skipping it",
+                    hash, error
+                )
+            }
+            TreeWarning::UnknownType { hash, type_ } => {
+                write!(f, "{} has unknown type {}, skipping it", hash, type_)
+            }
+            TreeWarning::MissingParent { hash, parent } => write!(
+                f,
+                "{} is in {}, which doesn't exist, showing it in the root",
+                hash, parent
+            ),
+            TreeWarning::ParentCycle { hash } => {
+                write!(f, "{} is inside itself, skipping it", hash)
+            }
+        }
+    }
+}
+
+/// The home and trash trees of a remarkable, and any problems found while
+/// building them.
+#[derive(Clone, Debug)]
+pub struct RemoteTrees {
+    pub root: Directory,
+    pub trash: Directory,
+    pub warnings: Vec<TreeWarning>,
+}
 
-    // create nodes for all files and directories
+pub fn build_tree(
+    hashes: Vec<&str>,
+    file_types: &HashMap<String, FileType>,
+    sess: &mut Session,
+) -> RemoteTrees {
+    let mut nodes = Vec::new();
+    let mut warnings = Vec::new();
     for hash in hashes {
         let metadata_file_name = format!("{}/{}.metadata", DIR, hash);
-        let metadata = read_remote_metadata(sess, &metadata_file_name);
+        match read_remote_metadata(sess, &metadata_file_name) {
+            Ok(Some(metadata)) => nodes.push((hash.to_string(), metadata)),
+            Ok(None) => warnings.push(TreeWarning::MissingMetadata {
+                hash: hash.to_string(),
+            }),
+            Err(e) => warnings.push(TreeWarning::InvalidMetadata {
+                hash: hash.to_string(),
+                error: e.to_string(),
+            }),
+        }
+    }
+    let mut trees = build_tree_from_metadata(nodes, file_types);
+    warnings.append(&mut trees.warnings);
+    trees.warnings = warnings;
+    trees
+}
+
+/// Build the home and trash trees from metadata which has already been read,
+/// e.g. from a backup.
+pub fn build_tree_from_metadata(
+    nodes: Vec<(String, Metadata)>,
+    file_types: &HashMap<String, FileType>,
+) -> RemoteTrees {
+    let mut warnings = Vec::new();
+    let collection_hashes: HashSet<String> = nodes
+        .iter()
+        .filter(|(_, metadata)| metadata.type_ == COLLECTION)
+        .map(|(hash, _)| hash.clone())
+        .collect();
+
+    // the children of each collection, by hash, in the order they were read
+    let mut files: HashMap<String, Vec<File>> = HashMap::new();
+    let mut collections: HashMap<String, Vec<(String, Metadata)>> = HashMap::new();
+    for (hash, metadata) in nodes {
+        if metadata.type_ != DOCUMENT && metadata.type_ != COLLECTION {
+            warnings.push(TreeWarning::UnknownType {
+                hash,
+                type_: metadata.type_.clone(),
+            });
+            continue;
+        }
+
+        // deleted documents and collections are quietly kept in the root if
+        // their parent has already gone
+        let parent = metadata.get_parent().cloned().unwrap_or_default();
+        let parent = match parent.as_str() {
+            "" | TRASH => parent,
+            _ if collection_hashes.contains(&parent) => parent,
+            _ if metadata.is_deleted() => String::new(),
+            _ => {
+                warnings.push(TreeWarning::MissingParent {
+                    hash: hash.clone(),
+                    parent,
+                });
+                String::new()
+            }
+        };
 
         if metadata.type_ == DOCUMENT {
-            let file = File::new(Hash::new(hash.to_string()), metadata);
-            files.push(file);
-        } else if metadata.type_ == COLLECTION {
-            let directory = Directory::new(Hash::new(hash.to_string()), metadata, None, None);
-            directories.push(directory);
+            let mut file = File::new(Hash::new(hash.clone()), metadata);
+            if let Some(file_type) = file_types.get(&hash) {
+                file.set_file_type(*file_type);
+            }
+            files.entry(parent).or_default().push(file);
         } else {
-            println!("Unknown type: {}", metadata.type_);
-        };
+            collections
+                .entry(parent)
+                .or_default()
+                .push((hash, metadata));
+        }
     }
 
     // manually create root and trash directories
@@ -55,46 +195,187 @@ pub fn build_tree(hashes: Vec<&str>, sess: &mut Session) -> (Directory, Director
         "420".to_string(),
         "directory".to_string(),
     );
+    let root = assemble_directory(String::new(), root_metadata, &mut files, &mut collections);
+    let trash = assemble_directory(
+        TRASH.to_string(),
+        trash_metadata,
+        &mut files,
+        &mut collections,
+    );
+
+    // whatever wasn't reached from the root or trash is in a cycle
+    let mut unreached: Vec<String> = collections
+        .into_values()
+        .flatten()
+        .map(|(hash, _)| hash)
+        .collect();
+    unreached.sort();
+    warnings.extend(
+        unreached
+            .into_iter()
+            .map(|hash| TreeWarning::ParentCycle { hash }),
+    );
+
+    RemoteTrees {
+        root,
+        trash,
+        warnings,
+    }
+}
+
+/// Build a directory with everything below it, taking its children out of
+/// `files` and `collections` as they are placed.
+fn assemble_directory(
+    hash: String,
+    metadata: Metadata,
+    files: &mut HashMap<String, Vec<File>>,
+    collections: &mut HashMap<String, Vec<(String, Metadata)>>,
+) -> Directory {
+    let directory_files = files.remove(&hash).unwrap_or_default();
+    let directories = collections
+        .remove(&hash)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(hash, metadata)| assemble_directory(hash, metadata, files, collections))
+        .collect();
+    Directory::new(
+        Hash::new(hash),
+        metadata,
+        Some(directory_files),
+        Some(directories),
+    )
+}
 
-    let root_directory = Directory::new(Hash::new("".to_string()), root_metadata, None, None);
-    let trash_directory =
-        Directory::new(Hash::new("trash".to_string()), trash_metadata, None, None);
-
-    // add root_directory at start of directories
-    directories.insert(0, root_directory);
-    // add trash_directory after root_directory
-    directories.insert(1, trash_directory);
-
-    // match files with parent directories
-    for file in files {
-        let parent_name = file.get_parent().unwrap();
-        // TODO improve this
-        let parent = directories
-            .iter_mut()
-            .find(|d| d.get_hash().to_string() == parent_name.to_string())
-            .unwrap();
-        parent.add_file(file);
-    }
-
-    // match directories with parent directories
-    for directory in directories.clone() {
-        // if parent is None, continue, else find parent and add directory
-        let parent_name = directory.get_parent();
-        if parent_name.is_none() {
+/// List the files in the remarkable's directory and build the home and trash
+/// trees, leaving out documents and collections which have been deleted.
+pub fn get_remote_trees(sess: &mut Session) -> RemoteTrees {
+    let trees = get_remote_trees_with_deleted(sess);
+    RemoteTrees {
+        root: split_deleted(&trees.root).0,
+        trash: split_deleted(&trees.trash).0,
+        warnings: trees.warnings,
+    }
+}
+
+/// Build the home and trash trees including documents and collections
+/// which have been deleted, i.e. have `"deleted": true`, but whose files
+/// haven't been removed yet.
+pub fn get_remote_trees_with_deleted(sess: &mut Session) -> RemoteTrees {
+    let output = run_remote_command(sess, format!("ls {}", DIR).as_str());
+    let hashes = get_hashes_from_ls_output(&output);
+    let file_types = get_file_types_from_ls_output(&output);
+    build_tree(hashes, &file_types, sess)
+}
+
+/// Split a tree into what is live and what has been deleted. Both keep the
+/// collections around what is in them, and everything in a deleted
+/// collection counts as deleted.
+pub fn split_deleted(directory: &Directory) -> (Directory, Directory) {
+    let (mut live_files, mut deleted_files) = (Vec::new(), Vec::new());
+    for file in directory.get_files() {
+        match file.get_metadata().is_deleted() {
+            true => deleted_files.push(file.clone()),
+            false => live_files.push(file.clone()),
+        }
+    }
+    let (mut live_directories, mut deleted_directories) = (Vec::new(), Vec::new());
+    for sub_directory in directory.get_directories() {
+        if sub_directory.get_metadata().is_deleted() {
+            deleted_directories.push(sub_directory.clone());
             continue;
         }
-        // TODO improve this
-        let parent = directories
-            .iter_mut()
-            .find(|d| d.get_hash().to_string() == parent_name.unwrap().to_string())
-            .unwrap();
-        parent.add_directory(directory.clone());
+        let (live, deleted) = split_deleted(sub_directory);
+        live_directories.push(live);
+        if !deleted.is_empty() {
+            deleted_directories.push(deleted);
+        }
     }
 
-    return (directories.remove(0), directories.remove(0));
+    let rebuild = |files, directories| {
+        Directory::new(
+            directory.get_hash().clone(),
+            directory.get_metadata().clone(),
+            Some(files),
+            Some(directories),
+        )
+    };
+    (
+        rebuild(live_files, live_directories),
+        rebuild(deleted_files, deleted_directories),
+    )
 }
 
-pub fn print_tree(node: &dyn Node, depth: usize) {
+/// Find a directory from its path relative to `root`, e.g. "Papers/2023".
+/// An empty path returns `root` itself.
+pub fn find_directory(root: &Directory, path: &str) -> Option<Directory> {
+    find_directory_ref(root, path).cloned()
+}
+
+fn find_directory_ref<'a>(root: &'a Directory, path: &str) -> Option<&'a Directory> {
+    let mut directory = root;
+    for name in path.split('/').filter(|n| !n.is_empty()) {
+        directory = directory
+            .get_directories()
+            .iter()
+            .find(|d| names_match(d.get_visible_name(), name, false))?;
+    }
+    Some(directory)
+}
+
+/// Find a document or collection from its path relative to `root`, e.g.
+/// "Papers/2023/Attention". Collections are preferred if a document has the
+/// same name. An empty path returns `root` itself.
+pub fn find_node<'a>(root: &'a Directory, path: &str) -> Option<&'a dyn Node> {
+    let (parent_path, name) = split_path(path);
+    if name.is_empty() {
+        return Some(root);
+    }
+    let parent = find_directory_ref(root, parent_path)?;
+    if let Some(directory) = parent
+        .get_directories()
+        .iter()
+        .find(|d| names_match(d.get_visible_name(), name, false))
+    {
+        return Some(directory);
+    }
+    parent
+        .get_files()
+        .iter()
+        .find(|f| names_match(f.get_visible_name(), name, false))
+        .map(|f| f as &dyn Node)
+}
+
+/// Split a remote path into the path of its parent and its name,
+/// e.g. "Papers/2023" into ("Papers", "2023").
+pub fn split_path(path: &str) -> (&str, &str) {
+    let path = path.trim_matches('/');
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+/// Find the path of a collection relative to `root` from its hash, e.g.
+/// "Papers/2023". The path of `root` itself is empty.
+pub fn find_path(root: &Directory, hash: &str) -> Option<String> {
+    if root.get_hash().to_string() == hash {
+        return Some(String::new());
+    }
+    root.get_directories().iter().find_map(|directory| {
+        find_path(directory, hash).map(|path| match path.is_empty() {
+            true => directory.get_visible_name().to_string(),
+            false => format!("{}/{}", directory.get_visible_name(), path),
+        })
+    })
+}
+
+/// Add the hashes of everything inside a collection.
+pub fn collect_hashes(directory: &Directory, hashes: &mut Vec<Hash>) {
+    hashes.extend(directory.get_files().iter().map(|f| f.get_hash().clone()));
+    for sub_directory in directory.get_directories() {
+        hashes.push(sub_directory.get_hash().clone());
+        collect_hashes(sub_directory, hashes);
+    }
+}
+
+pub fn print_tree(node: &dyn Node, depth: usize, dates: &DateFormat) {
     // Print the current node with indentation based on the depth
     let indent = " ".repeat(depth * 2);
 
@@ -107,31 +388,142 @@ pub fn print_tree(node: &dyn Node, depth: usize) {
             "/".bold().green()
         );
         for directory in directory_node.get_directories() {
-            print_tree(directory, depth + 1);
+            print_tree(directory, depth + 1, dates);
         }
         for file in directory_node.get_files() {
-            print_tree(file, depth + 1);
+            print_tree(file, depth + 1, dates);
         }
     } else if let Some(directory_node) = node.as_any().downcast_ref::<Directory>() {
         println!(
-            "{}{}{}",
+            "{}{}{}{}",
             indent,
             directory_node.get_visible_name().bold().green(),
-            "/".bold().green()
+            "/".bold().green(),
+            deleted_marker(directory_node)
         );
         for directory in directory_node.get_directories() {
-            print_tree(directory, depth + 1);
+            print_tree(directory, depth + 1, dates);
         }
         for file in directory_node.get_files() {
-            print_tree(file, depth + 1);
+            print_tree(file, depth + 1, dates);
         }
     } else if let Some(file_node) = node.as_any().downcast_ref::<File>() {
         println!(
-            "{}{} {} {}",
+            "{}{} {} {}{}",
             indent,
-            file_node.get_last_modified(),
+            dates.format_or(file_node.get_last_modified().as_ref(), "unknown"),
             file_node.get_hash().to_string().bold().purple().on_blue(),
-            file_node.get_visible_name().purple()
+            file_node.get_visible_name().purple(),
+            deleted_marker(file_node)
+        );
+    }
+}
+
+fn deleted_marker(node: &dyn Node) -> ColoredString {
+    match node.get_metadata().is_deleted() {
+        true => " (deleted)".italic().red(),
+        false => "".normal(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{deleted_directory, deleted_file, directory, file, file_names};
+
+    fn node(hash: &str, name: &str, parent: &str, type_: &str) -> (String, Metadata) {
+        let metadata = Metadata::new(name.into(), Some(parent.into()), "0".into(), type_.into());
+        (hash.to_string(), metadata)
+    }
+
+    #[test]
+    fn malformed_metadata_is_reported_and_what_can_be_placed_is() {
+        let mut deleted = node("g", "Deleted", "gone", DOCUMENT);
+        deleted.1.set_deleted(true);
+        let nodes = vec![
+            node("a", "Work", "", COLLECTION),
+            node("b", "Paper", "a", DOCUMENT),
+            node("c", "Lost", "missing", DOCUMENT),
+            node("d", "Weird", "", "TemplateType"),
+            node("e", "Loop", "f", COLLECTION),
+            node("f", "Back", "e", COLLECTION),
+            node("h", "Inside loop", "e", DOCUMENT),
+            node("i", "Binned", TRASH, DOCUMENT),
+            node("j", "Not a collection", "b", DOCUMENT),
+            deleted,
+        ];
+        let trees = build_tree_from_metadata(nodes, &HashMap::new());
+
+        assert_eq!(
+            trees.warnings,
+            vec![
+                TreeWarning::MissingParent {
+                    hash: "c".into(),
+                    parent: "missing".into(),
+                },
+                TreeWarning::UnknownType {
+                    hash: "d".into(),
+                    type_: "TemplateType".into(),
+                },
+                TreeWarning::MissingParent {
+                    hash: "j".into(),
+                    parent: "b".into(),
+                },
+                TreeWarning::ParentCycle { hash: "e".into() },
+                TreeWarning::ParentCycle { hash: "f".into() },
+            ]
+        );
+        assert_eq!(
+            file_names(&trees.root),
+            vec!["Lost", "Not a collection", "Deleted"]
+        );
+        assert_eq!(trees.root.get_directories().len(), 1);
+        assert_eq!(file_names(&trees.root.get_directories()[0]), vec!["Paper"]);
+        assert_eq!(file_names(&trees.trash), vec!["Binned"]);
+    }
+
+    #[test]
+    fn deleted_documents_and_collections_are_split_off() {
+        let gone = deleted_file("a", "Gone", Some(FileType::Pdf));
+        let old = deleted_directory(
+            "b",
+            "Old",
+            vec![file("c", "Inside", Some(FileType::Pdf))],
+            Vec::new(),
+        );
+        let draft = deleted_file("e", "Draft", Some(FileType::Notebook));
+        let tree = directory(
+            "",
+            "",
+            vec![gone, file("f", "Kept", Some(FileType::Pdf))],
+            vec![
+                old,
+                directory(
+                    "d",
+                    "Work",
+                    vec![draft, file("g", "Report", Some(FileType::Pdf))],
+                    Vec::new(),
+                ),
+            ],
+        );
+
+        let (live, deleted) = split_deleted(&tree);
+        assert_eq!(file_names(&live), vec!["Kept"]);
+        assert_eq!(live.get_directories().len(), 1);
+        assert_eq!(file_names(&live.get_directories()[0]), vec!["Report"]);
+
+        assert_eq!(file_names(&deleted), vec!["Gone"]);
+        let deleted_directories: Vec<(String, Vec<String>)> = deleted
+            .get_directories()
+            .iter()
+            .map(|d| (d.get_visible_name().to_string(), file_names(d)))
+            .collect();
+        assert_eq!(
+            deleted_directories,
+            vec![
+                ("Old".to_string(), vec!["Inside".to_string()]),
+                ("Work".to_string(), vec!["Draft".to_string()]),
+            ]
         );
     }
 }
@@ -0,0 +1,560 @@
+use std::collections::HashMap;
+
+use colored::*;
+use ssh2::Session;
+
+use crate::file_names::names_match;
+use crate::nodes::{Directory, DirectoryNode, Hash, Node};
+use crate::profile::DeviceProfile;
+use crate::remarkable_trees::{collect_hashes, find_node, split_path};
+use crate::ssh_utils::{create_remote_collection, update_remote_metadata};
+use crate::trash::trash_remote_nodes;
+
+/// Create a collection at a remote path, e.g. "Papers/2023".
+///
+/// Unless `parents` is set the parent has to exist and the collection must
+/// not. `created` holds the collections made earlier in the same run, which
+/// aren't in `root` yet. Returns the hash of the collection.
+pub fn make_remote_directory(
+    root: &Directory,
+    path: &str,
+    parents: bool,
+    created: &mut HashMap<String, Hash>,
+    profile: &DeviceProfile,
+    sess: &mut Session,
+) -> Result<Hash, Box<dyn std::error::Error>> {
+    let plan = plan_directory(root, path, parents, created)?;
+    let mut hash = plan.parent;
+    for (sub_path, name) in plan.missing {
+        let collection = create_remote_collection(&name, &hash, profile, sess)?;
+        println!(
+            "{} {}",
+            "Created:".bold().green(),
+            sub_path.italic().purple()
+        );
+        hash = collection.get_hash().clone();
+        created.insert(sub_path, hash.clone());
+    }
+
+    Ok(hash)
+}
+
+/// What `make_remote_directory` has to create.
+struct DirectoryPlan {
+    /// The deepest collection on the way which exists already
+    parent: Hash,
+    /// The path and name of each collection missing below it, outermost first
+    missing: Vec<(String, String)>,
+}
+
+/// Work out which collections on the way to `path` have to be created.
+fn plan_directory(
+    root: &Directory,
+    path: &str,
+    parents: bool,
+    created: &HashMap<String, Hash>,
+) -> Result<DirectoryPlan, Box<dyn std::error::Error>> {
+    let names: Vec<&str> = path.split('/').filter(|n| !n.is_empty()).collect();
+    if names.is_empty() {
+        return Err("no directory name given".into());
+    }
+
+    // `directory` is None once below a collection which isn't in `root`
+    let mut directory = Some(root);
+    let mut hash = root.get_hash().clone();
+    let mut missing = Vec::new();
+    let mut sub_path = String::new();
+    for (i, name) in names.iter().enumerate() {
+        let is_last = i == names.len() - 1;
+        if !sub_path.is_empty() {
+            sub_path.push('/');
+        }
+        sub_path.push_str(name);
+
+        let existing = match created.get(&sub_path) {
+            Some(created_hash) => Some((created_hash.clone(), None)),
+            None => directory
+                .and_then(|d| {
+                    d.get_directories()
+                        .iter()
+                        .find(|c| names_match(c.get_visible_name(), name, false))
+                })
+                .map(|d| (d.get_hash().clone(), Some(d))),
+        };
+        if let Some((existing_hash, existing_directory)) = existing {
+            if is_last && !parents {
+                return Err(format!("{} already exists", sub_path).into());
+            }
+            hash = existing_hash;
+            directory = existing_directory;
+            continue;
+        }
+
+        if let Some(d) = directory {
+            if d.get_files()
+                .iter()
+                .any(|f| names_match(f.get_visible_name(), name, false))
+            {
+                return Err(format!("{} is a document", sub_path).into());
+            }
+        }
+        if !is_last && !parents {
+            return Err(format!("{} does not exist", sub_path).into());
+        }
+
+        missing.push((sub_path.clone(), name.to_string()));
+        directory = None;
+    }
+
+    Ok(DirectoryPlan {
+        parent: hash,
+        missing,
+    })
+}
+
+/// Move documents and collections on the remote.
+///
+/// If `destination` is an existing collection the sources are moved into it.
+/// Otherwise there must be a single source, which is moved to the parent of
+/// `destination` and given its name.
+pub fn move_remote_nodes(
+    root: &Directory,
+    sources: &[String],
+    destination: &str,
+    sess: &mut Session,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let plan = plan_move(root, sources, destination)?;
+
+    for (source, node) in plan.nodes {
+        update_remote_metadata(sess, &node.get_hash().to_string(), |metadata| {
+            metadata.set_parent(plan.parent.to_string());
+            if let Some(name) = &plan.new_name {
+                metadata.set_visible_name(name.clone());
+            }
+        })?;
+        println!(
+            "{} {} {} {}",
+            "Moved:".bold().green(),
+            source.italic().purple(),
+            "->".bold(),
+            destination.italic().purple()
+        );
+    }
+
+    Ok(())
+}
+
+/// Where `move_remote_nodes` puts the sources.
+struct MovePlan<'a> {
+    /// The collection the sources are moved into
+    parent: Hash,
+    /// The name given to a single source moved to a path which doesn't exist
+    new_name: Option<String>,
+    nodes: Vec<(&'a String, &'a dyn Node)>,
+}
+
+/// Check every source of a move before anything is changed.
+fn plan_move<'a>(
+    root: &'a Directory,
+    sources: &'a [String],
+    destination: &str,
+) -> Result<MovePlan<'a>, Box<dyn std::error::Error>> {
+    let (parent, new_name) = match find_node(root, destination) {
+        Some(node) => match node.as_any().downcast_ref::<Directory>() {
+            Some(directory) => (directory, None),
+            None => return Err(format!("{} already exists", destination).into()),
+        },
+        None => {
+            if sources.len() > 1 {
+                return Err(format!("{} is not a collection", destination).into());
+            }
+            let (parent_path, name) = split_path(destination);
+            match find_node(root, parent_path).and_then(|n| n.as_any().downcast_ref::<Directory>())
+            {
+                Some(directory) => (directory, Some(name.to_string())),
+                None => return Err(format!("{} is not a collection", parent_path).into()),
+            }
+        }
+    };
+
+    let mut nodes: Vec<(&String, &dyn Node)> = Vec::new();
+    for source in sources {
+        let node = resolve_node(root, source)?;
+        if let Some(directory) = node.as_any().downcast_ref::<Directory>() {
+            if contains_collection(directory, parent.get_hash()) {
+                return Err(format!("cannot move {} into itself", source).into());
+            }
+        }
+        let name = new_name.as_deref().unwrap_or(node.get_visible_name());
+        check_name_is_free(parent, name, node.get_hash())?;
+        if let Some((other, _)) = nodes
+            .iter()
+            .find(|(_, other)| names_match(other.get_visible_name(), name, false))
+        {
+            return Err(format!("{} and {} have the same name", other, source).into());
+        }
+        nodes.push((source, node));
+    }
+
+    Ok(MovePlan {
+        parent: parent.get_hash().clone(),
+        new_name,
+        nodes,
+    })
+}
+
+/// Give a document or collection on the remote a new name, keeping it in
+/// the same collection.
+pub fn rename_remote_node(
+    root: &Directory,
+    path: &str,
+    new_name: &str,
+    sess: &mut Session,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let node = resolve_rename(root, path, new_name)?;
+    update_remote_metadata(sess, &node.get_hash().to_string(), |metadata| {
+        metadata.set_visible_name(new_name.to_string());
+    })?;
+    println!(
+        "{} {} {} {}",
+        "Renamed:".bold().green(),
+        path.italic().purple(),
+        "->".bold(),
+        new_name.italic().purple()
+    );
+    Ok(())
+}
+
+/// Find the node to rename, checking that its new name is valid and not
+/// taken by anything else in its collection.
+fn resolve_rename<'a>(
+    root: &'a Directory,
+    path: &str,
+    new_name: &str,
+) -> Result<&'a dyn Node, Box<dyn std::error::Error>> {
+    if new_name.is_empty() || new_name.contains('/') {
+        return Err(format!("{} is not a valid name", new_name).into());
+    }
+    let node = resolve_node(root, path)?;
+    let parent = find_node(root, split_path(path).0)
+        .and_then(|n| n.as_any().downcast_ref::<Directory>())
+        .expect("the parent of a node is a collection");
+    check_name_is_free(parent, new_name, node.get_hash())?;
+    Ok(node)
+}
+
+/// Add documents and collections on the remote to the favourites, or take
+/// them out of them.
+pub fn pin_remote_nodes(
+    root: &Directory,
+    paths: &[String],
+    pinned: bool,
+    sess: &mut Session,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut nodes = Vec::new();
+    for path in paths {
+        nodes.push((path, resolve_node(root, path)?));
+    }
+
+    for (path, node) in nodes {
+        if node.get_metadata().is_pinned() == pinned {
+            let state = match pinned {
+                true => "already pinned",
+                false => "not pinned",
+            };
+            println!(
+                "{} {} is {}",
+                "Skipping:".bold().yellow(),
+                path.italic().purple(),
+                state
+            );
+            continue;
+        }
+        update_remote_metadata(sess, &node.get_hash().to_string(), |metadata| {
+            metadata.set_pinned(pinned);
+        })?;
+        let done = match pinned {
+            true => "Pinned:",
+            false => "Unpinned:",
+        };
+        println!("{} {}", done.bold().green(), path.italic().purple());
+    }
+
+    Ok(())
+}
+
+/// Remove documents and collections on the remote. They are moved to the
+/// remarkable's trash, unless `permanent` is set in which case they and
+/// everything in them are marked as deleted.
+pub fn remove_remote_nodes(
+    root: &Directory,
+    paths: &[String],
+    permanent: bool,
+    sess: &mut Session,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut nodes = Vec::new();
+    for path in paths {
+        nodes.push((path, resolve_node(root, path)?));
+    }
+
+    if !permanent {
+        let hashes: Vec<Hash> = nodes.iter().map(|(_, n)| n.get_hash().clone()).collect();
+        trash_remote_nodes(&hashes, sess)?;
+        for (path, _) in nodes {
+            println!("{} {}", "Trashed:".bold().green(), path.italic().purple());
+        }
+        return Ok(());
+    }
+
+    for (path, node) in nodes {
+        for hash in hashes_to_delete(node) {
+            update_remote_metadata(sess, &hash.to_string(), |metadata| {
+                metadata.set_deleted(true);
+            })?;
+        }
+        println!("{} {}", "Deleted:".bold().green(), path.italic().purple());
+    }
+
+    Ok(())
+}
+
+/// Find a document or collection which can be changed, i.e. not the root.
+fn resolve_node<'a>(
+    root: &'a Directory,
+    path: &str,
+) -> Result<&'a dyn Node, Box<dyn std::error::Error>> {
+    if split_path(path).1.is_empty() {
+        return Err("cannot change the root collection".into());
+    }
+    find_node(root, path).ok_or_else(|| format!("{} not found on the remote", path).into())
+}
+
+/// The hashes to mark as deleted to remove a node permanently: its own and,
+/// for a collection, those of everything inside it.
+fn hashes_to_delete(node: &dyn Node) -> Vec<Hash> {
+    let mut hashes = vec![node.get_hash().clone()];
+    if let Some(directory) = node.as_any().downcast_ref::<Directory>() {
+        collect_hashes(directory, &mut hashes);
+    }
+    hashes
+}
+
+/// Fail if something other than the node `hash` in `parent` is called `name`,
+/// as the remarkable would show two entries with the same name.
+fn check_name_is_free(
+    parent: &Directory,
+    name: &str,
+    hash: &Hash,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let taken = parent
+        .get_directories()
+        .iter()
+        .map(|d| d as &dyn Node)
+        .chain(parent.get_files().iter().map(|f| f as &dyn Node))
+        .any(|n| {
+            names_match(n.get_visible_name(), name, false)
+                && n.get_hash().to_string() != hash.to_string()
+        });
+    if !taken {
+        return Ok(());
+    }
+    match parent.get_hash().to_string().is_empty() {
+        true => Err(format!("the root collection already contains {}", name).into()),
+        false => Err(format!("{} already contains {}", parent.get_visible_name(), name).into()),
+    }
+}
+
+/// Whether `hash` is `directory` or one of the collections inside it.
+fn contains_collection(directory: &Directory, hash: &Hash) -> bool {
+    directory.get_hash().to_string() == hash.to_string()
+        || directory
+            .get_directories()
+            .iter()
+            .any(|d| contains_collection(d, hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_types::FileType;
+    use crate::test_utils::{directory, file};
+
+    fn tree() -> Directory {
+        directory(
+            "",
+            "",
+            vec![file("a", "Notes", Some(FileType::Notebook))],
+            vec![
+                directory(
+                    "b",
+                    "Papers",
+                    vec![
+                        file("c", "Paper", Some(FileType::Pdf)),
+                        file("d", "Notes", Some(FileType::Pdf)),
+                    ],
+                    vec![directory("e", "2023", Vec::new(), Vec::new())],
+                ),
+                directory("f", "Books", Vec::new(), Vec::new()),
+            ],
+        )
+    }
+
+    fn error<T>(result: Result<T, Box<dyn std::error::Error>>) -> String {
+        match result {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    fn missing(plan: &DirectoryPlan) -> Vec<(&str, &str)> {
+        plan.missing
+            .iter()
+            .map(|(path, name)| (path.as_str(), name.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn parents_creates_every_missing_collection() {
+        let root = tree();
+        let plan = plan_directory(&root, "Papers/2023/Q1/Jan", true, &HashMap::new()).unwrap();
+        assert_eq!(plan.parent.to_string(), "e");
+        assert_eq!(
+            missing(&plan),
+            vec![("Papers/2023/Q1", "Q1"), ("Papers/2023/Q1/Jan", "Jan")]
+        );
+
+        let plan = plan_directory(&root, "Papers", true, &HashMap::new()).unwrap();
+        assert_eq!(plan.parent.to_string(), "b");
+        assert!(plan.missing.is_empty());
+    }
+
+    #[test]
+    fn without_parents_only_the_last_collection_is_created() {
+        let root = tree();
+        let plan = plan_directory(&root, "Papers/2024", false, &HashMap::new()).unwrap();
+        assert_eq!(plan.parent.to_string(), "b");
+        assert_eq!(missing(&plan), vec![("Papers/2024", "2024")]);
+
+        assert_eq!(
+            error(plan_directory(
+                &root,
+                "Papers/2024/Q1",
+                false,
+                &HashMap::new()
+            )),
+            "Papers/2024 does not exist"
+        );
+        assert_eq!(
+            error(plan_directory(&root, "Papers/2023", false, &HashMap::new())),
+            "Papers/2023 already exists"
+        );
+        assert_eq!(
+            error(plan_directory(&root, "Notes/Old", true, &HashMap::new())),
+            "Notes is a document"
+        );
+        assert_eq!(
+            error(plan_directory(&root, "/", true, &HashMap::new())),
+            "no directory name given"
+        );
+    }
+
+    #[test]
+    fn collections_created_earlier_in_the_run_are_reused() {
+        let root = tree();
+        let created = HashMap::from([("Books/New".to_string(), Hash::new("n".to_string()))]);
+        let plan = plan_directory(&root, "Books/New/Deep", false, &created).unwrap();
+        assert_eq!(plan.parent.to_string(), "n");
+        assert_eq!(missing(&plan), vec![("Books/New/Deep", "Deep")]);
+    }
+
+    #[test]
+    fn sources_are_moved_into_a_collection_or_renamed() {
+        let root = tree();
+        let sources = vec!["Papers/Paper".to_string()];
+
+        let plan = plan_move(&root, &sources, "Books").unwrap();
+        assert_eq!(plan.parent.to_string(), "f");
+        assert_eq!(plan.new_name, None);
+        assert_eq!(plan.nodes[0].1.get_hash().to_string(), "c");
+
+        let plan = plan_move(&root, &sources, "Books/Article").unwrap();
+        assert_eq!(plan.parent.to_string(), "f");
+        assert_eq!(plan.new_name.as_deref(), Some("Article"));
+
+        let plan = plan_move(&root, &sources, "/Article").unwrap();
+        assert_eq!(plan.parent.to_string(), "");
+        assert_eq!(plan.new_name.as_deref(), Some("Article"));
+    }
+
+    #[test]
+    fn invalid_moves_are_rejected() {
+        let root = tree();
+        let move_ = |sources: &[&str], destination: &str| {
+            let sources: Vec<String> = sources.iter().map(|s| s.to_string()).collect();
+            error(plan_move(&root, &sources, destination))
+        };
+
+        assert_eq!(
+            move_(&["Papers"], "Papers/2023"),
+            "cannot move Papers into itself"
+        );
+        assert_eq!(
+            move_(&["Papers"], "Papers"),
+            "cannot move Papers into itself"
+        );
+        assert_eq!(move_(&["Notes"], "Papers"), "Papers already contains Notes");
+        assert_eq!(
+            move_(&["Papers/Notes"], "/"),
+            "the root collection already contains Notes"
+        );
+        assert_eq!(
+            move_(&["Notes", "Papers/Notes"], "Books"),
+            "Notes and Papers/Notes have the same name"
+        );
+        assert_eq!(
+            move_(&["Notes", "Papers/Paper"], "Books/New"),
+            "Books/New is not a collection"
+        );
+        assert_eq!(
+            move_(&["Notes"], "Papers/Paper"),
+            "Papers/Paper already exists"
+        );
+        assert_eq!(move_(&["Gone"], "Books"), "Gone not found on the remote");
+        assert_eq!(move_(&["/"], "Books"), "cannot change the root collection");
+    }
+
+    #[test]
+    fn renames_must_not_clash_with_a_sibling() {
+        let root = tree();
+        let node = resolve_rename(&root, "Papers/Paper", "Paper 2").unwrap();
+        assert_eq!(node.get_hash().to_string(), "c");
+        // keeping the same name is allowed
+        assert!(resolve_rename(&root, "Papers/Paper", "Paper").is_ok());
+
+        assert_eq!(
+            error(resolve_rename(&root, "Papers/Paper", "Notes")),
+            "Papers already contains Notes"
+        );
+        assert_eq!(
+            error(resolve_rename(&root, "Notes", "Books")),
+            "the root collection already contains Books"
+        );
+        assert_eq!(
+            error(resolve_rename(&root, "Notes", "a/b")),
+            "a/b is not a valid name"
+        );
+    }
+
+    #[test]
+    fn permanent_removal_deletes_everything_inside() {
+        let root = tree();
+        let hashes = |path: &str| -> Vec<String> {
+            hashes_to_delete(find_node(&root, path).unwrap())
+                .iter()
+                .map(|h| h.to_string())
+                .collect()
+        };
+        assert_eq!(hashes("Papers"), vec!["b", "c", "d", "e"]);
+        assert_eq!(hashes("Papers/Paper"), vec!["c"]);
+    }
+}
@@ -0,0 +1,507 @@
+use std::collections::HashMap;
+use std::fs::create_dir_all;
+use std::path::{Component, Path, PathBuf};
+
+use colored::*;
+use ssh2::Session;
+
+use crate::constants::DIR;
+use crate::file_names::{names_match, unsanitize_file_name};
+use crate::file_types::FileType;
+use crate::local_fs::get_local_sha256;
+use crate::nodes::{Directory, DirectoryNode, File, Hash, Node};
+use crate::profile::DeviceProfile;
+use crate::ssh_utils::{
+    create_remote_collection, get_remote_file_stats, get_remote_sha256, update_remote_metadata,
+    RemoteFileStat,
+};
+use crate::sync_state::SyncState;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RenameKind {
+    /// The document has a new name in the same directory
+    Renamed,
+    /// The document is in a different directory
+    Moved,
+}
+
+/// Which side of a sync a change was made on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Remote,
+    Local,
+}
+
+/// A document which is at different paths on the remote and locally.
+/// Both paths are local paths relative to the synced directory, with the
+/// remote path using the names given by `map_to_local_names`.
+#[derive(Clone, Debug)]
+pub struct Rename {
+    pub hash: String,
+    pub kind: RenameKind,
+    pub changed_on: Side,
+    pub remote_path: PathBuf,
+    pub local_path: PathBuf,
+}
+
+impl Rename {
+    pub fn new(
+        hash: String,
+        changed_on: Side,
+        remote_path: PathBuf,
+        local_path: PathBuf,
+    ) -> Rename {
+        let kind = if remote_path.parent() == local_path.parent() {
+            RenameKind::Renamed
+        } else {
+            RenameKind::Moved
+        };
+        Rename {
+            hash,
+            kind,
+            changed_on,
+            remote_path,
+            local_path,
+        }
+    }
+
+    /// The path the document had before it changed.
+    pub fn get_from(&self) -> &Path {
+        match self.changed_on {
+            Side::Remote => &self.local_path,
+            Side::Local => &self.remote_path,
+        }
+    }
+
+    /// The path the document has after it changed.
+    pub fn get_to(&self) -> &Path {
+        match self.changed_on {
+            Side::Remote => &self.remote_path,
+            Side::Local => &self.local_path,
+        }
+    }
+}
+
+/// Find documents which are unique on both sides only because they were
+/// renamed or moved.
+///
+/// A remote document is paired with a local file when the sync state records
+/// the local file as its last synced path, in which case it was changed on the
+/// remote. Otherwise it is paired with a local file with the same size and
+/// sha256: if the document has been synced before the local copy was moved,
+/// and if not the remote is taken to be the one which changed.
+pub fn detect_renames(
+    unique_on_remote: &Directory,
+    unique_on_local: &Directory,
+    local_root: &Path,
+    state: &SyncState,
+    sess: &mut Session,
+) -> Result<Vec<Rename>, Box<dyn std::error::Error>> {
+    let remote_files = collect_files(unique_on_remote, Path::new(""));
+    let local_paths: Vec<PathBuf> = collect_files(unique_on_local, Path::new(""))
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect();
+
+    if remote_files.is_empty() || local_paths.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let stats = get_remote_file_stats(sess, DIR);
+    pair_renames(
+        remote_files,
+        local_paths,
+        local_root,
+        state,
+        &stats,
+        |payload_path| get_remote_sha256(sess, payload_path),
+    )
+}
+
+/// Pair remote documents with local paths as described in `detect_renames`,
+/// given the stats of the files on the remote and a way to get the sha256 of
+/// one of them.
+fn pair_renames(
+    remote_files: Vec<(PathBuf, File)>,
+    mut local_paths: Vec<PathBuf>,
+    local_root: &Path,
+    state: &SyncState,
+    stats: &HashMap<String, RemoteFileStat>,
+    mut remote_sha256: impl FnMut(&str) -> Option<String>,
+) -> Result<Vec<Rename>, Box<dyn std::error::Error>> {
+    let mut renames = Vec::new();
+    for (remote_path, file) in remote_files {
+        let hash = file.get_hash().to_string();
+        let extension = match file.get_file_type() {
+            Some(FileType::Pdf) => "pdf",
+            Some(FileType::Epub) => "epub",
+            // notebooks have no payload to compare
+            _ => continue,
+        };
+        let entry = state.get_entry(&hash);
+
+        // the local file is still where it was last synced to
+        if let Some(entry) = entry {
+            if let Some(i) = local_paths.iter().position(|p| *p == entry.path) {
+                let local_path = local_paths.remove(i);
+                renames.push(Rename::new(hash, Side::Remote, remote_path, local_path));
+                continue;
+            }
+        }
+
+        let payload_path = format!("{}/{}.{}", DIR, hash, extension);
+        let size = match stats.get(&payload_path) {
+            Some(stat) => stat.size,
+            None => continue,
+        };
+        let candidates: Vec<usize> = local_paths
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| {
+                state.get_hash(p).is_none()
+                    && local_root.join(p).metadata().ok().map(|m| m.len()) == Some(size)
+            })
+            .map(|(i, _)| i)
+            .collect();
+        if candidates.is_empty() {
+            continue;
+        }
+
+        let remote_sha256 = match entry {
+            Some(entry) if entry.size == Some(size) && entry.sha256.is_some() => {
+                entry.sha256.clone()
+            }
+            _ => remote_sha256(&payload_path),
+        };
+        let remote_sha256 = match remote_sha256 {
+            Some(sha256) => sha256,
+            None => continue,
+        };
+
+        for i in candidates {
+            if get_local_sha256(&local_root.join(&local_paths[i]))? == remote_sha256 {
+                let local_path = local_paths.remove(i);
+                let changed_on = if entry.is_some() {
+                    Side::Local
+                } else {
+                    Side::Remote
+                };
+                renames.push(Rename::new(hash, changed_on, remote_path, local_path));
+                break;
+            }
+        }
+    }
+
+    Ok(renames)
+}
+
+/// Remove renamed documents from the results of
+/// `remove_common_files_and_directories`, so they aren't copied again.
+pub fn remove_renamed(
+    unique_on_remote: &Directory,
+    unique_on_local: &Directory,
+    renames: &[Rename],
+) -> (Directory, Directory) {
+    let unique_on_remote = retain_files(unique_on_remote, Path::new(""), &|_, file| {
+        !renames
+            .iter()
+            .any(|r| r.hash == file.get_hash().to_string())
+    });
+    let unique_on_local = retain_files(unique_on_local, Path::new(""), &|path, _| {
+        !renames.iter().any(|r| r.local_path == path)
+    });
+    (unique_on_remote, unique_on_local)
+}
+
+pub fn print_renames(renames: &[Rename]) {
+    for rename in renames {
+        let kind = match rename.kind {
+            RenameKind::Renamed => "renamed",
+            RenameKind::Moved => "moved",
+        };
+        let side = match rename.changed_on {
+            Side::Remote => "on remote",
+            Side::Local => "locally",
+        };
+        println!(
+            "  {} {} {} {} {}",
+            kind.bold().yellow(),
+            side,
+            rename.get_from().to_string_lossy().purple(),
+            "->".bold(),
+            rename.get_to().to_string_lossy().purple()
+        );
+    }
+}
+
+/// Apply renames: documents changed on the remote are renamed locally and
+/// documents changed locally are renamed or moved on the remote.
+/// `remote_root` is the remote directory being synced, mapped to local names.
+pub fn apply_renames(
+    renames: &[Rename],
+    remote_root: &Directory,
+    local_root: &Path,
+    state: &mut SyncState,
+    profile: &DeviceProfile,
+    sess: &mut Session,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut created = HashMap::new();
+
+    for rename in renames {
+        match rename.changed_on {
+            Side::Remote => {
+                let from = local_root.join(&rename.local_path);
+                let to = local_root.join(&rename.remote_path);
+                if to.exists() {
+                    println!(
+                        "{} {} already exists",
+                        "Skipping:".bold().yellow(),
+                        to.to_string_lossy().purple()
+                    );
+                    continue;
+                }
+                if let Some(parent) = to.parent() {
+                    create_dir_all(parent)?;
+                }
+                std::fs::rename(&from, &to)?;
+                state.set_path(&rename.hash, rename.remote_path.clone());
+            }
+            Side::Local => {
+                let file_name = rename
+                    .local_path
+                    .file_name()
+                    .ok_or("local path has no file name")?;
+                let name = match FileType::from_path(&rename.local_path) {
+                    Some(_) => rename.local_path.file_stem().unwrap_or(file_name),
+                    None => file_name,
+                };
+                let name = unsanitize_file_name(&name.to_string_lossy());
+                let parent = get_or_create_remote_directory(
+                    remote_root,
+                    rename.local_path.parent().unwrap_or(Path::new("")),
+                    &mut created,
+                    profile,
+                    sess,
+                )?;
+
+                update_remote_metadata(sess, &rename.hash, |metadata| {
+                    metadata.set_visible_name(name);
+                    metadata.set_parent(parent.to_string());
+                })?;
+                state.set_path(&rename.hash, rename.local_path.clone());
+            }
+        }
+        println!(
+            "{} {} {} {}",
+            "Moved:".bold().green(),
+            rename.get_from().to_string_lossy().italic().purple(),
+            "->".bold(),
+            rename.get_to().to_string_lossy().italic().purple()
+        );
+    }
+
+    Ok(())
+}
+
+/// Find the hash of the remote directory at a local path relative to
+/// `remote_root`, creating any collections which don't exist yet.
+fn get_or_create_remote_directory(
+    remote_root: &Directory,
+    relative_path: &Path,
+    created: &mut HashMap<PathBuf, Directory>,
+    profile: &DeviceProfile,
+    sess: &mut Session,
+) -> Result<Hash, Box<dyn std::error::Error>> {
+    let mut directory = remote_root.clone();
+    let mut path = PathBuf::new();
+    for component in relative_path.components() {
+        let name = match component {
+            Component::Normal(name) => name.to_string_lossy().into_owned(),
+            _ => continue,
+        };
+        path.push(&name);
+
+        directory = if let Some(existing) = created.get(&path) {
+            existing.clone()
+        } else if let Some(existing) = directory
+            .get_directories()
+            .iter()
+            .find(|d| names_match(d.get_visible_name(), &name, false))
+        {
+            existing.clone()
+        } else {
+            let collection = create_remote_collection(
+                &unsanitize_file_name(&name),
+                directory.get_hash(),
+                profile,
+                sess,
+            )?;
+            created.insert(path.clone(), collection.clone());
+            collection
+        };
+    }
+    Ok(directory.get_hash().clone())
+}
+
+/// List every file in a directory tree with its path relative to the root.
+fn collect_files(directory: &Directory, relative_path: &Path) -> Vec<(PathBuf, File)> {
+    let mut files: Vec<(PathBuf, File)> = directory
+        .get_files()
+        .iter()
+        .map(|f| (relative_path.join(f.get_visible_name()), f.clone()))
+        .collect();
+    for sub_directory in directory.get_directories() {
+        files.extend(collect_files(
+            sub_directory,
+            &relative_path.join(sub_directory.get_visible_name()),
+        ));
+    }
+    files
+}
+
+/// Keep only the files in a directory tree for which `keep` returns true.
+fn retain_files(
+    directory: &Directory,
+    relative_path: &Path,
+    keep: &dyn Fn(&Path, &File) -> bool,
+) -> Directory {
+    let files = directory
+        .get_files()
+        .iter()
+        .filter(|f| keep(&relative_path.join(f.get_visible_name()), f))
+        .cloned()
+        .collect();
+    let directories = directory
+        .get_directories()
+        .iter()
+        .map(|d| retain_files(d, &relative_path.join(d.get_visible_name()), keep))
+        .collect();
+    Directory::new(
+        directory.get_hash().clone(),
+        directory.get_metadata().clone(),
+        Some(files),
+        Some(directories),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{directory, file};
+
+    fn stats(entries: &[(&str, u64)]) -> HashMap<String, RemoteFileStat> {
+        entries
+            .iter()
+            .map(|(hash, size)| {
+                let path = format!("{}/{}.pdf", DIR, hash);
+                let stat = RemoteFileStat {
+                    path: path.clone(),
+                    size: *size,
+                    modified: 0,
+                };
+                (path, stat)
+            })
+            .collect()
+    }
+
+    fn remote_files(files: Vec<File>) -> Vec<(PathBuf, File)> {
+        collect_files(&directory("", "", files, Vec::new()), Path::new(""))
+    }
+
+    #[test]
+    fn synced_path_pairs_a_rename_on_the_remote() {
+        let mut state = SyncState::default();
+        state.set_path("a", PathBuf::from("Old.pdf"));
+        let renames = pair_renames(
+            remote_files(vec![file("a", "New.pdf", Some(FileType::Pdf))]),
+            vec![PathBuf::from("Old.pdf")],
+            Path::new("/nonexistent"),
+            &state,
+            &HashMap::new(),
+            |_| panic!("the content shouldn't be compared"),
+        )
+        .unwrap();
+        assert_eq!(renames.len(), 1);
+        assert_eq!(renames[0].kind, RenameKind::Renamed);
+        assert_eq!(renames[0].changed_on, Side::Remote);
+        assert_eq!(renames[0].get_from(), Path::new("Old.pdf"));
+        assert_eq!(renames[0].get_to(), Path::new("New.pdf"));
+    }
+
+    #[test]
+    fn unsynced_documents_are_paired_by_content() {
+        let local_root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(local_root.path().join("Papers")).unwrap();
+        std::fs::write(local_root.path().join("Papers/Paper.pdf"), "abc").unwrap();
+        std::fs::write(local_root.path().join("Other.pdf"), "xyz").unwrap();
+        let sha256 = get_local_sha256(&local_root.path().join("Papers/Paper.pdf")).unwrap();
+
+        let renames = pair_renames(
+            remote_files(vec![file("a", "Paper.pdf", Some(FileType::Pdf))]),
+            vec![
+                PathBuf::from("Other.pdf"),
+                PathBuf::from("Papers/Paper.pdf"),
+            ],
+            local_root.path(),
+            &SyncState::default(),
+            &stats(&[("a", 3)]),
+            |_| Some(sha256.clone()),
+        )
+        .unwrap();
+        assert_eq!(renames.len(), 1);
+        assert_eq!(renames[0].kind, RenameKind::Moved);
+        assert_eq!(renames[0].changed_on, Side::Remote);
+        assert_eq!(renames[0].local_path, Path::new("Papers/Paper.pdf"));
+    }
+
+    #[test]
+    fn synced_documents_moved_locally_use_the_recorded_sha256() {
+        let local_root = tempfile::tempdir().unwrap();
+        std::fs::write(local_root.path().join("Paper.pdf"), "abc").unwrap();
+        let mut state = SyncState::default();
+        state
+            .record_file("a", local_root.path(), PathBuf::from("Paper.pdf"))
+            .unwrap();
+        std::fs::create_dir(local_root.path().join("Archive")).unwrap();
+        std::fs::rename(
+            local_root.path().join("Paper.pdf"),
+            local_root.path().join("Archive/Paper.pdf"),
+        )
+        .unwrap();
+
+        let renames = pair_renames(
+            remote_files(vec![file("a", "Paper.pdf", Some(FileType::Pdf))]),
+            vec![PathBuf::from("Archive/Paper.pdf")],
+            local_root.path(),
+            &state,
+            &stats(&[("a", 3)]),
+            |_| panic!("the recorded sha256 should be used"),
+        )
+        .unwrap();
+        assert_eq!(renames.len(), 1);
+        assert_eq!(renames[0].changed_on, Side::Local);
+        assert_eq!(renames[0].get_from(), Path::new("Paper.pdf"));
+        assert_eq!(renames[0].get_to(), Path::new("Archive/Paper.pdf"));
+    }
+
+    #[test]
+    fn different_content_and_notebooks_are_not_paired() {
+        let local_root = tempfile::tempdir().unwrap();
+        std::fs::write(local_root.path().join("Paper.pdf"), "abc").unwrap();
+        std::fs::write(local_root.path().join("Notes.pdf"), "abc").unwrap();
+
+        let renames = pair_renames(
+            remote_files(vec![
+                file("a", "Renamed.pdf", Some(FileType::Pdf)),
+                file("b", "Notes", Some(FileType::Notebook)),
+            ]),
+            vec![PathBuf::from("Paper.pdf"), PathBuf::from("Notes.pdf")],
+            local_root.path(),
+            &SyncState::default(),
+            &stats(&[("a", 3), ("b", 3)]),
+            |_| Some("0".repeat(64)),
+        )
+        .unwrap();
+        assert!(renames.is_empty());
+    }
+}
@@ -0,0 +1,254 @@
+use chrono::{DateTime, Utc};
+use colored::*;
+use serde_json::Value;
+use ssh2::Session;
+
+use crate::constants::DIR;
+use crate::dates::DateFormat;
+use crate::du::{get_sizes_by_hash, get_usage};
+use crate::file_types::FileType;
+use crate::nodes::{Directory, DirectoryNode, File, Node};
+use crate::snapshots::format_size;
+use crate::ssh_utils::run_remote_command;
+use crate::tags::{get_live_page_ids, get_tags};
+
+/// Everything remarko can tell about one document or collection, from its
+/// `.metadata`, `.content`, `.pagedata` and page directory.
+#[derive(Clone, Debug)]
+pub struct Details {
+    pub hash: String,
+    pub name: String,
+    /// The path from the root, e.g. "Papers/2023/Attention"
+    pub path: String,
+    /// "pdf", "epub", "notebook" or "collection"
+    pub kind: String,
+    /// The number of pages, or of documents and collections directly inside
+    /// a collection
+    pub count: Option<usize>,
+    /// All of its files on the device, in bytes, and for a collection those
+    /// of everything inside it
+    pub size: u64,
+    pub created: Option<DateTime<Utc>>,
+    pub modified: Option<DateTime<Utc>>,
+    pub opened: Option<DateTime<Utc>>,
+    /// Counting from 1
+    pub last_opened_page: Option<u64>,
+    /// Where it came from, e.g. the address it was emailed from
+    pub source: Option<String>,
+    pub tags: Vec<String>,
+    pub pinned: bool,
+    /// Pages with a `.rm` file, i.e. which have been written on
+    pub annotated_pages: usize,
+    /// The distinct templates used by its pages, in page order
+    pub templates: Vec<String>,
+}
+
+pub fn get_details(node: &dyn Node, path: &str, sess: &mut Session) -> Details {
+    let hash = node.get_hash().to_string();
+    let metadata = node.get_metadata();
+    let content: Value = serde_json::from_str(&run_remote_command(
+        sess,
+        &format!("cat {}/{}.content 2>/dev/null", DIR, hash),
+    ))
+    .unwrap_or(Value::Null);
+
+    let (kind, count, size) = if let Some(directory) = node.as_any().downcast_ref::<Directory>() {
+        let count = directory.get_files().len() + directory.get_directories().len();
+        // the root has no files of its own, and an empty hash would make
+        // `find` below look at the whole documents directory
+        let size = get_usage(directory, &get_sizes_by_hash(sess)).size;
+        ("collection".to_string(), Some(count), size)
+    } else {
+        let kind = node
+            .as_any()
+            .downcast_ref::<File>()
+            .and_then(|f| f.get_file_type())
+            .unwrap_or(FileType::Notebook)
+            .to_string();
+        let size = run_remote_command(
+            sess,
+            &format!(
+                "cd {} && find {} {}.* -type f -exec stat -c %s {{}} + 2>/dev/null",
+                DIR, hash, hash
+            ),
+        )
+        .lines()
+        .filter_map(|line| line.trim().parse::<u64>().ok())
+        .sum();
+        (kind, get_page_count(&content), size)
+    };
+
+    let annotated_pages = run_remote_command(sess, &format!("ls {}/{} 2>/dev/null", DIR, hash))
+        .lines()
+        .filter(|name| name.ends_with(".rm"))
+        .count();
+
+    let templates = get_templates(&content, || {
+        run_remote_command(sess, &format!("cat {}/{}.pagedata 2>/dev/null", DIR, hash))
+    });
+
+    Details {
+        name: node.get_visible_name().to_string(),
+        path: path.trim_matches('/').to_string(),
+        kind,
+        count,
+        size,
+        created: metadata.get_created(),
+        modified: metadata.get_last_modified(),
+        opened: metadata.get_last_opened(),
+        last_opened_page: metadata.get_last_opened_page().map(|page| page + 1),
+        source: metadata.get_source().cloned(),
+        tags: get_tags(&content),
+        pinned: metadata.is_pinned(),
+        annotated_pages,
+        templates,
+        hash,
+    }
+}
+
+pub fn print_details(details: &Details, dates: &DateFormat) {
+    let none = || "none".italic().to_string();
+    let field = |label: &str, value: String| println!("{:<18} {}", label.bold(), value);
+
+    field("Name:", details.name.purple().to_string());
+    field("Path:", details.path.clone());
+    field("UUID:", details.hash.clone());
+    field("Type:", details.kind.clone());
+    let count_label = match details.kind.as_str() {
+        "collection" => "Items:",
+        _ => "Pages:",
+    };
+    field(
+        count_label,
+        details.count.map(|c| c.to_string()).unwrap_or_else(none),
+    );
+    field("Size:", format_size(details.size));
+    let date = |date: &Option<DateTime<Utc>>| dates.format_or(date.as_ref(), "never");
+    field("Created:", date(&details.created));
+    field("Modified:", date(&details.modified));
+    field("Last opened:", date(&details.opened));
+    if details.kind == "collection" {
+        field("Pinned:", yes_no(details.pinned));
+        return;
+    }
+    field(
+        "Last opened page:",
+        details
+            .last_opened_page
+            .map(|p| p.to_string())
+            .unwrap_or_else(none),
+    );
+    field(
+        "Tags:",
+        match details.tags.is_empty() {
+            true => none(),
+            false => details.tags.join(", "),
+        },
+    );
+    field("Pinned:", yes_no(details.pinned));
+    field("Annotated pages:", details.annotated_pages.to_string());
+    if let Some(source) = &details.source {
+        field("Source:", source.clone());
+    }
+    field(
+        "Templates:",
+        match details.templates.is_empty() {
+            true => none(),
+            false => details.templates.join(", "),
+        },
+    );
+}
+
+/// The number of pages of a document, leaving out deleted pages still
+/// listed in `cPages`.
+fn get_page_count(content: &Value) -> Option<usize> {
+    content["pageCount"]
+        .as_u64()
+        .map(|c| c as usize)
+        .or_else(|| get_live_page_ids(content).map(|pages| pages.len()))
+}
+
+/// The distinct templates used by the pages of a document, in page order.
+/// Newer firmware keeps them in `cPages`, older in the `.pagedata` given by
+/// `read_pagedata`.
+fn get_templates(content: &Value, read_pagedata: impl FnOnce() -> String) -> Vec<String> {
+    let is_deleted = |page: &Value| page["deleted"]["value"].as_i64().unwrap_or(0) != 0;
+    let mut page_templates: Vec<String> = content["cPages"]["pages"]
+        .as_array()
+        .map(|pages| {
+            pages
+                .iter()
+                .filter(|p| !is_deleted(p))
+                .filter_map(|p| p["template"]["value"].as_str())
+                .map(|t| t.to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    if page_templates.is_empty() {
+        page_templates = read_pagedata()
+            .lines()
+            .map(|line| line.trim().to_string())
+            .collect();
+    }
+    let mut templates: Vec<String> = Vec::new();
+    for template in page_templates {
+        if !template.is_empty() && !templates.contains(&template) {
+            templates.push(template);
+        }
+    }
+    templates
+}
+
+fn yes_no(value: bool) -> String {
+    match value {
+        true => "yes".to_string(),
+        false => "no".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn no_pagedata() -> String {
+        panic!("the .pagedata shouldn't be read")
+    }
+
+    #[test]
+    fn page_counts_come_from_the_content() {
+        let c_pages = json!({"cPages": {"pages": [
+            {"id": "p1"},
+            {"id": "p2", "deleted": {"timestamp": "1:2", "value": 1}},
+            {"id": "p3"},
+        ]}});
+        assert_eq!(get_page_count(&c_pages), Some(2));
+        assert_eq!(get_page_count(&json!({"pages": ["p1", "p2"]})), Some(2));
+        assert_eq!(
+            get_page_count(&json!({"pageCount": 12, "pages": []})),
+            Some(12)
+        );
+        assert_eq!(get_page_count(&json!({"fileType": "pdf"})), None);
+        assert_eq!(get_page_count(&Value::Null), None);
+    }
+
+    #[test]
+    fn templates_come_from_c_pages() {
+        let content = json!({"cPages": {"pages": [
+            {"id": "p1", "template": {"value": "Lined"}},
+            {"id": "p2", "template": {"value": "Grid"}},
+            {"id": "p3", "template": {"value": "Dots"}, "deleted": {"value": 1}},
+            {"id": "p4", "template": {"value": "Lined"}},
+        ]}});
+        assert_eq!(get_templates(&content, no_pagedata), vec!["Lined", "Grid"]);
+    }
+
+    #[test]
+    fn templates_come_from_pagedata_on_older_firmware() {
+        let content = json!({"pages": ["p1", "p2", "p3"]});
+        let templates = get_templates(&content, || "Blank\nLS Checklist\n\nBlank\n".to_string());
+        assert_eq!(templates, vec!["Blank", "LS Checklist"]);
+        assert!(get_templates(&Value::Null, String::new).is_empty());
+    }
+}
@@ -0,0 +1,434 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{create_dir_all, read_dir, rename, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use chrono::Local;
+use colored::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use ssh2::Session;
+
+use crate::backup::select_documents;
+use crate::constants::{DIR, SNAPSHOTS_DIR};
+use crate::nodes::Metadata;
+use crate::remarkable_trees::get_hash_from_path;
+use crate::ssh_utils::{
+    get_remote_file_stats, read_from_remote, run_remote_command, write_to_remote,
+};
+
+// files larger than this, e.g. pdfs and pages with a lot of strokes, are
+// stored in chunks of this size, so a change to one part of them only stores
+// the chunks which changed
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// A file in the documents directory as it was when a snapshot was taken.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotFile {
+    /// The sha256 of the content, which is the name of its object in the
+    /// store unless it was stored in chunks
+    pub sha256: String,
+    /// The sha256s of the chunks of a large file, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+    pub size: u64,
+    /// seconds since the epoch
+    pub modified: i64,
+}
+
+/// The state of the documents directory at one point in time, with files
+/// keyed by their path relative to it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub id: String,
+    /// How many bytes of new content were stored by this snapshot
+    pub stored: u64,
+    pub files: BTreeMap<String, SnapshotFile>,
+}
+
+impl Snapshot {
+    pub fn get_size(&self) -> u64 {
+        self.files.values().map(|f| f.size).sum()
+    }
+}
+
+/// Snapshots of one remarkable, stored in `~/.local/share/remarko/snapshots/<host>`.
+///
+/// File contents are stored once per distinct sha256 in `objects/`, so each
+/// snapshot only adds the files which changed since the last one. Large files
+/// are split into chunks, which are stored the same way.
+pub struct SnapshotStore {
+    root: PathBuf,
+}
+
+impl SnapshotStore {
+    pub fn open(host_name: &str) -> Result<SnapshotStore, Box<dyn std::error::Error>> {
+        let root = dirs::data_dir()
+            .ok_or("No data directory")?
+            .join("remarko")
+            .join(SNAPSHOTS_DIR)
+            .join(host_name);
+        create_dir_all(root.join("objects"))?;
+        create_dir_all(root.join("snapshots"))?;
+        Ok(SnapshotStore { root })
+    }
+
+    /// All snapshots, oldest first.
+    pub fn list(&self) -> Result<Vec<Snapshot>, Box<dyn std::error::Error>> {
+        let mut ids: Vec<String> = read_dir(self.root.join("snapshots"))?
+            .filter_map(|entry| {
+                let file_name = entry.ok()?.file_name().to_string_lossy().into_owned();
+                file_name.strip_suffix(".json").map(|id| id.to_string())
+            })
+            .collect();
+        ids.sort_by(|a, b| id_order(a).cmp(&id_order(b)));
+        ids.iter().map(|id| self.load(id)).collect()
+    }
+
+    /// An id for a snapshot taken at `time`, with a suffix if one was already
+    /// taken in the same second, e.g. "2024-01-31_18-30-00.2".
+    fn new_id(&self, time: &str) -> String {
+        let exists = |id: &str| {
+            self.root
+                .join("snapshots")
+                .join(format!("{}.json", id))
+                .exists()
+        };
+        let mut id = time.to_string();
+        let mut suffix = 1;
+        while exists(&id) {
+            suffix += 1;
+            id = format!("{}.{}", time, suffix);
+        }
+        id
+    }
+
+    pub fn load(&self, id: &str) -> Result<Snapshot, Box<dyn std::error::Error>> {
+        let path = self.root.join("snapshots").join(format!("{}.json", id));
+        let file = File::open(&path).map_err(|_| format!("No snapshot {}", id))?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+
+    fn save(&self, snapshot: &Snapshot) -> Result<(), Box<dyn std::error::Error>> {
+        let path = self
+            .root
+            .join("snapshots")
+            .join(format!("{}.json", snapshot.id));
+        write_atomically(&path, &serde_json::to_vec(snapshot)?)?;
+        Ok(())
+    }
+
+    fn object_path(&self, sha256: &str) -> PathBuf {
+        self.root.join("objects").join(&sha256[..2]).join(sha256)
+    }
+
+    /// Store content, compressed, unless it is already in the store.
+    /// Returns its sha256 and how many bytes were added to the store.
+    fn store_object(&self, content: &[u8]) -> Result<(String, u64), Box<dyn std::error::Error>> {
+        let mut hasher = Sha256::new();
+        hasher.update(content);
+        let sha256 = format!("{:x}", hasher.finalize());
+
+        let path = self.object_path(&sha256);
+        if path.exists() {
+            return Ok((sha256, 0));
+        }
+        create_dir_all(path.parent().unwrap())?;
+        let compressed = zstd::encode_all(content, 0)?;
+        write_atomically(&path, &compressed)?;
+        Ok((sha256, compressed.len() as u64))
+    }
+
+    fn read_object(&self, sha256: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let compressed = std::fs::read(self.object_path(sha256))
+            .map_err(|_| format!("Missing object {} in snapshot store", sha256))?;
+        Ok(zstd::decode_all(compressed.as_slice())?)
+    }
+
+    /// Store the content of a file, in chunks if it is large. Returns its
+    /// sha256, the sha256s of its chunks and how many bytes were added.
+    fn store_file(
+        &self,
+        content: &[u8],
+    ) -> Result<(String, Vec<String>, u64), Box<dyn std::error::Error>> {
+        if content.len() <= CHUNK_SIZE {
+            let (sha256, added) = self.store_object(content)?;
+            return Ok((sha256, Vec::new(), added));
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(content);
+        let sha256 = format!("{:x}", hasher.finalize());
+
+        let mut chunks = Vec::new();
+        let mut stored = 0;
+        for chunk in content.chunks(CHUNK_SIZE) {
+            let (chunk_sha256, added) = self.store_object(chunk)?;
+            chunks.push(chunk_sha256);
+            stored += added;
+        }
+        Ok((sha256, chunks, stored))
+    }
+
+    fn read_file(&self, file: &SnapshotFile) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        if file.chunks.is_empty() {
+            return self.read_object(&file.sha256);
+        }
+        let mut content = Vec::with_capacity(file.size as usize);
+        for chunk in &file.chunks {
+            content.extend(self.read_object(chunk)?);
+        }
+        Ok(content)
+    }
+
+    /// Take a snapshot of the documents directory. Only files whose size or
+    /// modification time changed since the last snapshot are transferred.
+    pub fn take_snapshot(
+        &self,
+        sess: &mut Session,
+    ) -> Result<Snapshot, Box<dyn std::error::Error>> {
+        let previous = self.list()?.pop();
+        let stats = get_remote_file_stats(sess, DIR);
+
+        let mut files = BTreeMap::new();
+        let mut stored = 0;
+        for stat in stats.values() {
+            let relative_path = match stat.path.strip_prefix(&format!("{}/", DIR)) {
+                Some(path) => path.to_string(),
+                None => continue,
+            };
+            let unchanged = previous
+                .as_ref()
+                .and_then(|p| p.files.get(&relative_path))
+                .filter(|f| f.size == stat.size && f.modified == stat.modified);
+
+            let (sha256, chunks) = match unchanged {
+                Some(file) => (file.sha256.clone(), file.chunks.clone()),
+                None => {
+                    let (sha256, chunks, added) =
+                        self.store_file(&read_from_remote(&stat.path, sess))?;
+                    stored += added;
+                    (sha256, chunks)
+                }
+            };
+            files.insert(
+                relative_path,
+                SnapshotFile {
+                    sha256,
+                    chunks,
+                    size: stat.size,
+                    modified: stat.modified,
+                },
+            );
+        }
+
+        let snapshot = Snapshot {
+            id: self.new_id(&Local::now().format("%Y-%m-%d_%H-%M-%S").to_string()),
+            stored,
+            files,
+        };
+        self.save(&snapshot)?;
+        Ok(snapshot)
+    }
+
+    /// Find the hashes of documents and collections in a snapshot from their
+    /// paths in the snapshot's own tree, or their hashes.
+    pub fn resolve_documents(
+        &self,
+        snapshot: &Snapshot,
+        documents: &[String],
+        sess: &mut Session,
+    ) -> Result<BTreeSet<String>, Box<dyn std::error::Error>> {
+        let mut metadata: Vec<(String, Metadata)> = Vec::new();
+        let mut file_names = Vec::new();
+        for (path, file) in &snapshot.files {
+            if path.contains('/') {
+                continue;
+            }
+            if let Some(hash) = path.strip_suffix(".metadata") {
+                let content = self.read_file(file)?;
+                metadata.push((hash.to_string(), serde_json::from_slice(&content)?));
+            }
+            file_names.push(path.clone());
+        }
+        select_documents(metadata, &file_names, documents, sess)
+            .map_err(|e| format!("{} in snapshot {}", e, snapshot.id).into())
+    }
+
+    /// Put documents back on the remarkable as they were in a snapshot. Files
+    /// they have now which they didn't have then, e.g. new pages, are removed.
+    /// Returns the number of files restored.
+    pub fn restore_documents(
+        &self,
+        snapshot: &Snapshot,
+        hashes: &BTreeSet<String>,
+        sess: &mut Session,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let mut restored = 0;
+        for hash in hashes {
+            let files: Vec<(&String, &SnapshotFile)> = snapshot
+                .files
+                .iter()
+                .filter(|(path, _)| get_hash_from_path(Path::new(path)).as_ref() == Some(hash))
+                .collect();
+
+            run_remote_command(sess, &format!("rm -rf {}/{} {}/{}.*", DIR, hash, DIR, hash));
+            let directories: BTreeSet<&str> = files
+                .iter()
+                .filter_map(|(path, _)| path.rsplit_once('/').map(|(parent, _)| parent))
+                .collect();
+            for directory in directories {
+                run_remote_command(sess, &format!("mkdir -p {}/{}", DIR, directory));
+            }
+            for (path, file) in files {
+                let content = self.read_file(file)?;
+                write_to_remote(&content, &format!("{}/{}", DIR, path), sess);
+                restored += 1;
+            }
+        }
+        Ok(restored)
+    }
+}
+
+/// Write a file in the store via a temporary file next to it, so an
+/// interrupted write never leaves a partial file behind.
+fn write_atomically(path: &Path, content: &[u8]) -> Result<(), std::io::Error> {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(".tmp-{}", std::process::id()));
+    let temporary_path = path.with_file_name(file_name);
+    std::fs::write(&temporary_path, content)?;
+    rename(&temporary_path, path)
+}
+
+/// Sort snapshot ids by when they were taken, then by their suffix.
+fn id_order(id: &str) -> (&str, u32) {
+    match id.split_once('.') {
+        Some((time, suffix)) => (time, suffix.parse().unwrap_or_default()),
+        None => (id, 1),
+    }
+}
+
+pub fn print_snapshots(snapshots: &[Snapshot]) {
+    for snapshot in snapshots {
+        println!(
+            "  {}  {:>6} files  {:>10}  {:>10} new",
+            snapshot.id.bold(),
+            snapshot.files.len(),
+            format_size(snapshot.get_size()),
+            format_size(snapshot.stored)
+        );
+    }
+}
+
+/// Format a number of bytes for people, e.g. "1.5 MB".
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} {}", bytes, UNITS[0]),
+        _ => format!("{:.1} {}", size, UNITS[unit]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> (tempfile::TempDir, SnapshotStore) {
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path().to_path_buf();
+        create_dir_all(root.join("objects")).unwrap();
+        create_dir_all(root.join("snapshots")).unwrap();
+        (directory, SnapshotStore { root })
+    }
+
+    fn snapshot(id: &str) -> Snapshot {
+        Snapshot {
+            id: id.to_string(),
+            stored: 0,
+            files: BTreeMap::new(),
+        }
+    }
+
+    fn file(sha256: String, chunks: Vec<String>, size: usize) -> SnapshotFile {
+        SnapshotFile {
+            sha256,
+            chunks,
+            size: size as u64,
+            modified: 0,
+        }
+    }
+
+    // content which doesn't compress away, so each chunk has a distinct size
+    fn content(size: usize) -> Vec<u8> {
+        (0..size as u64)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect()
+    }
+
+    #[test]
+    fn small_files_are_one_object() {
+        let (_directory, store) = store();
+        let (sha256, chunks, added) = store.store_file(b"metadata").unwrap();
+        assert!(chunks.is_empty());
+        assert!(added > 0);
+        assert_eq!(store.store_file(b"metadata").unwrap().2, 0);
+        let read = store.read_file(&file(sha256, chunks, 8)).unwrap();
+        assert_eq!(read, b"metadata");
+    }
+
+    #[test]
+    fn large_files_only_store_changed_chunks() {
+        let (_directory, store) = store();
+        let mut content = content(CHUNK_SIZE * 3 + 10);
+        let (sha256, chunks, added) = store.store_file(&content).unwrap();
+        assert_eq!(chunks.len(), 4);
+        let read = store
+            .read_file(&file(sha256.clone(), chunks, content.len()))
+            .unwrap();
+        assert_eq!(read, content);
+
+        *content.last_mut().unwrap() ^= 0xff;
+        let (changed_sha256, changed_chunks, changed_added) = store.store_file(&content).unwrap();
+        assert_ne!(changed_sha256, sha256);
+        assert!(changed_added > 0 && changed_added < added);
+        let read = store
+            .read_file(&file(changed_sha256, changed_chunks, content.len()))
+            .unwrap();
+        assert_eq!(read, content);
+    }
+
+    #[test]
+    fn no_temporary_files_are_left_behind() {
+        let (_directory, store) = store();
+        let (sha256, _, _) = store.store_file(b"page").unwrap();
+        let objects: Vec<_> = read_dir(store.object_path(&sha256).parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(objects, vec![std::ffi::OsString::from(&sha256)]);
+    }
+
+    #[test]
+    fn snapshots_in_the_same_second_get_a_suffix() {
+        let (_directory, store) = store();
+        let time = "2024-01-31_18-30-00";
+        assert_eq!(store.new_id(time), time);
+        for _ in 0..11 {
+            let id = store.new_id(time);
+            store.save(&snapshot(&id)).unwrap();
+        }
+        store.save(&snapshot("2024-01-31_18-30-01")).unwrap();
+        assert_eq!(store.new_id(time), "2024-01-31_18-30-00.12");
+
+        let ids: Vec<String> = store.list().unwrap().into_iter().map(|s| s.id).collect();
+        assert_eq!(ids[0], time);
+        assert_eq!(ids[1], "2024-01-31_18-30-00.2");
+        assert_eq!(ids[10], "2024-01-31_18-30-00.11");
+        assert_eq!(ids[11], "2024-01-31_18-30-01");
+    }
+}
@@ -1,25 +1,30 @@
+use std::collections::HashMap;
 use std::fs::{create_dir_all, File};
 use std::io::{BufReader, Read, Write};
 use std::net::TcpStream;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use colored::*;
 use dirs::home_dir;
+use serde_json::Value;
 use ssh2::Session;
 use ssh2_config::{HostParams, ParseRule, SshConfig};
+use uuid::Uuid;
 
 use crate::constants;
-use crate::nodes::{Directory, DirectoryNode, Metadata, Node};
+use crate::file_names::{names_match, unsanitize_file_name};
+use crate::file_types::FileType;
+use crate::nodes::{Directory, DirectoryNode, Hash, Metadata, Node};
+use crate::profile::DeviceProfile;
 
 pub fn get_ssh_config() -> SshConfig {
     let config_path = format!("{}/.ssh/config", home_dir().unwrap().to_str().unwrap());
     let config_file = File::open(&config_path)
-        .expect(format!("Unable to open {}/.ssh/config", config_path).as_str());
+        .unwrap_or_else(|_| panic!("Unable to open {}/.ssh/config", config_path));
     let mut reader = BufReader::new(config_file);
-    let config = SshConfig::default()
+    SshConfig::default()
         .parse(&mut reader, ParseRule::STRICT)
-        .expect("Failed to parse configuration");
-    return config;
+        .expect("Failed to parse configuration")
 }
 
 pub fn connect_to_remote(params: HostParams) -> Session {
@@ -35,7 +40,7 @@ pub fn connect_to_remote(params: HostParams) -> Session {
     sess.handshake().unwrap();
     sess.userauth_pubkey_file(user, None, Path::new(&identity_file), None)
         .expect("authentication failed");
-    return sess;
+    sess
 }
 
 pub fn run_remote_command(sess: &mut Session, command: &str) -> String {
@@ -43,41 +48,167 @@ pub fn run_remote_command(sess: &mut Session, command: &str) -> String {
     channel.exec(command).unwrap();
     let mut output = String::new();
     channel.read_to_string(&mut output).unwrap();
-    return output;
+    output
 }
 
-pub fn read_remote_metadata(sess: &mut Session, file_name: &str) -> Metadata {
-    let command = format!("cat {}", file_name);
-    let output = run_remote_command(sess, &command);
-    let metadata: Metadata = serde_json::from_str(&output).unwrap();
-    return metadata;
+// printed before the contents of a file which exists, so an empty file can
+// be told apart from a missing one
+const EXISTS_MARKER: &str = "@@remarko-exists@@";
+
+/// Read a `.metadata` file, which is None if it doesn't exist. An empty file
+/// is an error, like any other which isn't valid metadata.
+pub fn read_remote_metadata(
+    sess: &mut Session,
+    file_name: &str,
+) -> Result<Option<Metadata>, serde_json::Error> {
+    let command = format!(
+        "test -f {} && echo {} && cat {}",
+        file_name, EXISTS_MARKER, file_name
+    );
+    parse_metadata_output(&run_remote_command(sess, &command))
+}
+
+fn parse_metadata_output(output: &str) -> Result<Option<Metadata>, serde_json::Error> {
+    match output.strip_prefix(&format!("{}\n", EXISTS_MARKER)) {
+        Some(content) => serde_json::from_str(content).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// The size and modification time of a file on the remote.
+#[derive(Clone, Debug)]
+pub struct RemoteFileStat {
+    pub path: String,
+    pub size: u64,
+    /// seconds since the epoch
+    pub modified: i64,
+}
+
+/// Stat every file below `directory` on the remote, keyed by path.
+pub fn get_remote_file_stats(
+    sess: &mut Session,
+    directory: &str,
+) -> HashMap<String, RemoteFileStat> {
+    let command = format!("find {} -type f -exec stat -c '%s %Y %n' {{}} +", directory);
+    parse_file_stats(&run_remote_command(sess, &command))
+}
+
+/// Parse the output of `stat -c '%s %Y %n'`, keyed by path.
+pub fn parse_file_stats(output: &str) -> HashMap<String, RemoteFileStat> {
+    let mut stats = HashMap::new();
+    for line in output.lines() {
+        let mut parts = line.splitn(3, ' ');
+        let (size, modified, path) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(size), Some(modified), Some(path)) => (size, modified, path),
+            _ => continue,
+        };
+        if let (Ok(size), Ok(modified)) = (size.parse(), modified.parse()) {
+            stats.insert(
+                path.to_string(),
+                RemoteFileStat {
+                    path: path.to_string(),
+                    size,
+                    modified,
+                },
+            );
+        }
+    }
+    stats
+}
+
+/// Get the sha256 of a file on the remote as a hex string.
+pub fn get_remote_sha256(sess: &mut Session, file_path: &str) -> Option<String> {
+    let output = run_remote_command(sess, &format!("sha256sum {}", file_path));
+    output
+        .split_whitespace()
+        .next()
+        .filter(|hash| hash.len() == 64)
+        .map(|hash| hash.to_string())
+}
+
+/// Rewrite the `.metadata` of a document or collection on the remote.
+///
+/// Fields remarko doesn't know about are kept. The version is bumped and the
+/// modification time updated so xochitl treats the change like one made on
+/// the device.
+pub fn update_remote_metadata(
+    sess: &mut Session,
+    hash: &str,
+    update: impl FnOnce(&mut Metadata),
+) -> Result<(), Box<dyn std::error::Error>> {
+    let metadata_file_name = format!("{}/{}.metadata", constants::DIR, hash);
+    let output = run_remote_command(sess, &format!("cat {}", metadata_file_name));
+    let mut metadata: Metadata = serde_json::from_str(&output)?;
+
+    update(&mut metadata);
+    metadata.mark_changed();
+
+    write_to_remote(
+        serde_json::to_string_pretty(&metadata)?.as_bytes(),
+        &metadata_file_name,
+        sess,
+    );
+    Ok(())
+}
+
+/// Rewrite the `.content` of a document on the remote, if `update` says it
+/// changed anything. Returns whether it did.
+///
+/// The content is edited as json, so everything remarko doesn't touch is
+/// written back as it was, and the metadata is marked as changed with
+/// `update_remote_metadata` so xochitl picks the new content up.
+pub fn update_remote_content(
+    sess: &mut Session,
+    hash: &str,
+    update: impl FnOnce(&mut Value) -> Result<bool, Box<dyn std::error::Error>>,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let content_file_name = format!("{}/{}.content", constants::DIR, hash);
+    let output = run_remote_command(sess, &format!("cat {}", content_file_name));
+    let mut content: Value = serde_json::from_str(&output)?;
+
+    if !update(&mut content)? {
+        return Ok(false);
+    }
+
+    write_to_remote(
+        serde_json::to_string_pretty(&content)?.as_bytes(),
+        &content_file_name,
+        sess,
+    );
+    update_remote_metadata(sess, hash, |_| {})?;
+    Ok(true)
 }
 
 pub fn check_remote_file_exists(sess: &mut Session, file_path: &str) -> bool {
     let command = format!("test -f {} && echo 'nice'", file_path);
     let output = run_remote_command(sess, &command);
-    return output.trim() == "nice";
+    output.trim() == "nice"
 }
 
 // TODO be consistent with whether sess is first or last argument
 pub fn send_to_remote(local_file_path: &str, remote_file_path: &str, sess: &mut Session) {
     let mut local_file = File::open(local_file_path).unwrap();
 
-    // get file metadata to retrieve the file size
-    let metadata = local_file.metadata().unwrap();
-
-    // write the file
-    let mut remote_file = sess
-        .scp_send(Path::new(remote_file_path), 0o644, metadata.len(), None)
-        .unwrap();
-
     // create a buffer to write the contents of the local file to the remote file
     let mut buffer = Vec::new();
     local_file
         .read_to_end(&mut buffer)
         .expect("Failed to read local file");
+    write_to_remote(&buffer, remote_file_path, sess);
+}
+
+/// Writes a buffer to a file on the remote, replacing it if it exists.
+pub fn write_to_remote(buffer: &[u8], remote_file_path: &str, sess: &mut Session) {
+    let mut remote_file = sess
+        .scp_send(
+            Path::new(remote_file_path),
+            0o644,
+            buffer.len() as u64,
+            None,
+        )
+        .unwrap();
     remote_file
-        .write_all(&buffer)
+        .write_all(buffer)
         .expect("Failed to write to remote file");
 
     // close the channel and wait for the whole content to be transferred
@@ -115,7 +246,15 @@ pub fn send_to_remote(local_file_path: &str, remote_file_path: &str, sess: &mut
 /// This function is used to copy a file from the remote to the local file system.
 /// The remote file is read into a buffer and then written to the local file.
 pub fn copy_from_remote(remote_file_path: &str, local_file_path: &str, sess: &mut Session) {
-    // read remote file to buffer
+    let buffer = read_from_remote(remote_file_path, sess);
+
+    // write buffer to file
+    let mut local_file = File::create(local_file_path).unwrap();
+    local_file.write_all(&buffer).unwrap();
+}
+
+/// Reads the whole of a file on the remote into a buffer.
+pub fn read_from_remote(remote_file_path: &str, sess: &mut Session) -> Vec<u8> {
     let (mut remote_file, _) = sess.scp_recv(Path::new(remote_file_path)).unwrap();
     let mut buffer = Vec::new();
     remote_file.read_to_end(&mut buffer).unwrap();
@@ -126,9 +265,7 @@ pub fn copy_from_remote(remote_file_path: &str, local_file_path: &str, sess: &mu
     remote_file.close().unwrap();
     remote_file.wait_close().unwrap();
 
-    // write buffer to file
-    let mut local_file = File::create(local_file_path).unwrap();
-    local_file.write_all(&buffer).unwrap();
+    buffer
 }
 
 pub fn copy_directory_from_remote(
@@ -143,21 +280,34 @@ pub fn copy_directory_from_remote(
 
     // copy files from the directory
     for file in directory.get_files() {
-        let pdf_file_name = format!("{}.pdf", file.get_hash());
-        let remote_file_path = format!("{}/{}", constants::DIR, &pdf_file_name);
+        let extension = match file.get_file_type() {
+            Some(FileType::Notebook) => {
+                println!(
+                    "{} {} ({}) is a notebook, which can't be exported yet",
+                    "Skipping:".bold().yellow(),
+                    file.get_hash().to_string().purple(),
+                    file.get_visible_name()
+                );
+                continue;
+            }
+            Some(FileType::Epub) => "epub",
+            _ => "pdf",
+        };
+        let payload_file_name = format!("{}.{}", file.get_hash(), extension);
+        let remote_file_path = format!("{}/{}", constants::DIR, &payload_file_name);
 
         // check remote file path exists
-        if check_remote_file_exists(sess, &remote_file_path) == false {
+        if !check_remote_file_exists(sess, &remote_file_path) {
             println!(
                 "{} {} ({}) does not exist on remote",
                 "Error:".bold().red(),
-                &pdf_file_name.purple(),
+                &payload_file_name.purple(),
                 file.get_visible_name()
             );
             continue;
         }
 
-        let local_file_path = local_path.join(file.get_visible_name());
+        let local_file_path = local_path.join(file.get_local_file_name());
         copy_from_remote(&remote_file_path, local_file_path.to_str().unwrap(), sess);
     }
 
@@ -169,3 +319,156 @@ pub fn copy_directory_from_remote(
 
     Ok(())
 }
+
+/// Creates a collection on the remote and returns its (empty) directory node.
+pub fn create_remote_collection(
+    name: &str,
+    parent: &Hash,
+    profile: &DeviceProfile,
+    sess: &mut Session,
+) -> Result<Directory, Box<dyn std::error::Error>> {
+    let hash = Uuid::new_v4().to_string();
+    let metadata = profile.new_metadata(name, parent, constants::COLLECTION);
+
+    write_to_remote(
+        serde_json::to_string_pretty(&metadata)?.as_bytes(),
+        &format!("{}/{}.metadata", constants::DIR, hash),
+        sess,
+    );
+    write_to_remote(b"{}", &format!("{}/{}.content", constants::DIR, hash), sess);
+
+    Ok(Directory::new(
+        Hash::new(hash),
+        serde_json::from_value(metadata)?,
+        None,
+        None,
+    ))
+}
+
+/// Uploads a local file as a new document on the remote. Returns the hash of
+/// the new document.
+pub fn upload_document(
+    local_file_path: &Path,
+    file_type: FileType,
+    parent: &Hash,
+    profile: &DeviceProfile,
+    sess: &mut Session,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let hash = Uuid::new_v4().to_string();
+    let name = unsanitize_file_name(
+        &local_file_path
+            .file_stem()
+            .ok_or("file has no name")?
+            .to_string_lossy(),
+    );
+
+    let payload = std::fs::read(local_file_path)?;
+    let extension = file_type.transfer_format().local_extension();
+
+    // write the payload first so xochitl never sees a document without one
+    write_to_remote(
+        &payload,
+        &format!("{}/{}.{}", constants::DIR, hash, extension),
+        sess,
+    );
+    let content = profile.new_content(extension);
+    write_to_remote(
+        serde_json::to_string_pretty(&content)?.as_bytes(),
+        &format!("{}/{}.content", constants::DIR, hash),
+        sess,
+    );
+    let metadata = profile.new_metadata(&name, parent, constants::DOCUMENT);
+    write_to_remote(
+        serde_json::to_string_pretty(&metadata)?.as_bytes(),
+        &format!("{}/{}.metadata", constants::DIR, hash),
+        sess,
+    );
+
+    Ok(hash)
+}
+
+/// Uploads every file in `directory` to `remote_directory`, creating any
+/// sub-directories which don't exist on the remote yet.
+/// Returns the local path and new hash of everything which was created.
+pub fn send_directory_to_remote(
+    directory: &Directory,
+    local_path: &Path,
+    remote_directory: &Directory,
+    ignore_case: bool,
+    profile: &DeviceProfile,
+    sess: &mut Session,
+) -> Result<Vec<(PathBuf, String)>, Box<dyn std::error::Error>> {
+    let mut pushed = Vec::new();
+
+    for file in directory.get_files() {
+        let file_type = match file.get_file_type() {
+            Some(file_type) => file_type,
+            None => continue,
+        };
+        let local_file_path = local_path.join(file.get_visible_name());
+        let hash = upload_document(
+            &local_file_path,
+            file_type,
+            remote_directory.get_hash(),
+            profile,
+            sess,
+        )?;
+        println!(
+            "{} {}",
+            "Pushed:".bold().green(),
+            local_file_path.to_str().unwrap().italic().purple()
+        );
+        pushed.push((local_file_path, hash));
+    }
+
+    for sub_directory in directory.get_directories() {
+        let sub_local_path = local_path.join(sub_directory.get_visible_name());
+        let sub_remote_directory = match remote_directory.get_directories().iter().find(|d| {
+            names_match(
+                d.get_visible_name(),
+                sub_directory.get_visible_name(),
+                ignore_case,
+            )
+        }) {
+            Some(d) => d.clone(),
+            None => {
+                let collection = create_remote_collection(
+                    &unsanitize_file_name(sub_directory.get_visible_name()),
+                    remote_directory.get_hash(),
+                    profile,
+                    sess,
+                )?;
+                pushed.push((sub_local_path.clone(), collection.get_hash().to_string()));
+                collection
+            }
+        };
+        pushed.extend(send_directory_to_remote(
+            sub_directory,
+            &sub_local_path,
+            &sub_remote_directory,
+            ignore_case,
+            profile,
+            sess,
+        )?);
+    }
+
+    Ok(pushed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_and_empty_metadata_are_told_apart() {
+        assert!(parse_metadata_output("").unwrap().is_none());
+        assert!(parse_metadata_output(&format!("{}\n", EXISTS_MARKER)).is_err());
+        assert!(parse_metadata_output(&format!("{}\n{{", EXISTS_MARKER)).is_err());
+
+        let json = r#"{"visibleName":"Notes","lastModified":"0","type":"DocumentType"}"#;
+        let metadata = parse_metadata_output(&format!("{}\n{}", EXISTS_MARKER, json))
+            .unwrap()
+            .unwrap();
+        assert_eq!(metadata.type_, "DocumentType");
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::constants::{STATE_DIR, STATE_FILE};
+use crate::local_fs::get_local_sha256;
+use crate::nodes::{Directory, DirectoryNode, Node};
+
+/// What remarko knows about a document or collection it has synced.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StateEntry {
+    /// The local path, relative to the synced directory
+    pub path: PathBuf,
+    /// The size of the local file when it was last synced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// The sha256 of the local file when it was last synced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// The mapping between remote hashes and local paths for a synced directory,
+/// stored in `.remarko/state.json` inside it.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SyncState {
+    #[serde(default)]
+    entries: BTreeMap<String, StateEntry>,
+}
+
+impl SyncState {
+    /// Load the state for a local directory, or an empty state if it has
+    /// never been synced.
+    pub fn load(local_root: &Path) -> Result<SyncState, Box<dyn std::error::Error>> {
+        let state_file = local_root.join(STATE_DIR).join(STATE_FILE);
+        if !state_file.exists() {
+            return Ok(SyncState::default());
+        }
+        let reader = BufReader::new(File::open(state_file)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    pub fn save(&self, local_root: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let state_dir = local_root.join(STATE_DIR);
+        create_dir_all(&state_dir)?;
+        let writer = BufWriter::new(File::create(state_dir.join(STATE_FILE))?);
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+
+    pub fn get_path(&self, hash: &str) -> Option<&Path> {
+        self.entries.get(hash).map(|e| e.path.as_path())
+    }
+
+    /// Find the hash recorded for a local path.
+    pub fn get_hash(&self, path: &Path) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(_, e)| e.path == path)
+            .map(|(hash, _)| hash.as_str())
+    }
+
+    pub fn get_entry(&self, hash: &str) -> Option<&StateEntry> {
+        self.entries.get(hash)
+    }
+
+    /// Set the local path of a document or collection. The recorded content
+    /// is kept, since renaming or moving a file doesn't change it.
+    pub fn set_path(&mut self, hash: &str, path: PathBuf) {
+        match self.entries.get_mut(hash) {
+            Some(entry) => entry.path = path,
+            None => {
+                self.entries.insert(
+                    hash.to_string(),
+                    StateEntry {
+                        path,
+                        size: None,
+                        sha256: None,
+                    },
+                );
+            }
+        }
+    }
+
+    pub fn remove(&mut self, hash: &str) {
+        self.entries.remove(hash);
+    }
+
+    /// Forget everything at or below a local path.
+    pub fn remove_below(&mut self, path: &Path) {
+        self.entries.retain(|_, e| !e.path.starts_with(path));
+    }
+
+    /// Record the path and content of a local file. The sha256 is only
+    /// recalculated when the file has changed size or has never been hashed.
+    pub fn record_file(
+        &mut self,
+        hash: &str,
+        local_root: &Path,
+        path: PathBuf,
+    ) -> Result<(), std::io::Error> {
+        let size = local_root.join(&path).metadata()?.len();
+        let sha256 = match self.entries.get(hash) {
+            Some(entry) if entry.size == Some(size) && entry.sha256.is_some() => {
+                entry.sha256.clone()
+            }
+            _ => Some(get_local_sha256(&local_root.join(&path))?),
+        };
+        self.entries.insert(
+            hash.to_string(),
+            StateEntry {
+                path,
+                size: Some(size),
+                sha256,
+            },
+        );
+        Ok(())
+    }
+
+    /// Record the local path of every node in a directory which has been mapped
+    /// to local names and exists under `local_root`.
+    /// `relative_path` is the path of `directory` relative to `local_root`.
+    pub fn record_directory(
+        &mut self,
+        directory: &Directory,
+        local_root: &Path,
+        relative_path: &Path,
+    ) -> Result<(), std::io::Error> {
+        for file in directory.get_files() {
+            let path = relative_path.join(file.get_visible_name());
+            if local_root.join(&path).exists() {
+                self.record_file(&file.get_hash().to_string(), local_root, path)?;
+            }
+        }
+        for sub_directory in directory.get_directories() {
+            let path = relative_path.join(sub_directory.get_visible_name());
+            if local_root.join(&path).exists() {
+                self.set_path(&sub_directory.get_hash().to_string(), path.clone());
+                self.record_directory(sub_directory, local_root, &path)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,391 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::Utc;
+use colored::*;
+use serde_json::{json, Value};
+use ssh2::Session;
+
+use crate::constants::DIR;
+use crate::nodes::{Directory, DirectoryNode, File, Node};
+use crate::profile::get_page_ids;
+use crate::remarkable_trees::find_node;
+use crate::ssh_utils::{run_remote_command, update_remote_content};
+
+// separates the files printed by `cat` when reading them all at once
+const FILE_MARKER: &str = "@@remarko-file@@";
+
+/// A tag on one page of a document.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PageTag {
+    pub name: String,
+    pub page_id: String,
+}
+
+/// The tags on a document and on its pages.
+#[derive(Clone, Debug, Default)]
+pub struct DocumentTags {
+    pub tags: Vec<String>,
+    pub page_tags: Vec<PageTag>,
+}
+
+impl DocumentTags {
+    pub fn from_content(content: &Value) -> DocumentTags {
+        DocumentTags {
+            tags: get_tags(content),
+            page_tags: get_page_tags(content),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty() && self.page_tags.is_empty()
+    }
+
+    /// Whether the document or any of its pages has a tag, regardless of case.
+    pub fn contains(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t.eq_ignore_ascii_case(tag))
+            || self
+                .page_tags
+                .iter()
+                .any(|t| t.name.eq_ignore_ascii_case(tag))
+    }
+}
+
+/// How many documents and pages have a tag.
+#[derive(Clone, Debug)]
+pub struct TagCount {
+    pub name: String,
+    pub documents: usize,
+    pub pages: usize,
+}
+
+/// The document tags in a `.content`, which are a list of
+/// `{"name": ..., "timestamp": ...}`.
+pub fn get_tags(content: &Value) -> Vec<String> {
+    content["tags"]
+        .as_array()
+        .map(|tags| {
+            tags.iter()
+                .filter_map(|t| t["name"].as_str())
+                .map(|t| t.to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The page tags in a `.content`, which are a list of
+/// `{"name": ..., "pageId": ..., "timestamp": ...}`.
+pub fn get_page_tags(content: &Value) -> Vec<PageTag> {
+    content["pageTags"]
+        .as_array()
+        .map(|tags| {
+            tags.iter()
+                .filter_map(|t| {
+                    Some(PageTag {
+                        name: t["name"].as_str()?.to_string(),
+                        page_id: t["pageId"].as_str()?.to_string(),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Add a tag to the document, or to one of its pages if `page_id` is given.
+/// Returns false if it already had the tag.
+///
+/// ```
+/// use remarko::tags::{add_tag, get_page_tags, get_tags};
+/// use serde_json::json;
+///
+/// let mut content = json!({"fileType": "pdf", "tags": []});
+/// assert!(add_tag(&mut content, "reading", None, 1700000000000));
+/// assert!(!add_tag(&mut content, "reading", None, 1700000000000));
+/// assert!(add_tag(&mut content, "todo", Some("page-1"), 1700000000000));
+/// assert_eq!(get_tags(&content), vec!["reading"]);
+/// assert_eq!(get_page_tags(&content)[0].page_id, "page-1");
+/// assert_eq!(content["fileType"], "pdf");
+/// ```
+pub fn add_tag(content: &mut Value, tag: &str, page_id: Option<&str>, timestamp: i64) -> bool {
+    let (key, entry) = match page_id {
+        Some(page_id) => (
+            "pageTags",
+            json!({"name": tag, "pageId": page_id, "timestamp": timestamp}),
+        ),
+        None => ("tags", json!({"name": tag, "timestamp": timestamp})),
+    };
+    if !content[key].is_array() {
+        content[key] = json!([]);
+    }
+    let tags = content[key].as_array_mut().expect("just checked");
+    if tags.iter().any(|t| is_tag(t, tag, page_id)) {
+        return false;
+    }
+    tags.push(entry);
+    true
+}
+
+/// Remove a tag from the document, or from one of its pages if `page_id` is
+/// given. Returns false if it didn't have the tag.
+///
+/// ```
+/// use remarko::tags::{get_tags, remove_tag};
+/// use serde_json::json;
+///
+/// let mut content = json!({"tags": [{"name": "reading", "timestamp": 1700000000000_i64}]});
+/// assert!(!remove_tag(&mut content, "reading", Some("page-1")));
+/// assert!(remove_tag(&mut content, "reading", None));
+/// assert!(get_tags(&content).is_empty());
+/// ```
+pub fn remove_tag(content: &mut Value, tag: &str, page_id: Option<&str>) -> bool {
+    let key = match page_id {
+        Some(_) => "pageTags",
+        None => "tags",
+    };
+    let tags = match content[key].as_array_mut() {
+        Some(tags) => tags,
+        None => return false,
+    };
+    let before = tags.len();
+    tags.retain(|t| !is_tag(t, tag, page_id));
+    tags.len() != before
+}
+
+fn is_tag(entry: &Value, tag: &str, page_id: Option<&str>) -> bool {
+    entry["name"] == tag && page_id.is_none_or(|page_id| entry["pageId"] == page_id)
+}
+
+/// Read the tags of every document on the remarkable at once, by hash.
+/// Documents without tags are left out.
+pub fn read_all_tags(sess: &mut Session) -> HashMap<String, DocumentTags> {
+    let output = run_remote_command(
+        sess,
+        &format!(
+            "cd {} && for f in *.content; do [ -f \"$f\" ] && printf '\\n{} %s\\n' \"$f\" && cat \"$f\"; done",
+            DIR, FILE_MARKER
+        ),
+    );
+    let mut tags = HashMap::new();
+    for chunk in output.split(&format!("\n{} ", FILE_MARKER)).skip(1) {
+        let (name, content) = match chunk.split_once('\n') {
+            Some(parts) => parts,
+            None => continue,
+        };
+        let hash = name.trim_end_matches(".content");
+        let document_tags = serde_json::from_str(content)
+            .map(|content: Value| DocumentTags::from_content(&content))
+            .unwrap_or_default();
+        if !document_tags.is_empty() {
+            tags.insert(hash.to_string(), document_tags);
+        }
+    }
+    tags
+}
+
+/// Count the documents and pages with each tag among the documents in a
+/// tree, sorted by tag.
+pub fn count_tags(directory: &Directory, tags: &HashMap<String, DocumentTags>) -> Vec<TagCount> {
+    let mut counts: BTreeMap<String, TagCount> = BTreeMap::new();
+    count_directory_tags(directory, tags, &mut counts);
+    counts.into_values().collect()
+}
+
+fn count_directory_tags(
+    directory: &Directory,
+    tags: &HashMap<String, DocumentTags>,
+    counts: &mut BTreeMap<String, TagCount>,
+) {
+    for file in directory.get_files() {
+        let document_tags = match tags.get(&file.get_hash().to_string()) {
+            Some(document_tags) => document_tags,
+            None => continue,
+        };
+        for tag in &document_tags.tags {
+            tag_count(counts, tag).documents += 1;
+        }
+        for page_tag in &document_tags.page_tags {
+            tag_count(counts, &page_tag.name).pages += 1;
+        }
+    }
+    for sub_directory in directory.get_directories() {
+        count_directory_tags(sub_directory, tags, counts);
+    }
+}
+
+fn tag_count<'a>(counts: &'a mut BTreeMap<String, TagCount>, name: &str) -> &'a mut TagCount {
+    counts.entry(name.to_string()).or_insert_with(|| TagCount {
+        name: name.to_string(),
+        documents: 0,
+        pages: 0,
+    })
+}
+
+pub fn print_tag_counts(counts: &[TagCount]) {
+    let width = counts
+        .iter()
+        .map(|c| c.name.len())
+        .max()
+        .unwrap_or_default();
+    for count in counts {
+        println!(
+            "  {}  {} {}, {} {}",
+            format!("{:<width$}", count.name, width = width)
+                .bold()
+                .purple(),
+            count.documents,
+            plural(count.documents, "document"),
+            count.pages,
+            plural(count.pages, "page"),
+        );
+    }
+}
+
+fn plural(count: usize, word: &str) -> String {
+    match count {
+        1 => word.to_string(),
+        _ => format!("{}s", word),
+    }
+}
+
+/// Tag a document on the remote, or one of its pages if `page` is given,
+/// counting from 1.
+pub fn add_remote_tag(
+    root: &Directory,
+    path: &str,
+    tag: &str,
+    page: Option<usize>,
+    sess: &mut Session,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let timestamp = Utc::now().timestamp_millis();
+    let changed = edit_remote_tags(root, path, tag, page, sess, |content, page_id| {
+        add_tag(content, tag, page_id, timestamp)
+    })?;
+    match changed {
+        true => println!(
+            "{} {} {}",
+            "Tagged:".bold().green(),
+            describe(path, page).italic().purple(),
+            tag.bold()
+        ),
+        false => println!(
+            "{} {} already has tag {}",
+            "Skipping:".bold().yellow(),
+            describe(path, page).italic().purple(),
+            tag.bold()
+        ),
+    }
+    Ok(())
+}
+
+/// Remove a tag from a document on the remote, or from one of its pages if
+/// `page` is given, counting from 1.
+pub fn remove_remote_tag(
+    root: &Directory,
+    path: &str,
+    tag: &str,
+    page: Option<usize>,
+    sess: &mut Session,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let changed = edit_remote_tags(root, path, tag, page, sess, |content, page_id| {
+        remove_tag(content, tag, page_id)
+    })?;
+    match changed {
+        true => println!(
+            "{} {} {}",
+            "Untagged:".bold().green(),
+            describe(path, page).italic().purple(),
+            tag.bold()
+        ),
+        false => println!(
+            "{} {} doesn't have tag {}",
+            "Skipping:".bold().yellow(),
+            describe(path, page).italic().purple(),
+            tag.bold()
+        ),
+    }
+    Ok(())
+}
+
+fn edit_remote_tags(
+    root: &Directory,
+    path: &str,
+    tag: &str,
+    page: Option<usize>,
+    sess: &mut Session,
+    edit: impl FnOnce(&mut Value, Option<&str>) -> bool,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    if tag.trim().is_empty() {
+        return Err("tags can't be empty".into());
+    }
+    let node = find_node(root, path).ok_or_else(|| format!("{} not found on the remote", path))?;
+    if !node.as_any().is::<File>() {
+        return Err(format!("{} is not a document", path).into());
+    }
+
+    update_remote_content(sess, &node.get_hash().to_string(), |content| {
+        let page_id = match page {
+            Some(page) => Some(
+                get_live_page_ids(content)
+                    .and_then(|ids| Some(ids.get(page.checked_sub(1)?)?.to_string()))
+                    .ok_or_else(|| format!("{} has no page {}", path, page))?,
+            ),
+            None => None,
+        };
+        Ok(edit(content, page_id.as_deref()))
+    })
+}
+
+/// The ids of the pages of a document as they are numbered on the tablet,
+/// leaving out pages which were deleted but are still listed in `cPages`.
+pub fn get_live_page_ids(content: &Value) -> Option<Vec<&str>> {
+    if let Some(pages) = content["cPages"]["pages"].as_array() {
+        let is_deleted = |page: &Value| page["deleted"]["value"].as_i64().unwrap_or(0) != 0;
+        return Some(
+            pages
+                .iter()
+                .filter(|page| !is_deleted(page))
+                .filter_map(|page| page["id"].as_str())
+                .collect(),
+        );
+    }
+    get_page_ids(content)
+}
+
+fn describe(path: &str, page: Option<usize>) -> String {
+    match page {
+        Some(page) => format!("{} (page {})", path, page),
+        None => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deleted_pages_are_not_numbered() {
+        let content = json!({
+            "formatVersion": 2,
+            "cPages": {"pages": [
+                {"id": "p1"},
+                {"id": "p2", "deleted": {"timestamp": "1:2", "value": 1}},
+                {"id": "p3", "deleted": {"timestamp": "1:2", "value": 0}},
+            ]},
+        });
+        assert_eq!(get_live_page_ids(&content), Some(vec!["p1", "p3"]));
+
+        let content = json!({"pages": ["p1", "p2"]});
+        assert_eq!(get_live_page_ids(&content), Some(vec!["p1", "p2"]));
+    }
+
+    #[test]
+    fn tags_are_matched_on_documents_and_pages_regardless_of_case() {
+        let content = json!({
+            "tags": [{"name": "Reading", "timestamp": 1}],
+            "pageTags": [{"name": "figures", "pageId": "p1", "timestamp": 1}],
+        });
+        let tags = DocumentTags::from_content(&content);
+        assert!(tags.contains("reading"));
+        assert!(tags.contains("FIGURES"));
+        assert!(!tags.contains("todo"));
+    }
+}
@@ -0,0 +1,66 @@
+//! Helpers for building trees in tests.
+
+use crate::constants::{COLLECTION, DOCUMENT};
+use crate::file_types::FileType;
+use crate::nodes::{Directory, DirectoryNode, File, Hash, Metadata, Node};
+
+pub fn file(hash: &str, name: &str, file_type: Option<FileType>) -> File {
+    let metadata = Metadata::new(name.to_string(), None, "0".into(), DOCUMENT.into());
+    with_metadata(hash, metadata, file_type)
+}
+
+/// A document marked as deleted on the remarkable.
+pub fn deleted_file(hash: &str, name: &str, file_type: Option<FileType>) -> File {
+    let mut metadata = Metadata::new(name.to_string(), None, "0".into(), DOCUMENT.into());
+    metadata.set_deleted(true);
+    with_metadata(hash, metadata, file_type)
+}
+
+fn with_metadata(hash: &str, metadata: Metadata, file_type: Option<FileType>) -> File {
+    let mut file = File::new(Hash::new(hash.to_string()), metadata);
+    if let Some(file_type) = file_type {
+        file.set_file_type(file_type);
+    }
+    file
+}
+
+pub fn directory(
+    hash: &str,
+    name: &str,
+    files: Vec<File>,
+    directories: Vec<Directory>,
+) -> Directory {
+    let metadata = Metadata::new(name.to_string(), None, "0".into(), COLLECTION.into());
+    Directory::new(
+        Hash::new(hash.to_string()),
+        metadata,
+        Some(files),
+        Some(directories),
+    )
+}
+
+/// A collection marked as deleted on the remarkable.
+pub fn deleted_directory(
+    hash: &str,
+    name: &str,
+    files: Vec<File>,
+    directories: Vec<Directory>,
+) -> Directory {
+    let mut metadata = Metadata::new(name.to_string(), None, "0".into(), COLLECTION.into());
+    metadata.set_deleted(true);
+    Directory::new(
+        Hash::new(hash.to_string()),
+        metadata,
+        Some(files),
+        Some(directories),
+    )
+}
+
+/// The names of the files directly in a directory.
+pub fn file_names(directory: &Directory) -> Vec<String> {
+    directory
+        .get_files()
+        .iter()
+        .map(|f| f.get_visible_name().to_string())
+        .collect()
+}
@@ -0,0 +1,337 @@
+use std::collections::BTreeMap;
+
+use chrono::{Duration, Utc};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use ssh2::Session;
+
+use crate::constants::{DIR, REMOTE_STATE_DIR, REMOTE_TRASH_FILE, TRASH};
+use crate::dates::DateFormat;
+use crate::nodes::{Directory, DirectoryNode, Hash, Node};
+use crate::remarkable_trees::{collect_hashes, find_node, find_path, split_path};
+use crate::ssh_utils::{run_remote_command, update_remote_metadata, write_to_remote};
+
+/// The collections documents were in before remarko moved them to the trash,
+/// and when they were moved, stored on the remarkable so they can be
+/// restored from any machine.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TrashRecord {
+    #[serde(default)]
+    parents: BTreeMap<String, String>,
+    /// Milliseconds since the epoch, like `lastModified`
+    #[serde(default)]
+    trashed: BTreeMap<String, i64>,
+}
+
+impl TrashRecord {
+    pub fn load(sess: &mut Session) -> Result<TrashRecord, Box<dyn std::error::Error>> {
+        let output = run_remote_command(
+            sess,
+            &format!("cat {}/{} 2>/dev/null", REMOTE_STATE_DIR, REMOTE_TRASH_FILE),
+        );
+        if output.trim().is_empty() {
+            return Ok(TrashRecord::default());
+        }
+        TrashRecord::parse(&output)
+    }
+
+    fn parse(json: &str) -> Result<TrashRecord, Box<dyn std::error::Error>> {
+        let value: serde_json::Value = serde_json::from_str(json)?;
+        // older versions only stored the parents
+        match value.get("parents") {
+            Some(_) => Ok(serde_json::from_value(value)?),
+            None => Ok(TrashRecord {
+                parents: serde_json::from_value(value)?,
+                ..TrashRecord::default()
+            }),
+        }
+    }
+
+    pub fn save(&self, sess: &mut Session) -> Result<(), Box<dyn std::error::Error>> {
+        run_remote_command(sess, &format!("mkdir -p {}", REMOTE_STATE_DIR));
+        write_to_remote(
+            serde_json::to_string_pretty(self)?.as_bytes(),
+            &format!("{}/{}", REMOTE_STATE_DIR, REMOTE_TRASH_FILE),
+            sess,
+        );
+        Ok(())
+    }
+
+    pub fn get_parent(&self, hash: &str) -> Option<&str> {
+        self.parents.get(hash).map(|p| p.as_str())
+    }
+
+    /// When remarko moved a document or collection to the trash.
+    pub fn get_trashed(&self, hash: &str) -> Option<i64> {
+        self.trashed.get(hash).copied()
+    }
+
+    fn remove(&mut self, hash: &str) {
+        self.parents.remove(hash);
+        self.trashed.remove(hash);
+    }
+}
+
+/// Move documents and collections to the remarkable's trash, remembering
+/// where they were.
+pub fn trash_remote_nodes(
+    hashes: &[Hash],
+    sess: &mut Session,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut record = TrashRecord::load(sess)?;
+    let now = Utc::now().timestamp_millis();
+    for hash in hashes {
+        let hash = hash.to_string();
+        let mut parent = None;
+        update_remote_metadata(sess, &hash, |metadata| {
+            parent = metadata.get_parent().cloned();
+            metadata.set_parent(TRASH.to_string());
+        })?;
+        match parent {
+            Some(parent) if parent != TRASH => {
+                record.trashed.insert(hash.clone(), now);
+                record.parents.insert(hash, parent);
+            }
+            _ => {}
+        }
+    }
+    record.save(sess)
+}
+
+/// Print what is in the trash, with where it was if that is known.
+pub fn print_trash(trash: &Directory, root: &Directory, record: &TrashRecord, dates: &DateFormat) {
+    let origin = |hash: &Hash| {
+        record
+            .get_parent(&hash.to_string())
+            .and_then(|parent| find_path(root, parent))
+            .map(|path| format!("(from /{})", path).italic().to_string())
+            .unwrap_or_default()
+    };
+    for directory in trash.get_directories() {
+        println!(
+            "  {}{} {}",
+            directory.get_visible_name().bold().green(),
+            "/".bold().green(),
+            origin(directory.get_hash())
+        );
+    }
+    for file in trash.get_files() {
+        println!(
+            "  {} {} {}",
+            dates.format_or(file.get_last_modified().as_ref(), "unknown"),
+            file.get_visible_name().purple(),
+            origin(file.get_hash())
+        );
+    }
+}
+
+/// Move a document or collection out of the trash.
+///
+/// It goes into the collection at `to` if given, otherwise back where it was
+/// before it was trashed. If that isn't known, or the collection no longer
+/// exists, it goes into the root.
+pub fn restore_from_trash(
+    trash: &Directory,
+    root: &Directory,
+    path: &str,
+    to: Option<&str>,
+    sess: &mut Session,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let node = match find_node(trash, path) {
+        Some(node) if !split_path(path).1.is_empty() => node,
+        _ => return Err(format!("{} not found in the trash", path).into()),
+    };
+    let hash = node.get_hash().to_string();
+    let mut record = TrashRecord::load(sess)?;
+
+    let (parent, parent_path) = match to {
+        Some(to) => match find_node(root, to) {
+            Some(directory) if directory.as_any().is::<Directory>() => (
+                directory.get_hash().to_string(),
+                to.trim_matches('/').to_string(),
+            ),
+            _ => return Err(format!("{} is not a collection", to).into()),
+        },
+        None => match record
+            .get_parent(&hash)
+            .and_then(|parent| Some((parent.to_string(), find_path(root, parent)?)))
+        {
+            Some(found) => found,
+            None => (root.get_hash().to_string(), String::new()),
+        },
+    };
+
+    update_remote_metadata(sess, &hash, |metadata| {
+        metadata.set_parent(parent);
+    })?;
+    record.remove(&hash);
+    record.save(sess)?;
+
+    println!(
+        "{} {} {} {}",
+        "Restored:".bold().green(),
+        path.italic().purple(),
+        "->".bold(),
+        format!("/{}", parent_path).italic().purple()
+    );
+    Ok(())
+}
+
+/// Find what `empty_trash` would delete: everything in the trash, or only
+/// what was trashed longer ago than `older_than`. Documents remarko didn't
+/// trash itself go by when they were last modified instead.
+pub fn find_expired<'a>(
+    trash: &'a Directory,
+    record: &TrashRecord,
+    older_than: Option<Duration>,
+) -> Vec<&'a dyn Node> {
+    let now = Utc::now().timestamp_millis();
+    let is_expired = |node: &dyn Node| match older_than {
+        Some(older_than) => record
+            .get_trashed(&node.get_hash().to_string())
+            .or_else(|| node.get_metadata().get_last_modified_timestamp())
+            .is_some_and(|trashed| now - trashed >= older_than.num_milliseconds()),
+        None => true,
+    };
+
+    let mut expired: Vec<&dyn Node> = Vec::new();
+    for directory in trash.get_directories() {
+        if is_expired(directory) {
+            expired.push(directory);
+        }
+    }
+    for file in trash.get_files() {
+        if is_expired(file) {
+            expired.push(file);
+        }
+    }
+    expired
+}
+
+/// Permanently delete documents and collections, with everything in them,
+/// by removing all of their files from the remarkable.
+pub fn empty_trash(
+    expired: &[&dyn Node],
+    sess: &mut Session,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut record = TrashRecord::load(sess)?;
+    for node in expired {
+        let mut hashes = vec![node.get_hash().clone()];
+        if let Some(directory) = node.as_any().downcast_ref::<Directory>() {
+            collect_hashes(directory, &mut hashes);
+        }
+
+        // the payload, .content, .metadata, .pagedata and so on, plus the
+        // directories of pages, thumbnails and highlights
+        let targets: Vec<String> = hashes
+            .iter()
+            .map(|hash| hash.to_string())
+            .filter(|hash| !hash.is_empty())
+            .flat_map(|hash| [format!("{}/{}", DIR, hash), format!("{}/{}.*", DIR, hash)])
+            .collect();
+        run_remote_command(sess, &format!("rm -rf {}", targets.join(" ")));
+
+        for hash in &hashes {
+            record.remove(&hash.to_string());
+        }
+        println!(
+            "{} {}",
+            "Deleted:".bold().green(),
+            node.get_visible_name().italic().purple()
+        );
+    }
+    record.save(sess)
+}
+
+/// Parse an age like "30d", "12h" or "2w".
+pub fn parse_age(age: &str) -> Result<Duration, String> {
+    let error = || format!("Invalid age: {}, expected e.g. 30d, 12h or 2w", age);
+    let unit_start = age.find(|c: char| !c.is_ascii_digit()).ok_or_else(error)?;
+    let amount: i64 = age[..unit_start].parse().map_err(|_| error())?;
+    let duration = match &age[unit_start..] {
+        "m" => Duration::try_minutes(amount),
+        "h" => Duration::try_hours(amount),
+        "d" => Duration::try_days(amount),
+        "w" => Duration::try_weeks(amount),
+        _ => None,
+    };
+    duration.ok_or_else(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_types::FileType;
+    use crate::test_utils::{directory, file};
+
+    #[test]
+    fn ages_are_parsed_by_unit() {
+        assert_eq!(parse_age("90m"), Ok(Duration::try_minutes(90).unwrap()));
+        assert_eq!(parse_age("12h"), Ok(Duration::try_hours(12).unwrap()));
+        assert_eq!(parse_age("30d"), Ok(Duration::try_days(30).unwrap()));
+        assert_eq!(parse_age("2w"), Ok(Duration::try_days(14).unwrap()));
+    }
+
+    #[test]
+    fn invalid_ages_are_errors() {
+        for age in ["", "30", "d", "30y", "-3d", "3.5d", "30dd"] {
+            assert!(parse_age(age).is_err(), "{} should be invalid", age);
+        }
+    }
+
+    #[test]
+    fn ages_too_large_are_errors_rather_than_panics() {
+        assert!(parse_age("9999999999999999w").is_err());
+        assert!(parse_age("99999999999999999999d").is_err());
+    }
+
+    #[test]
+    fn old_records_without_trash_times_are_read() {
+        let record = TrashRecord::parse(r#"{"a": "b"}"#).unwrap();
+        assert_eq!(record.get_parent("a"), Some("b"));
+        assert_eq!(record.get_trashed("a"), None);
+
+        let record =
+            TrashRecord::parse(r#"{"parents": {"a": "b"}, "trashed": {"a": 1700000000000}}"#)
+                .unwrap();
+        assert_eq!(record.get_parent("a"), Some("b"));
+        assert_eq!(record.get_trashed("a"), Some(1700000000000));
+    }
+
+    #[test]
+    fn expiry_goes_by_when_documents_were_trashed() {
+        // every document was last modified at the epoch
+        let trash = directory(
+            TRASH,
+            "trash",
+            vec![
+                file("a", "Trashed today", Some(FileType::Pdf)),
+                file("b", "Trashed long ago", Some(FileType::Pdf)),
+                file("c", "Trashed on the remarkable", Some(FileType::Pdf)),
+            ],
+            vec![directory("d", "Old collection", Vec::new(), Vec::new())],
+        );
+        let now = Utc::now().timestamp_millis();
+        let day = Duration::try_days(1).unwrap().num_milliseconds();
+        let mut record = TrashRecord::default();
+        record.trashed.insert("a".to_string(), now - day);
+        record.trashed.insert("b".to_string(), now - 40 * day);
+        record.trashed.insert("d".to_string(), now - 40 * day);
+
+        let names = |expired: Vec<&dyn Node>| -> Vec<String> {
+            expired
+                .iter()
+                .map(|n| n.get_visible_name().to_string())
+                .collect()
+        };
+        assert_eq!(
+            names(find_expired(&trash, &record, Duration::try_days(30))),
+            vec![
+                "Old collection",
+                "Trashed long ago",
+                "Trashed on the remarkable"
+            ]
+        );
+        assert_eq!(names(find_expired(&trash, &record, None)).len(), 4);
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use colored::*;
+use serde::{Deserialize, Serialize};
+
+use crate::local_fs::remove_common_files_and_directories;
+use crate::nodes::{Directory, DirectoryNode, Node};
+
+/// The home and trash trees of a remarkable at one point in time, as saved
+/// by `list --save`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SavedTrees {
+    pub root: Directory,
+    pub trash: Directory,
+}
+
+impl SavedTrees {
+    pub fn load(path: &Path) -> Result<SavedTrees, Box<dyn std::error::Error>> {
+        let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), self)?;
+        Ok(())
+    }
+}
+
+/// What changed between two versions of a remote tree, as paths from the root.
+#[derive(Clone, Debug, Default)]
+pub struct TreeChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// The old and new paths of documents and collections which were renamed
+    /// or moved
+    pub renamed: Vec<(String, String)>,
+    /// Documents at the same path with a newer modification time
+    pub modified: Vec<String>,
+}
+
+impl TreeChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.renamed.is_empty()
+            && self.modified.is_empty()
+    }
+}
+
+/// Where a node is in a tree.
+struct Location {
+    path: String,
+    last_modified: Option<i64>,
+}
+
+/// Compare two versions of a remote tree.
+///
+/// Nodes at the same path are removed with `remove_common_files_and_directories`.
+/// What is left on either side is matched by hash: nodes which are in both
+/// trees were renamed or moved, and the rest were added or removed. A node at
+/// the same path with a different hash was deleted and added again, so it is
+/// both removed and added. Documents at the same path are modified if their
+/// modification time changed.
+pub fn compare_trees(old: &Directory, new: &Directory) -> TreeChanges {
+    let mut old_locations = HashMap::new();
+    index_tree(old, "", &mut old_locations);
+    let mut new_locations = HashMap::new();
+    index_tree(new, "", &mut new_locations);
+
+    let (unique_on_old, unique_on_new) = remove_common_files_and_directories(old, new, false);
+    let mut unique_old_hashes = Vec::new();
+    collect_unique(&unique_on_old, "", &new_locations, &mut unique_old_hashes);
+    let mut unique_new_hashes = Vec::new();
+    collect_unique(&unique_on_new, "", &old_locations, &mut unique_new_hashes);
+
+    let mut changes = TreeChanges::default();
+    let mut renamed = HashSet::new();
+    for (hash, path) in unique_old_hashes {
+        match new_locations.get(&hash) {
+            Some(location) if location.path != path => {
+                changes.renamed.push((path, location.path.clone()));
+                renamed.insert(hash);
+            }
+            Some(_) => {}
+            None => changes.removed.push(path),
+        }
+    }
+    for (hash, path) in unique_new_hashes {
+        match old_locations.get(&hash) {
+            Some(location) if location.path != path && !renamed.contains(&hash) => {
+                changes.renamed.push((location.path.clone(), path));
+            }
+            Some(_) => {}
+            None => changes.added.push(path),
+        }
+    }
+    let old_paths: HashMap<&str, &str> = old_locations
+        .iter()
+        .map(|(hash, location)| (location.path.as_str(), hash.as_str()))
+        .collect();
+    for (hash, new_location) in &new_locations {
+        if old_locations.contains_key(hash) || changes.added.contains(&new_location.path) {
+            continue;
+        }
+        match old_paths.get(new_location.path.as_str()) {
+            Some(old_hash) if !new_locations.contains_key(*old_hash) => {
+                changes.removed.push(new_location.path.clone());
+                changes.added.push(new_location.path.clone());
+            }
+            _ => {}
+        }
+    }
+    for (hash, new_location) in &new_locations {
+        if let Some(old_location) = old_locations.get(hash) {
+            if old_location.path == new_location.path
+                && old_location.last_modified.is_some()
+                && old_location.last_modified != new_location.last_modified
+            {
+                changes.modified.push(new_location.path.clone());
+            }
+        }
+    }
+
+    remove_nested(&mut changes.added);
+    remove_nested(&mut changes.removed);
+    changes.renamed.sort();
+    changes.modified.sort();
+    changes
+}
+
+pub fn print_tree_changes(changes: &TreeChanges) {
+    if changes.is_empty() {
+        println!("\nNo changes");
+        return;
+    }
+    let sections = [
+        ("Added:", &changes.added),
+        ("Removed:", &changes.removed),
+        ("Modified:", &changes.modified),
+    ];
+    for (title, paths) in sections {
+        if paths.is_empty() {
+            continue;
+        }
+        println!("\n{}", title);
+        for path in paths {
+            println!("  {}", path.purple());
+        }
+    }
+    if !changes.renamed.is_empty() {
+        println!("\nRenamed or moved:");
+        for (from, to) in &changes.renamed {
+            println!("  {} {} {}", from.purple(), "->".bold(), to.purple());
+        }
+    }
+}
+
+/// Record the path of every document and collection in a tree by hash.
+/// Only documents record their modification time, since a collection's
+/// changes whenever anything in it does.
+fn index_tree(directory: &Directory, path: &str, locations: &mut HashMap<String, Location>) {
+    for file in directory.get_files() {
+        locations.insert(
+            file.get_hash().to_string(),
+            Location {
+                path: join(path, file.get_visible_name()),
+                last_modified: file.get_metadata().get_last_modified_timestamp(),
+            },
+        );
+    }
+    for sub_directory in directory.get_directories() {
+        let sub_path = join(path, sub_directory.get_visible_name());
+        locations.insert(
+            sub_directory.get_hash().to_string(),
+            Location {
+                path: sub_path.clone(),
+                last_modified: None,
+            },
+        );
+        index_tree(sub_directory, &sub_path, locations);
+    }
+}
+
+/// List the hashes and paths of the nodes in the result of
+/// `remove_common_files_and_directories`. Directories which are only there
+/// because they contain differences, i.e. are at the same path on the other
+/// side, are skipped, and other directories are listed whole rather than
+/// with everything in them.
+fn collect_unique(
+    directory: &Directory,
+    path: &str,
+    other_locations: &HashMap<String, Location>,
+    unique: &mut Vec<(String, String)>,
+) {
+    for file in directory.get_files() {
+        unique.push((
+            file.get_hash().to_string(),
+            join(path, file.get_visible_name()),
+        ));
+    }
+    for sub_directory in directory.get_directories() {
+        let hash = sub_directory.get_hash().to_string();
+        let sub_path = join(path, sub_directory.get_visible_name());
+        match other_locations.get(&hash) {
+            Some(location) if location.path == sub_path => {
+                collect_unique(sub_directory, &sub_path, other_locations, unique)
+            }
+            _ => unique.push((hash, sub_path)),
+        }
+    }
+}
+
+/// Sort paths and leave out those inside another of them, so collections
+/// which were added or removed are listed whole.
+fn remove_nested(paths: &mut Vec<String>) {
+    paths.sort();
+    paths.dedup();
+    let all = paths.clone();
+    paths.retain(|path| {
+        !all.iter()
+            .any(|other| path.starts_with(&format!("{}/", other)))
+    });
+}
+
+fn join(path: &str, name: &str) -> String {
+    match path.is_empty() {
+        true => name.to_string(),
+        false => format!("{}/{}", path, name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::DOCUMENT;
+    use crate::file_types::FileType;
+    use crate::nodes::{File, Hash, Metadata};
+    use crate::test_utils::{directory, file};
+
+    fn modified_file(hash: &str, name: &str, last_modified: &str) -> File {
+        let metadata = Metadata::new(name.into(), None, last_modified.into(), DOCUMENT.into());
+        File::new(Hash::new(hash.to_string()), metadata)
+    }
+
+    fn tree(files: Vec<File>, directories: Vec<Directory>) -> Directory {
+        directory("", "", files, directories)
+    }
+
+    #[test]
+    fn renames_and_moves_are_matched_by_hash() {
+        let old = tree(
+            vec![file("a", "Paper", Some(FileType::Pdf))],
+            vec![directory("b", "Work", Vec::new(), Vec::new())],
+        );
+        let new = tree(
+            Vec::new(),
+            vec![directory(
+                "b",
+                "Job",
+                vec![file("a", "Paper", Some(FileType::Pdf))],
+                Vec::new(),
+            )],
+        );
+        let changes = compare_trees(&old, &new);
+        assert_eq!(
+            changes.renamed,
+            vec![
+                ("Paper".to_string(), "Job/Paper".to_string()),
+                ("Work".to_string(), "Job".to_string()),
+            ]
+        );
+        assert!(changes.added.is_empty());
+        assert!(changes.removed.is_empty());
+    }
+
+    #[test]
+    fn collections_are_added_and_removed_whole() {
+        let old = tree(
+            Vec::new(),
+            vec![directory(
+                "a",
+                "Old",
+                vec![file("b", "Notes", Some(FileType::Notebook))],
+                Vec::new(),
+            )],
+        );
+        let new = tree(
+            vec![file("c", "Book", Some(FileType::Epub))],
+            vec![directory(
+                "d",
+                "New",
+                vec![file("e", "Notes", Some(FileType::Notebook))],
+                Vec::new(),
+            )],
+        );
+        let changes = compare_trees(&old, &new);
+        assert_eq!(changes.removed, vec!["Old"]);
+        assert_eq!(changes.added, vec!["Book", "New"]);
+    }
+
+    #[test]
+    fn documents_with_a_new_modification_time_are_modified() {
+        let old = tree(vec![modified_file("a", "Notes", "1000")], Vec::new());
+        let new = tree(vec![modified_file("a", "Notes", "2000")], Vec::new());
+        let changes = compare_trees(&old, &new);
+        assert_eq!(changes.modified, vec!["Notes"]);
+        assert!(compare_trees(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn documents_readded_with_the_same_name_are_removed_and_added() {
+        let old = tree(
+            vec![file("a", "Notes", Some(FileType::Notebook))],
+            Vec::new(),
+        );
+        let new = tree(
+            vec![file("b", "Notes", Some(FileType::Notebook))],
+            Vec::new(),
+        );
+        let changes = compare_trees(&old, &new);
+        assert_eq!(changes.removed, vec!["Notes"]);
+        assert_eq!(changes.added, vec!["Notes"]);
+        assert!(changes.modified.is_empty());
+    }
+
+    #[test]
+    fn collections_readded_with_the_same_name_are_listed_whole() {
+        let old = tree(
+            Vec::new(),
+            vec![directory(
+                "a",
+                "Work",
+                vec![file("b", "Notes", Some(FileType::Notebook))],
+                Vec::new(),
+            )],
+        );
+        let new = tree(
+            Vec::new(),
+            vec![directory(
+                "c",
+                "Work",
+                vec![file("d", "Notes", Some(FileType::Notebook))],
+                Vec::new(),
+            )],
+        );
+        let changes = compare_trees(&old, &new);
+        assert_eq!(changes.removed, vec!["Work"]);
+        assert_eq!(changes.added, vec!["Work"]);
+    }
+}
@@ -0,0 +1,566 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use chrono::{Local, NaiveDate, TimeZone};
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+
+use crate::file_types::FileType;
+use crate::nodes::{Directory, DirectoryNode, File, Node};
+use crate::tags::DocumentTags;
+
+/// What to sort the documents and collections in each collection by.
+/// Names sort A to Z, the rest newest or largest first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortKey {
+    Name,
+    Modified,
+    Opened,
+    Size,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "name" => Ok(SortKey::Name),
+            "modified" => Ok(SortKey::Modified),
+            "opened" => Ok(SortKey::Opened),
+            "size" => Ok(SortKey::Size),
+            other => Err(format!("unknown sort key: {}", other)),
+        }
+    }
+}
+
+/// A pattern to match names against: a regex between slashes, e.g.
+/// `/^Meeting \d+/`, or otherwise a glob, e.g. `*notes*`, which ignores case.
+///
+/// ```
+/// use remarko::tree_filter::NamePattern;
+///
+/// let glob: NamePattern = "*notes*".parse().unwrap();
+/// assert!(glob.is_match("Meeting Notes"));
+/// let regex: NamePattern = r"/^Meeting \d+$/".parse().unwrap();
+/// assert!(regex.is_match("Meeting 12"));
+/// assert!(!regex.is_match("Meeting notes"));
+/// ```
+#[derive(Clone, Debug)]
+pub enum NamePattern {
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl NamePattern {
+    pub fn is_match(&self, name: &str) -> bool {
+        match self {
+            NamePattern::Glob(glob) => glob.is_match(name),
+            NamePattern::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+impl FromStr for NamePattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix('/').and_then(|s| s.strip_suffix('/')) {
+            Some(regex) => Regex::new(regex)
+                .map(NamePattern::Regex)
+                .map_err(|e| format!("invalid regex {}: {}", s, e)),
+            None => GlobBuilder::new(s)
+                .case_insensitive(true)
+                .literal_separator(false)
+                .build()
+                .map(|glob| NamePattern::Glob(glob.compile_matcher()))
+                .map_err(|e| format!("invalid glob {}: {}", s, e)),
+        }
+    }
+}
+
+/// Which documents to keep in a tree. Every condition which is set has to
+/// match, and a document matches an empty filter.
+#[derive(Clone, Debug, Default)]
+pub struct TreeFilter {
+    pub types: Option<Vec<FileType>>,
+    /// Milliseconds since the epoch
+    pub modified_since: Option<i64>,
+    pub name: Option<NamePattern>,
+    pub pinned: bool,
+    pub tag: Option<String>,
+}
+
+impl TreeFilter {
+    pub fn is_empty(&self) -> bool {
+        self.types.is_none()
+            && self.modified_since.is_none()
+            && self.name.is_none()
+            && !self.pinned
+            && self.tag.is_none()
+    }
+
+    /// `tags` holds the tags of each document by hash, and is only needed
+    /// when filtering by tag. A document matches a tag on any of its pages.
+    pub fn matches(&self, file: &File, tags: &HashMap<String, DocumentTags>) -> bool {
+        let metadata = file.get_metadata();
+        self.types
+            .as_ref()
+            .is_none_or(|types| file.get_file_type().is_some_and(|t| types.contains(&t)))
+            && self.modified_since.is_none_or(|since| {
+                metadata
+                    .get_last_modified_timestamp()
+                    .is_some_and(|t| t >= since)
+            })
+            && self
+                .name
+                .as_ref()
+                .is_none_or(|name| name.is_match(file.get_visible_name()))
+            && (!self.pinned || metadata.is_pinned())
+            && self.tag.as_ref().is_none_or(|tag| {
+                tags.get(&file.get_hash().to_string())
+                    .is_some_and(|t| t.contains(tag))
+            })
+    }
+}
+
+/// Remove the documents which don't match `filter`, and the collections
+/// which are left with no matches in them, keeping the structure around
+/// the matches. Everything in a pinned collection counts as pinned.
+pub fn filter_tree(
+    directory: &Directory,
+    filter: &TreeFilter,
+    tags: &HashMap<String, DocumentTags>,
+) -> Directory {
+    if filter.is_empty() {
+        return directory.clone();
+    }
+    let files = directory
+        .get_files()
+        .iter()
+        .filter(|f| filter.matches(f, tags))
+        .cloned()
+        .collect();
+    let directories = directory
+        .get_directories()
+        .iter()
+        .map(|d| match filter.pinned && d.get_metadata().is_pinned() {
+            true => {
+                let filter = TreeFilter {
+                    pinned: false,
+                    ..filter.clone()
+                };
+                filter_tree(d, &filter, tags)
+            }
+            false => filter_tree(d, filter, tags),
+        })
+        .filter(|d| !d.is_empty())
+        .collect();
+
+    Directory::new(
+        directory.get_hash().clone(),
+        directory.get_metadata().clone(),
+        Some(files),
+        Some(directories),
+    )
+}
+
+/// Sort the documents and collections in every collection. `sizes` holds
+/// the size of each document and collection by hash, and is only needed
+/// when sorting by size.
+pub fn sort_tree(
+    directory: &Directory,
+    key: Option<SortKey>,
+    reverse: bool,
+    sizes: &HashMap<String, u64>,
+) -> Directory {
+    let mut files = directory.get_files().clone();
+    let mut directories: Vec<Directory> = directory
+        .get_directories()
+        .iter()
+        .map(|d| sort_tree(d, key, reverse, sizes))
+        .collect();
+
+    if let Some(key) = key {
+        files.sort_by(|a, b| compare(a, b, key, &|f| size_of_file(f, sizes)));
+        directories.sort_by(|a, b| compare(a, b, key, &|d| size_of_directory(d, sizes)));
+    }
+    if reverse {
+        files.reverse();
+        directories.reverse();
+    }
+
+    Directory::new(
+        directory.get_hash().clone(),
+        directory.get_metadata().clone(),
+        Some(files),
+        Some(directories),
+    )
+}
+
+/// Leave out everything more than `depth` collections below `directory`.
+/// The collections at that depth are kept, but empty.
+pub fn limit_depth(directory: &Directory, depth: usize) -> Directory {
+    let (files, directories) = match depth {
+        0 => (Vec::new(), Vec::new()),
+        _ => (
+            directory.get_files().clone(),
+            directory
+                .get_directories()
+                .iter()
+                .map(|d| limit_depth(d, depth - 1))
+                .collect(),
+        ),
+    };
+    Directory::new(
+        directory.get_hash().clone(),
+        directory.get_metadata().clone(),
+        Some(files),
+        Some(directories),
+    )
+}
+
+/// Put every document in a tree directly in its root, leaving out the
+/// collections, e.g. to pull documents from all over the device into one
+/// folder.
+pub fn flatten_tree(directory: &Directory) -> Directory {
+    let mut files = Vec::new();
+    collect_files(directory, &mut files);
+    Directory::new(
+        directory.get_hash().clone(),
+        directory.get_metadata().clone(),
+        Some(files),
+        None,
+    )
+}
+
+fn collect_files(directory: &Directory, files: &mut Vec<File>) {
+    files.extend(directory.get_files().iter().cloned());
+    for sub_directory in directory.get_directories() {
+        collect_files(sub_directory, files);
+    }
+}
+
+/// Parse a date like 2026-01-01 as the start of that day in local time, in
+/// milliseconds since the epoch.
+pub fn parse_date(date: &str) -> Result<i64, String> {
+    let date = NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d")
+        .map_err(|_| format!("invalid date {}, expected YYYY-MM-DD", date))?;
+    Local
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0).expect("midnight exists"))
+        .earliest()
+        .map(|t| t.timestamp_millis())
+        .ok_or_else(|| format!("invalid date {}", date))
+}
+
+fn compare<T: Node>(a: &T, b: &T, key: SortKey, size: &dyn Fn(&T) -> u64) -> Ordering {
+    match key {
+        SortKey::Name => a
+            .get_visible_name()
+            .to_lowercase()
+            .cmp(&b.get_visible_name().to_lowercase()),
+        SortKey::Modified => Reverse(a.get_metadata().get_last_modified_timestamp())
+            .cmp(&Reverse(b.get_metadata().get_last_modified_timestamp())),
+        SortKey::Opened => Reverse(a.get_metadata().get_last_opened_timestamp())
+            .cmp(&Reverse(b.get_metadata().get_last_opened_timestamp())),
+        SortKey::Size => Reverse(size(a)).cmp(&Reverse(size(b))),
+    }
+}
+
+fn size_of_file(file: &File, sizes: &HashMap<String, u64>) -> u64 {
+    sizes
+        .get(&file.get_hash().to_string())
+        .copied()
+        .unwrap_or_default()
+}
+
+fn size_of_directory(directory: &Directory, sizes: &HashMap<String, u64>) -> u64 {
+    sizes
+        .get(&directory.get_hash().to_string())
+        .copied()
+        .unwrap_or_default()
+        + directory
+            .get_files()
+            .iter()
+            .map(|f| size_of_file(f, sizes))
+            .sum::<u64>()
+        + directory
+            .get_directories()
+            .iter()
+            .map(|d| size_of_directory(d, sizes))
+            .sum::<u64>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nodes::{Hash, Metadata};
+    use crate::test_utils::{directory, file, file_names};
+
+    fn pinned_file(hash: &str, name: &str) -> File {
+        let mut metadata = Metadata::new(name.into(), None, "0".into(), "DocumentType".into());
+        metadata.set_pinned(true);
+        File::new(Hash::new(hash.to_string()), metadata)
+    }
+
+    fn pinned_directory(hash: &str, name: &str, files: Vec<File>) -> Directory {
+        let mut metadata = Metadata::new(name.into(), None, "0".into(), "CollectionType".into());
+        metadata.set_pinned(true);
+        Directory::new(Hash::new(hash.to_string()), metadata, Some(files), None)
+    }
+
+    fn pinned_filter() -> TreeFilter {
+        TreeFilter {
+            pinned: true,
+            ..TreeFilter::default()
+        }
+    }
+
+    #[test]
+    fn documents_in_pinned_collections_are_pinned() {
+        let tree = directory(
+            "",
+            "",
+            vec![pinned_file("a", "Starred"), file("b", "Plain", None)],
+            vec![
+                pinned_directory(
+                    "c",
+                    "Favourites",
+                    vec![file("d", "Inside", None), file("e", "Also inside", None)],
+                ),
+                directory(
+                    "f",
+                    "Other",
+                    vec![file("g", "Not starred", None)],
+                    Vec::new(),
+                ),
+            ],
+        );
+        let filtered = filter_tree(&tree, &pinned_filter(), &HashMap::new());
+        assert_eq!(file_names(&filtered), vec!["Starred"]);
+        assert_eq!(filtered.get_directories().len(), 1);
+        assert_eq!(
+            file_names(&filtered.get_directories()[0]),
+            vec!["Inside", "Also inside"]
+        );
+
+        let flattened = flatten_tree(&filtered);
+        assert_eq!(
+            file_names(&flattened),
+            vec!["Starred", "Inside", "Also inside"]
+        );
+    }
+
+    #[test]
+    fn other_conditions_still_apply_in_pinned_collections() {
+        let tree = directory(
+            "",
+            "",
+            Vec::new(),
+            vec![pinned_directory(
+                "a",
+                "Favourites",
+                vec![file("b", "Meeting notes", None), file("c", "Paper", None)],
+            )],
+        );
+        let filter = TreeFilter {
+            name: Some("*notes*".parse().unwrap()),
+            ..pinned_filter()
+        };
+        let filtered = filter_tree(&tree, &filter, &HashMap::new());
+        assert_eq!(
+            file_names(&filtered.get_directories()[0]),
+            vec!["Meeting notes"]
+        );
+    }
+
+    #[test]
+    fn collections_without_matches_are_left_out() {
+        let tree = directory(
+            "",
+            "",
+            vec![file("a", "Paper", Some(FileType::Pdf))],
+            vec![directory(
+                "b",
+                "Books",
+                vec![file("c", "Novel", Some(FileType::Epub))],
+                Vec::new(),
+            )],
+        );
+        let filter = TreeFilter {
+            types: Some(vec![FileType::Pdf]),
+            ..TreeFilter::default()
+        };
+        let filtered = filter_tree(&tree, &filter, &HashMap::new());
+        assert_eq!(file_names(&filtered), vec!["Paper"]);
+        assert!(filtered.get_directories().is_empty());
+    }
+
+    fn dated_file(hash: &str, name: &str, modified: &str, opened: Option<&str>) -> File {
+        let mut metadata = serde_json::json!({
+            "visibleName": name,
+            "lastModified": modified,
+            "type": "DocumentType",
+        });
+        if let Some(opened) = opened {
+            metadata["lastOpened"] = opened.into();
+        }
+        File::new(
+            Hash::new(hash.to_string()),
+            serde_json::from_value(metadata).unwrap(),
+        )
+    }
+
+    fn sorted_names(tree: &Directory, key: Option<SortKey>, reverse: bool) -> Vec<String> {
+        file_names(&sort_tree(tree, key, reverse, &HashMap::new()))
+    }
+
+    #[test]
+    fn names_sort_ignoring_case() {
+        let tree = directory(
+            "",
+            "",
+            vec![
+                file("a", "beta", None),
+                file("b", "Alpha", None),
+                file("c", "gamma", None),
+            ],
+            Vec::new(),
+        );
+        let key = Some(SortKey::Name);
+        assert_eq!(
+            sorted_names(&tree, key, false),
+            vec!["Alpha", "beta", "gamma"]
+        );
+        assert_eq!(
+            sorted_names(&tree, key, true),
+            vec!["gamma", "beta", "Alpha"]
+        );
+        // without a key the order is kept, or just reversed
+        assert_eq!(
+            sorted_names(&tree, None, false),
+            vec!["beta", "Alpha", "gamma"]
+        );
+        assert_eq!(
+            sorted_names(&tree, None, true),
+            vec!["gamma", "Alpha", "beta"]
+        );
+    }
+
+    #[test]
+    fn newest_come_first_and_missing_times_last() {
+        let tree = directory(
+            "",
+            "",
+            vec![
+                dated_file("a", "Old", "100", Some("300")),
+                dated_file("b", "New", "300", Some("0")),
+                dated_file("c", "Unknown", "", None),
+                dated_file("d", "Also new", "300", Some("200")),
+            ],
+            Vec::new(),
+        );
+        let modified = Some(SortKey::Modified);
+        // ties keep their order
+        assert_eq!(
+            sorted_names(&tree, modified, false),
+            vec!["New", "Also new", "Old", "Unknown"]
+        );
+        assert_eq!(
+            sorted_names(&tree, modified, true),
+            vec!["Unknown", "Old", "Also new", "New"]
+        );
+        // 0 means never opened
+        assert_eq!(
+            sorted_names(&tree, Some(SortKey::Opened), false),
+            vec!["Old", "Also new", "New", "Unknown"]
+        );
+    }
+
+    #[test]
+    fn collections_sort_by_everything_in_them() {
+        let tree = directory(
+            "",
+            "",
+            vec![
+                file("a", "Small", None),
+                file("b", "Large", None),
+                file("c", "Unknown", None),
+            ],
+            vec![
+                directory("d", "Shallow", vec![file("e", "Medium", None)], Vec::new()),
+                directory(
+                    "f",
+                    "Deep",
+                    Vec::new(),
+                    vec![directory(
+                        "g",
+                        "Inner",
+                        vec![file("h", "Tiny", None), file("i", "Huge", None)],
+                        Vec::new(),
+                    )],
+                ),
+            ],
+        );
+        let sizes = HashMap::from(
+            [
+                ("a", 10),
+                ("b", 300),
+                ("e", 200),
+                ("f", 1),
+                ("h", 5),
+                ("i", 400),
+            ]
+            .map(|(hash, size)| (hash.to_string(), size)),
+        );
+        let sorted = sort_tree(&tree, Some(SortKey::Size), false, &sizes);
+        assert_eq!(file_names(&sorted), vec!["Large", "Small", "Unknown"]);
+        let directories: Vec<&String> = sorted
+            .get_directories()
+            .iter()
+            .map(|d| d.get_visible_name())
+            .collect();
+        assert_eq!(directories, vec!["Deep", "Shallow"]);
+        // collections inside are sorted too
+        assert_eq!(
+            file_names(&sorted.get_directories()[0].get_directories()[0]),
+            vec!["Huge", "Tiny"]
+        );
+    }
+
+    #[test]
+    fn depth_keeps_collections_but_not_their_contents() {
+        let tree = directory(
+            "",
+            "",
+            vec![file("a", "Top", None)],
+            vec![directory(
+                "b",
+                "Papers",
+                vec![file("c", "Paper", None)],
+                vec![directory(
+                    "d",
+                    "2023",
+                    vec![file("e", "Old paper", None)],
+                    Vec::new(),
+                )],
+            )],
+        );
+
+        let limited = limit_depth(&tree, 0);
+        assert!(limited.get_files().is_empty());
+        assert!(limited.get_directories().is_empty());
+
+        let limited = limit_depth(&tree, 1);
+        assert_eq!(file_names(&limited), vec!["Top"]);
+        let papers = &limited.get_directories()[0];
+        assert_eq!(papers.get_visible_name(), "Papers");
+        assert!(papers.get_files().is_empty());
+        assert!(papers.get_directories().is_empty());
+
+        let limited = limit_depth(&tree, 2);
+        let papers = &limited.get_directories()[0];
+        assert_eq!(file_names(papers), vec!["Paper"]);
+        assert!(papers.get_directories()[0].get_files().is_empty());
+    }
+}